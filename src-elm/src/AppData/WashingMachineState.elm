@@ -1,5 +1,6 @@
 module AppData.WashingMachineState exposing (..)
 
+import AUTOGEN_FILE_translations as Intl
 import Array exposing (Array)
 import Json.Decode as Decode
 import Json.Decode.Extra as Decode
@@ -22,6 +23,7 @@ type alias State =
     , stepType : Int
     , stepNumber : Int
     , alarmCode : Int
+    , alarm : Maybe Alarm
     , portholeOpen : Bool
     , cycleRemaining : Int
     , stepRemaining : Int
@@ -30,6 +32,16 @@ type alias State =
     }
 
 
+{-| Meaning of the alarm code, as found in the catalog of the backend
+-}
+type alias Alarm =
+    { severity : String
+    , restartable : Bool
+    , description : Intl.Translation
+    , remedy : Intl.Translation
+    }
+
+
 type alias Statistics =
     { cycles : Int
     , interruptedCycles : Int
@@ -66,10 +78,44 @@ type alias Configuration =
     }
 
 
+{-| Operations supported by the connection to the machine; the matching actions are hidden
+otherwise
+-}
+type alias Capabilities =
+    { remoteControl : Bool
+    , clearAlarms : Bool
+    , downloadConfiguration : Bool
+    , uploadConfiguration : Bool
+    , selectConfiguration : Bool
+    , statistics : Bool
+    }
+
+
+noCapabilities : Capabilities
+noCapabilities =
+    Capabilities False False False False False False
+
+
+capabilitiesDecoder : Decode.Decoder Capabilities
+capabilitiesDecoder =
+    Decode.succeed Capabilities
+        |> Pipeline.required "remote_control" Decode.bool
+        |> Pipeline.required "clear_alarms" Decode.bool
+        |> Pipeline.required "download_configuration" Decode.bool
+        |> Pipeline.required "upload_configuration" Decode.bool
+        |> Pipeline.required "select_configuration" Decode.bool
+        |> Pipeline.required "statistics" Decode.bool
+
+
+{-| `Degraded` carries the last known data and the seconds since the machine was last reached;
+`Reconnecting` and `Offline` have no data to show
+-}
 type ConnectionState
     = Disconnected
     | Connected String Bool State Configuration Statistics
-    | Error
+    | Degraded Int String Bool State Configuration Statistics
+    | Reconnecting { attempt : Int, unreachableSecs : Int, retryInSecs : Int }
+    | Offline Int
 
 
 connectionStateUpdateDecoder : Decode.Decoder ConnectionState
@@ -108,12 +154,27 @@ connectionStateUpdateDecoder =
                 |> Pipeline.required "step_code" Decode.int
                 |> Pipeline.required "step_number" Decode.int
                 |> Pipeline.required "alarm_code" Decode.int
+                |> Pipeline.optional "alarm" (Decode.nullable alarmDecoder) Nothing
                 |> Pipeline.required "porthole_open" Decode.bool
                 |> Pipeline.required "cycle_remaining" Decode.int
                 |> Pipeline.required "step_remaining" Decode.int
                 |> Pipeline.required "step_count" Decode.int
                 |> Pipeline.custom (Decode.map3 Sensors (Decode.field "temperature" Decode.int) (Decode.field "level" Decode.int) (Decode.field "speed" Decode.int))
 
+        translationDecoder : Decode.Decoder Intl.Translation
+        translationDecoder =
+            Decode.map2 Intl.Translation
+                (Decode.field "it" Decode.string)
+                (Decode.field "en" Decode.string)
+
+        alarmDecoder : Decode.Decoder Alarm
+        alarmDecoder =
+            Decode.succeed Alarm
+                |> Pipeline.required "severity" Decode.string
+                |> Pipeline.required "restartable" Decode.bool
+                |> Pipeline.required "description" translationDecoder
+                |> Pipeline.required "remedy" translationDecoder
+
         programPreviewDecoder : Decode.Decoder ProgramPreview
         programPreviewDecoder =
             Decode.succeed ProgramPreview
@@ -145,22 +206,53 @@ connectionStateUpdateDecoder =
                 |> Pipeline.required "porthole_closings" Decode.int
                 |> Pipeline.required "porthole_openings" Decode.int
                 |> Pipeline.required "soap_times" (Decode.list Decode.int)
+
+        connectedDecoder : String -> (String -> Bool -> State -> Configuration -> Statistics -> ConnectionState) -> Decode.Decoder ConnectionState
+        connectedDecoder variant constructor =
+            Decode.succeed constructor
+                |> Pipeline.requiredAt [ variant, "name" ] Decode.string
+                |> Pipeline.requiredAt [ variant, "active" ] Decode.bool
+                |> Pipeline.requiredAt [ variant, "state" ] washingMachineStateDecoder
+                |> Pipeline.requiredAt [ variant, "configuration" ] configurationDecoder
+                |> Pipeline.requiredAt [ variant, "stats" ] statisticsDecoder
     in
     Decode.oneOf
         [ Decode.null Disconnected
-        , Decode.succeed Connected
-            |> Pipeline.requiredAt [ "Connected", "name" ] Decode.string
-            |> Pipeline.requiredAt [ "Connected", "active" ] Decode.bool
-            |> Pipeline.requiredAt [ "Connected", "state" ] washingMachineStateDecoder
-            |> Pipeline.requiredAt [ "Connected", "configuration" ] configurationDecoder
-            |> Pipeline.requiredAt [ "Connected", "stats" ] statisticsDecoder
+        , connectedDecoder "Connected" Connected
+
+        -- A degraded connection still carries the last known data
+        , Decode.at [ "Degraded", "unreachable_secs" ] Decode.int
+            |> Decode.andThen (\unreachableSecs -> connectedDecoder "Degraded" (Degraded unreachableSecs))
+        , Decode.field "Reconnecting"
+            (Decode.map3
+                (\attempt unreachableSecs retryInSecs ->
+                    Reconnecting { attempt = attempt, unreachableSecs = unreachableSecs, retryInSecs = retryInSecs }
+                )
+                (Decode.field "attempt" Decode.int)
+                (Decode.field "unreachable_secs" Decode.int)
+                (Decode.field "retry_in_secs" Decode.int)
+            )
+        , Decode.field "Offline" (Decode.map Offline (Decode.field "unreachable_secs" Decode.int))
         , Decode.andThen
             (\s ->
-                if s == "Error" then
-                    Decode.succeed Error
+                if s == "Connecting" then
+                    Decode.succeed Disconnected
 
                 else
-                    Decode.fail "Invalid error"
+                    Decode.fail "Invalid connection state"
             )
             Decode.string
         ]
+
+
+{-| Decodes a state update tagged with the machine it refers to; the flag tells whether it
+is the machine currently selected in the backend
+-}
+machineUpdateDecoder : Decode.Decoder ( Bool, ConnectionState, Capabilities )
+machineUpdateDecoder =
+    Decode.map3 (\selected state capabilities -> ( selected, state, capabilities ))
+        (Decode.field "selected" Decode.bool)
+        (Decode.field "state" connectionStateUpdateDecoder)
+        (Decode.field "capabilities" (Decode.nullable capabilitiesDecoder)
+            |> Decode.map (Maybe.withDefault noCapabilities)
+        )
@@ -61,6 +61,7 @@ type alias Model =
     , config : Maybe MachineConfiguration
     , snackbar : Snackbar.Snackbar String
     , connectionState : WSS.ConnectionState
+    , capabilities : WSS.Capabilities
     , sensorsData : Array WSS.Sensors
     , localMachines : Maybe (List ( IpAddress, String ))
     , things5Token : Maybe String
@@ -83,6 +84,7 @@ init language =
       , config = Nothing
       , snackbar = Snackbar.init
       , connectionState = WSS.Disconnected
+      , capabilities = WSS.noCapabilities
       , localMachines = Just []
       , sensorsData = Array.empty
       , things5Token = Nothing
@@ -185,6 +187,7 @@ type Msg
     | IpAddresses Encode.Value
     | LocalConnectionRequest (Maybe IpAddress)
     | BackendSnackbarMessage String
+    | NotificationClicked String
     | MsgAbout
     | MsgDismissAbout
 
@@ -207,6 +210,9 @@ port remoteMachineLoaded : (Array Int -> msg) -> Sub msg
 port notificationMessage : (String -> msg) -> Sub msg
 
 
+port selectMachine : (String -> msg) -> Sub msg
+
+
 update : Msg -> Model -> ( Model, Cmd Msg )
 update msg model =
     let
@@ -258,7 +264,10 @@ update msg model =
 
         fillTabWithConnection connection m =
             case ( m.connectionState, connection ) of
-                ( WSS.Error, WSS.Connected _ _ _ _ _ ) ->
+                ( WSS.Reconnecting _, WSS.Connected _ _ _ _ _ ) ->
+                    { m | connectionState = connection, tabModel = toRemoteControl }
+
+                ( WSS.Offline _, WSS.Connected _ _ _ _ _ ) ->
                     { m | connectionState = connection, tabModel = toRemoteControl }
 
                 ( WSS.Disconnected, WSS.Connected _ _ _ _ _ ) ->
@@ -326,9 +335,12 @@ update msg model =
             )
 
         ( StateUpdate state, _ ) ->
-            case decodeEvent WSS.connectionStateUpdateDecoder state of
-                Ok res ->
-                    ( model |> fillTabWithConnection res |> addSensorsData res, Cmd.none )
+            case decodeEvent WSS.machineUpdateDecoder state of
+                Ok ( True, res, capabilities ) ->
+                    ( { model | capabilities = capabilities } |> fillTabWithConnection res |> addSensorsData res, Cmd.none )
+
+                Ok ( False, _, _ ) ->
+                    ( model, Cmd.none )
 
                 Err error ->
                     ( newRawMessage (Decode.errorToString error) model, Cmd.none )
@@ -340,6 +352,10 @@ update msg model =
             , Cmd.none
             )
 
+        -- The backend has already selected the machine, its state follows
+        ( NotificationClicked _, _ ) ->
+            ( { model | tabModel = toRemoteControl } |> hideMenu, Cmd.none )
+
         ( LocalConnectionRequest (Just ip), _ ) ->
             ( model |> hideMenu, Ports.washingMachineHttpConnect (toString ip) )
 
@@ -523,6 +539,7 @@ subscriptions _ =
         , ipAddresses IpAddresses
         , remoteMachineLoaded StupidElmMachineLoaded
         , notificationMessage BackendSnackbarMessage
+        , selectMachine NotificationClicked
         ]
 
 
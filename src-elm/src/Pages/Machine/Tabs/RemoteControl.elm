@@ -29,6 +29,7 @@ type alias Datum =
 type alias SharedModel a =
     { a
         | connectionState : ConnectionState
+        , capabilities : WMS.Capabilities
         , context : Context
         , config : Maybe WMC.MachineConfiguration
         , sensorsData : Array WMS.Sensors
@@ -134,44 +135,82 @@ topPanelHeight =
 
 view : SharedModel a -> Model -> Ui.Element Msg
 view ({ connectionState, context, localMachines } as sharedModel) model =
+    let
+        machineName name =
+            localMachines
+                |> Maybe.andThen
+                    (List.foldl
+                        (\( ip, node ) acc ->
+                            case acc of
+                                Just _ ->
+                                    acc
+
+                                Nothing ->
+                                    if toString ip == name then
+                                        Just ( toString ip, node )
+
+                                    else
+                                        Nothing
+                        )
+                        Nothing
+                    )
+                |> Maybe.map (\( ip, node ) -> "Nodo " ++ node ++ ": " ++ ip)
+                |> Maybe.withDefault name
+
+        notice text =
+            Ui.paragraph [ Ui.width Ui.fill ] <| [ Ui.text text ]
+
+        unreachableFor secs =
+            translate Intl.NonRaggiungibileDa context ++ " " ++ formatElapsed secs
+    in
     Ui.column [ Ui.width Ui.fill, Ui.height Ui.fill, Ui.padding 16, Ui.spacing 16 ]
         [ case connectionState of
             Connected name active state configuration stats ->
-                let
-                    machineName =
-                        localMachines
-                            |> Maybe.andThen
-                                (List.foldl
-                                    (\( ip, node ) acc ->
-                                        case acc of
-                                            Just _ ->
-                                                acc
-
-                                            Nothing ->
-                                                if toString ip == name then
-                                                    Just ( toString ip, node )
-
-                                                else
-                                                    Nothing
-                                    )
-                                    Nothing
-                                )
-                            |> Maybe.map (\( ip, node ) -> "Nodo " ++ node ++ ": " ++ ip)
-                            |> Maybe.withDefault name
-                in
-                machineView sharedModel model machineName active state configuration stats
+                machineView sharedModel model (machineName name) active state configuration stats
+
+            Degraded unreachableSecs name active state configuration stats ->
+                Ui.column [ Ui.width Ui.fill, Ui.spacing 16 ]
+                    [ notice <| translate Intl.DatiNonAggiornati context ++ ": " ++ unreachableFor unreachableSecs
+                    , machineView sharedModel model (machineName name) active state configuration stats
+                    ]
 
-            Error ->
-                Ui.paragraph [ Ui.width Ui.fill ] <| [ Ui.text <| translate Intl.ErroreDiRete context ]
+            Reconnecting { attempt, unreachableSecs, retryInSecs } ->
+                notice <|
+                    unreachableFor unreachableSecs
+                        ++ ". "
+                        ++ translate Intl.TentativoDiRiconnessione context
+                        ++ " "
+                        ++ String.fromInt attempt
+                        ++ ", "
+                        ++ translate Intl.NuovoTentativoTra context
+                        ++ " "
+                        ++ formatElapsed retryInSecs
+
+            Offline unreachableSecs ->
+                notice <| translate Intl.MacchinaOffline context ++ ": " ++ unreachableFor unreachableSecs
 
             Disconnected ->
-                Ui.paragraph [ Ui.width Ui.fill ] <| [ Ui.text <| translate Intl.Disconnesso context ]
+                notice <| translate Intl.Disconnesso context
         ]
         |> AppWidgets.scrollbarYEl [ Ui.width Ui.fill, Ui.height Ui.fill, Ui.padding 16 ]
 
 
+{-| Seconds as a short duration, e.g. `1h 5m` or `40s`
+-}
+formatElapsed : Int -> String
+formatElapsed secs =
+    if secs >= 3600 then
+        String.fromInt (secs // 3600) ++ "h " ++ String.fromInt (modBy 60 (secs // 60)) ++ "m"
+
+    else if secs >= 60 then
+        String.fromInt (secs // 60) ++ "m " ++ String.fromInt (modBy 60 secs) ++ "s"
+
+    else
+        String.fromInt secs ++ "s"
+
+
 machineView : SharedModel a -> Model -> String -> Bool -> WMS.State -> WMS.Configuration -> WMS.Statistics -> Ui.Element Msg
-machineView { context, config, sensorsData } { hoveringTemperature, hoveringLevel, hoveringSpeed, hoveringDetergents, statsExpanded } name active { state, credit, cycleNumber, stepType, portholeOpen, alarmCode, cycleRemaining, stepRemaining, stepNumber, stepCount } configuration stats =
+machineView { context, config, sensorsData, capabilities } { hoveringTemperature, hoveringLevel, hoveringSpeed, hoveringDetergents, statsExpanded } name active { state, credit, cycleNumber, stepType, portholeOpen, alarmCode, alarm, cycleRemaining, stepRemaining, stepNumber, stepCount } configuration stats =
     let
         washType =
             Array.get cycleNumber configuration.programs |> Maybe.map .washType |> Maybe.withDefault 0
@@ -186,6 +225,13 @@ machineView { context, config, sensorsData } { hoveringTemperature, hoveringLeve
         cardStyle =
             [ Ui.height Ui.fill, Ui.width Ui.fill, Ui.padding 8, Ui.spacing 8 ]
 
+        onlyIf supported element =
+            if supported then
+                element
+
+            else
+                Ui.none
+
         programList : Array WMS.ProgramPreview -> (Int -> msg) -> Ui.Element msg
         programList ps start =
             let
@@ -193,7 +239,7 @@ machineView { context, config, sensorsData } { hoveringTemperature, hoveringLeve
                     Ui.row [ Ui.width Ui.fill, Ui.spacing 8 ]
                         [ Ui.paragraph [ Ui.width Ui.fill, Ui.alignLeft ] [ Ui.text <| String.fromInt (i + 1) ++ " " ++ program.name ]
                         , AppWidgets.washTypeImage program.washType 32
-                        , AppWidgets.iconButton SolidIcons.play (start i) "start"
+                        , onlyIf capabilities.remoteControl <| AppWidgets.iconButton SolidIcons.play (start i) "start"
                         ]
                         |> Widget.asItem
             in
@@ -209,7 +255,7 @@ machineView { context, config, sensorsData } { hoveringTemperature, hoveringLeve
                     Ui.row [ Ui.width Ui.fill, Ui.spacing 8 ]
                         [ Ui.paragraph [ Ui.width Ui.fill, Ui.alignLeft ] [ Ui.text archiveName ]
                         , AppWidgets.iconButton SolidIcons.download (download archiveName) "download"
-                        , AppWidgets.iconButton SolidIcons.check (select archiveName) "select"
+                        , onlyIf capabilities.selectConfiguration <| AppWidgets.iconButton SolidIcons.check (select archiveName) "select"
                         ]
                         |> Widget.asItem
             in
@@ -223,6 +269,7 @@ machineView { context, config, sensorsData } { hoveringTemperature, hoveringLeve
                 )
                 config
                 |> Maybe.withDefault Ui.none
+                |> onlyIf capabilities.uploadConfiguration
 
         statusCard : Ui.Element Msg
         statusCard =
@@ -280,10 +327,11 @@ machineView { context, config, sensorsData } { hoveringTemperature, hoveringLeve
                 runningInfo button =
                     Ui.row [ Ui.centerY, Ui.width Ui.fill, Ui.spacing 16 ]
                         [ Ui.column [ Ui.alignLeft, Ui.spacing 16 ] [ Ui.text <| formatTime cycleRemaining, Ui.text <| formatTime stepRemaining ]
-                        , Ui.column [ Ui.alignRight, Ui.spacing 16 ]
-                            [ button
-                            , AppWidgets.iconButton SolidIcons.stop StopProgram "stop"
-                            ]
+                        , onlyIf capabilities.remoteControl <|
+                            Ui.column [ Ui.alignRight, Ui.spacing 16 ]
+                                [ button
+                                , AppWidgets.iconButton SolidIcons.stop StopProgram "stop"
+                                ]
                         ]
                         |> Ui.el (Style.focusedBorder True ++ cardStyle)
             in
@@ -304,9 +352,19 @@ machineView { context, config, sensorsData } { hoveringTemperature, hoveringLeve
                     [ Ui.column [ Ui.centerY, Ui.spacing 32, Ui.width Ui.fill ]
                         [ Ui.paragraph [ Ui.alignLeft ]
                             [ Ui.text <| translate Intl.Allarme context ++ ": " ++ String.fromInt alarmCode ]
-                        , AppWidgets.textButton
-                            (translate Intl.Azzera context)
-                            (Just ClearAlarms)
+                        , alarm
+                            |> Maybe.map
+                                (\{ description, remedy } ->
+                                    Ui.column [ Ui.alignLeft, Ui.spacing 8 ]
+                                        [ Ui.paragraph [] [ Ui.text <| Intl.getTranslation context.language description ]
+                                        , Ui.paragraph [ Font.size 14 ] [ Ui.text <| Intl.getTranslation context.language remedy ]
+                                        ]
+                                )
+                            |> Maybe.withDefault Ui.none
+                        , onlyIf capabilities.clearAlarms <|
+                            AppWidgets.textButton
+                                (translate Intl.Azzera context)
+                                (Just ClearAlarms)
                         ]
                     , Ui.image [ Ui.alignRight ] { src = "images/warning.png", description = "warning" }
                     ]
@@ -452,10 +510,10 @@ machineView { context, config, sensorsData } { hoveringTemperature, hoveringLeve
             ]
         , controlPanel
         , Style.br
-        , statsPanel stats
+        , onlyIf capabilities.statistics <| statsPanel stats
         , Style.br
         , Ui.column [ Ui.centerX, Ui.spacing 32 ]
-            [ AppWidgets.textButton (translate Intl.ScaricaConfigurazione context ++ " " ++ configuration.name) (Just LoadConfig)
+            [ onlyIf capabilities.downloadConfiguration <| AppWidgets.textButton (translate Intl.ScaricaConfigurazione context ++ " " ++ configuration.name) (Just LoadConfig)
             , sendConfigBtn
             ]
         ]
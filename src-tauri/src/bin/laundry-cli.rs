@@ -0,0 +1,262 @@
+use clap::{Parser, Subcommand};
+use laundry_control::controller::{
+  discovery,
+  history::HistoryStore,
+  prefs, report, things5_api,
+  washing_machine::{self as ws, recording::Recorder, WashingMachineConnection},
+  Error,
+};
+use simplelog::*;
+use std::{
+  fs,
+  path::PathBuf,
+  process,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+/// Command line client for WS2020 washing machines, over the local network or Things5
+#[derive(Parser)]
+#[clap(name = "laundry-cli", version)]
+struct Cli {
+  /// IP address of a machine on the local network
+  #[clap(long, global = true, conflicts_with = "device")]
+  ip: Option<String>,
+
+  /// Things5 device id of the machine
+  #[clap(long, global = true)]
+  device: Option<String>,
+
+  /// Things5 access token; defaults to the one saved by the last login
+  #[clap(long, global = true)]
+  token: Option<String>,
+
+  /// Address and port of a Modbus TCP controller
+  #[clap(long, global = true, conflicts_with_all = &["ip", "device"])]
+  modbus: Option<String>,
+
+  /// Register map of the Modbus controller; defaults to the saved one
+  #[clap(long, global = true)]
+  register_map: Option<PathBuf>,
+
+  /// Print debug information on stderr
+  #[clap(short, long, global = true)]
+  verbose: bool,
+
+  #[clap(subcommand)]
+  command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Look for machines on the local network
+  Discover,
+  /// Log into Things5 and save the token for later use
+  Login { username: String, password: String },
+  /// List the Things5 devices available to the current token
+  Devices,
+  /// Print the full state of the machine
+  State,
+  /// Print the machine statistics
+  Statistics,
+  /// Print the operations supported by the connection
+  Capabilities,
+  /// Start the program with the given index
+  Start { program: u16 },
+  /// Resume the current program
+  Restart,
+  /// Pause the current program
+  Pause,
+  /// Stop the current program
+  Stop,
+  /// Clear the active alarms
+  ClearAlarms,
+  /// Download the configuration archive of the machine to a file
+  PullConfig { file: PathBuf },
+  /// Upload a configuration archive from a file to the machine
+  PushConfig {
+    file: PathBuf,
+    /// Archive to activate once the upload is complete
+    #[clap(long)]
+    select: Option<String>,
+  },
+  /// Activate one of the configuration archives stored on the machine
+  SelectConfig { archive: String },
+  /// Record the session with the machine to a file, for later replay
+  Record {
+    file: PathBuf,
+    /// Length of the recording
+    #[clap(long, default_value = "60")]
+    seconds: u64,
+  },
+  /// Save the service report of the machine as HTML
+  Report {
+    file: PathBuf,
+    /// Also save the report as PDF, through wkhtmltopdf, which must be on the PATH
+    #[clap(long)]
+    pdf: Option<PathBuf>,
+  },
+}
+
+fn print_json(value: impl serde::Serialize) -> Result<(), Error> {
+  let json = serde_json::to_string_pretty(&value).map_err(|e| Error::Json(e.to_string()))?;
+  println!("{}", json);
+  Ok(())
+}
+
+fn token(cli: &Cli) -> Result<String, Error> {
+  cli
+    .token
+    .clone()
+    .or_else(prefs::get_token)
+    .ok_or(Error::Value)
+}
+
+async fn connect(cli: &Cli) -> Result<Box<dyn WashingMachineConnection>, Error> {
+  if let Some(address) = &cli.modbus {
+    return connect_modbus(address, &cli.register_map).await;
+  }
+
+  let connection: Box<dyn WashingMachineConnection> = match (&cli.ip, &cli.device) {
+    (Some(ip), _) => Box::new(ws::local::Connection::new(ip.clone()).await),
+    (None, Some(device)) => {
+      Box::new(ws::things5::Connection::new(token(cli)?, device.clone()).await)
+    }
+    (None, None) => return Err(Error::NotConnected),
+  };
+
+  if connection.get_connection_state().is_connected() {
+    Ok(connection)
+  } else {
+    Err(Error::NotConnected)
+  }
+}
+
+async fn connect_modbus(
+  address: &str,
+  register_map: &Option<PathBuf>,
+) -> Result<Box<dyn WashingMachineConnection>, Error> {
+  let address = address.parse().map_err(|_| Error::Value)?;
+  let map = match register_map {
+    Some(path) => {
+      let content = fs::read_to_string(path).map_err(|e| Error::Server(e.to_string()))?;
+      ws::modbus::RegisterMap::parse(content.as_str())?
+    }
+    None => ws::modbus::RegisterMap::load(),
+  };
+
+  let connection = ws::modbus::Connection::new(address, map).await;
+  if connection.get_connection_state().is_connected() {
+    Ok(Box::new(connection))
+  } else {
+    Err(Error::NotConnected)
+  }
+}
+
+async fn run(cli: &Cli) -> Result<(), Error> {
+  match &cli.command {
+    Command::Discover => {
+      let addresses = discovery::poll()
+        .await
+        .map_err(|e| Error::Network(e.to_string()))?;
+      print_json(addresses)
+    }
+
+    Command::Login { username, password } => {
+      let token = things5_api::authorize(username.as_str(), password.as_str()).await?;
+      prefs::set_token(token.clone())?;
+      print_json(token)
+    }
+
+    Command::Devices => print_json(things5_api::get_devices(token(cli)?.as_str()).await?),
+
+    Command::State => print_json(connect(cli).await?.get_connection_state()),
+
+    Command::Statistics => match connect(cli).await?.get_connection_state().data() {
+      Some(data) => print_json(&data.stats),
+      None => Err(Error::NotConnected),
+    },
+
+    Command::Capabilities => print_json(connect(cli).await?.capabilities()),
+
+    Command::Start { program } => connect(cli).await?.start_program(*program).await,
+    Command::Restart => connect(cli).await?.restart().await,
+    Command::Pause => connect(cli).await?.pause().await,
+    Command::Stop => connect(cli).await?.stop().await,
+    Command::ClearAlarms => connect(cli).await?.clear_alarms().await,
+
+    Command::PullConfig { file } => {
+      let bytes = connect(cli).await?.get_machine_configuration().await?;
+      fs::write(file, bytes).map_err(|e| Error::Server(e.to_string()))
+    }
+
+    Command::PushConfig { file, select } => {
+      let bytes = fs::read(file).map_err(|e| Error::Server(e.to_string()))?;
+      let connection = connect(cli).await?;
+      connection.send_machine_configuration(bytes).await?;
+      if let Some(archive) = select {
+        connection
+          .select_machine_configuration(archive.clone())
+          .await?;
+      }
+      Ok(())
+    }
+
+    Command::SelectConfig { archive } => {
+      connect(cli)
+        .await?
+        .select_machine_configuration(archive.clone())
+        .await
+    }
+
+    Command::Record { file, seconds } => {
+      let connection: Arc<dyn WashingMachineConnection> = Arc::from(connect(cli).await?);
+      let recorder =
+        Recorder::new(connection, file.clone()).map_err(|e| Error::Server(e.to_string()))?;
+      let end = Instant::now() + Duration::from_secs(*seconds);
+      while Instant::now() < end {
+        tokio::time::sleep(recorder.suggested_refresh_period()).await;
+        recorder.refresh_data().await;
+      }
+      Ok(())
+    }
+
+    Command::Report { file, pdf } => {
+      let machine = cli
+        .ip
+        .clone()
+        .or_else(|| cli.device.clone())
+        .unwrap_or_default();
+      match connect(cli).await?.get_connection_state().data() {
+        Some(data) => {
+          let alarms = report::recent_alarms(&HistoryStore::default(), &machine);
+          let html = report::render(&machine, data, &alarms);
+          report::save(html.as_str(), file, pdf.as_deref())
+        }
+        None => Err(Error::NotConnected),
+      }
+    }
+  }
+}
+
+#[tokio::main]
+async fn main() {
+  let cli = Cli::parse();
+
+  TermLogger::init(
+    if cli.verbose {
+      LevelFilter::Debug
+    } else {
+      LevelFilter::Warn
+    },
+    Config::default(),
+    TerminalMode::Stderr,
+    ColorChoice::Auto,
+  )
+  .unwrap();
+
+  if let Err(e) = run(&cli).await {
+    eprintln!("Error: {:?}", e);
+    process::exit(1);
+  }
+}
@@ -0,0 +1,104 @@
+use clap::Parser;
+use laundry_control::emulator::{Emulator, Fault};
+use simplelog::*;
+use std::process;
+
+/// Emulates a WS2020 board on the local network, with a simulated machine behind its API
+#[derive(Parser)]
+#[clap(name = "ws2020-emulator", version)]
+struct Cli {
+  /// Address of the HTTP API
+  #[clap(long, default_value = "127.0.0.1:8080")]
+  http: String,
+
+  /// Address answering discovery probes
+  #[clap(long, default_value = "0.0.0.0:4040")]
+  discovery: String,
+
+  /// Address advertised in the discovery replies; defaults to the host of the HTTP API
+  #[clap(long)]
+  advertise: Option<String>,
+
+  /// Node number advertised in the discovery replies
+  #[clap(long, default_value = "1")]
+  node: String,
+
+  /// How many times faster than the wall clock the simulated machine runs, at most 1000
+  #[clap(long, default_value = "1")]
+  speedup: u32,
+
+  /// Faults to serve before answering normally, as `endpoint=fault` with fault one of
+  /// `timeout`, `bad-json`, `no-content-length` or an HTTP status
+  #[clap(long = "fault")]
+  faults: Vec<String>,
+
+  /// Print debug information on stderr
+  #[clap(short, long)]
+  verbose: bool,
+}
+
+fn parse_fault(spec: &str) -> Option<(String, Fault)> {
+  let (endpoint, fault) = spec.split_once('=')?;
+  let fault = match fault {
+    "timeout" => Fault::Timeout,
+    "bad-json" => Fault::BadJson,
+    "no-content-length" => Fault::MissingContentLength,
+    status => Fault::Status(status.parse().ok()?),
+  };
+  Some((String::from(endpoint), fault))
+}
+
+#[tokio::main]
+async fn main() {
+  let cli = Cli::parse();
+
+  TermLogger::init(
+    if cli.verbose {
+      LevelFilter::Debug
+    } else {
+      LevelFilter::Info
+    },
+    Config::default(),
+    TerminalMode::Stderr,
+    ColorChoice::Auto,
+  )
+  .unwrap();
+
+  let emulator = Emulator::new(cli.node.clone(), cli.speedup);
+  for spec in &cli.faults {
+    match parse_fault(spec) {
+      Some((endpoint, fault)) => emulator.script(endpoint.as_str(), vec![fault]),
+      None => {
+        eprintln!("Error: invalid fault {}", spec);
+        process::exit(1);
+      }
+    }
+  }
+
+  let http = match emulator.serve_http(cli.http.as_str()).await {
+    Ok(address) => address,
+    Err(e) => {
+      eprintln!("Error: unable to serve the API on {}: {:?}", cli.http, e);
+      process::exit(1);
+    }
+  };
+  let advertise = cli.advertise.clone().unwrap_or_else(|| http.to_string());
+  if let Err(e) = emulator
+    .serve_discovery(cli.discovery.as_str(), advertise.clone())
+    .await
+  {
+    eprintln!(
+      "Error: unable to answer discovery on {}: {:?}",
+      cli.discovery, e
+    );
+    process::exit(1);
+  }
+
+  log::info!(
+    "Emulating node {} on {}, advertised as {}",
+    cli.node,
+    http,
+    advertise
+  );
+  futures::future::pending::<()>().await;
+}
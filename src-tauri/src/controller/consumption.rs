@@ -0,0 +1,305 @@
+//! Water, energy and detergent estimates computed from the growth of the machine counters and
+//! the nominal rates of each machine.
+use super::statistics::{self, PeriodRow, StatisticsRange};
+use super::{prefs, Error};
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+
+/// Nominal rates of a machine; the counters they apply to are in seconds
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ConsumptionRates {
+  pub heater_kw: f64,
+  pub cold_water_lpm: f64,
+  pub warm_water_lpm: f64,
+  pub recovery_water_lpm: f64,
+  pub flux_water_lpm: f64,
+  /// Flow of every detergent pump, in the order of `Statistics.soap_times`
+  pub soap_mlps: Vec<f64>,
+  pub energy_price_kwh: f64,
+  pub water_price_m3: f64,
+  /// Price of the detergent of every pump, per litre
+  pub detergent_price_l: Vec<f64>,
+}
+
+/// Rates applied to every machine, unless overridden for a specific one
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RatesTable {
+  pub defaults: ConsumptionRates,
+  pub machines: HashMap<String, ConsumptionRates>,
+}
+
+impl RatesTable {
+  pub fn rates(self: &Self, machine: &String) -> &ConsumptionRates {
+    self.machines.get(machine).unwrap_or(&self.defaults)
+  }
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Cost {
+  pub energy: f64,
+  pub water: f64,
+  pub detergent: f64,
+  pub total: f64,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ConsumptionEstimate {
+  pub machine: String,
+  pub machine_name: String,
+  /// Start of the period, missing when the estimate covers the whole range
+  pub period_start: Option<DateTime<Local>>,
+  pub energy_kwh: f64,
+  pub water_l: f64,
+  /// Detergent dispensed by every pump
+  pub detergent_ml: Vec<f64>,
+  pub cost: Cost,
+}
+
+fn delta(row: &PeriodRow, name: &str) -> f64 {
+  row
+    .counters
+    .iter()
+    .find(|counter| counter.name == name)
+    .map_or(0.0, |counter| counter.delta as f64)
+}
+
+fn estimate(row: &PeriodRow, rates: &ConsumptionRates) -> ConsumptionEstimate {
+  let energy_kwh = delta(row, "heating_time") / 3600.0 * rates.heater_kw;
+  let water_l = (delta(row, "cold_water_time") * rates.cold_water_lpm
+    + delta(row, "warm_water_time") * rates.warm_water_lpm
+    + delta(row, "recovery_water_time") * rates.recovery_water_lpm
+    + delta(row, "flux_water_time") * rates.flux_water_lpm)
+    / 60.0;
+
+  let pumps = row
+    .counters
+    .iter()
+    .filter(|counter| counter.name.starts_with("soap_time_"))
+    .count();
+  let detergent_ml: Vec<f64> = (0..pumps)
+    .map(|i| {
+      let rate = rates.soap_mlps.get(i).cloned().unwrap_or_default();
+      delta(row, format!("soap_time_{}", i + 1).as_str()) * rate
+    })
+    .collect();
+
+  let energy = energy_kwh * rates.energy_price_kwh;
+  let water = water_l / 1000.0 * rates.water_price_m3;
+  let detergent: f64 = detergent_ml
+    .iter()
+    .enumerate()
+    .map(|(i, ml)| ml / 1000.0 * rates.detergent_price_l.get(i).cloned().unwrap_or_default())
+    .sum();
+
+  ConsumptionEstimate {
+    machine: row.machine.clone(),
+    machine_name: row.machine_name.clone(),
+    period_start: Some(row.period_start),
+    energy_kwh,
+    water_l,
+    detergent_ml,
+    cost: Cost {
+      energy,
+      water,
+      detergent,
+      total: energy + water + detergent,
+    },
+  }
+}
+
+/// Adds the estimate of a later sample of the same machine to the running total
+fn accumulate(total: &mut ConsumptionEstimate, other: ConsumptionEstimate) {
+  total.energy_kwh += other.energy_kwh;
+  total.water_l += other.water_l;
+  if total.detergent_ml.len() < other.detergent_ml.len() {
+    total.detergent_ml.resize(other.detergent_ml.len(), 0.0);
+  }
+  for (i, ml) in other.detergent_ml.into_iter().enumerate() {
+    total.detergent_ml[i] += ml;
+  }
+  total.cost.energy += other.cost.energy;
+  total.cost.water += other.cost.water;
+  total.cost.detergent += other.cost.detergent;
+  total.cost.total += other.cost.total;
+  total.machine_name = other.machine_name;
+}
+
+/// Estimates per machine and period; without a period every machine gets a single estimate
+/// covering the whole range
+pub fn estimates(range: &StatisticsRange) -> Result<Vec<ConsumptionEstimate>, Error> {
+  let rows = statistics::periods(range)?;
+  Ok(combine(
+    &rows,
+    &prefs::get_consumption_rates(),
+    range.period.is_some(),
+  ))
+}
+
+fn combine(rows: &Vec<PeriodRow>, table: &RatesTable, by_period: bool) -> Vec<ConsumptionEstimate> {
+  let estimates = rows
+    .iter()
+    .map(|row| estimate(row, table.rates(&row.machine)));
+
+  if by_period {
+    return estimates.collect();
+  }
+
+  let mut totals: Vec<ConsumptionEstimate> = vec![];
+  for estimate in estimates {
+    match totals
+      .iter_mut()
+      .find(|total| total.machine == estimate.machine)
+    {
+      Some(total) => accumulate(total, estimate),
+      None => totals.push(ConsumptionEstimate {
+        period_start: None,
+        ..estimate
+      }),
+    }
+  }
+  totals
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::controller::statistics::Counter;
+
+  fn row(machine: &str, deltas: &[(&str, u32)]) -> PeriodRow {
+    PeriodRow {
+      machine: String::from(machine),
+      machine_name: String::from("Lavanderia"),
+      period_start: Local::now(),
+      samples: 2,
+      counters: deltas
+        .iter()
+        .map(|(name, delta)| Counter {
+          name: String::from(*name),
+          total: 10000,
+          delta: *delta,
+        })
+        .collect(),
+    }
+  }
+
+  fn rates() -> ConsumptionRates {
+    ConsumptionRates {
+      heater_kw: 6.0,
+      cold_water_lpm: 10.0,
+      warm_water_lpm: 5.0,
+      recovery_water_lpm: 0.0,
+      flux_water_lpm: 0.0,
+      soap_mlps: vec![2.0],
+      energy_price_kwh: 0.25,
+      water_price_m3: 2.0,
+      detergent_price_l: vec![4.0],
+    }
+  }
+
+  fn table() -> RatesTable {
+    RatesTable {
+      defaults: rates(),
+      machines: HashMap::new(),
+    }
+  }
+
+  fn assert_close(actual: f64, expected: f64) {
+    assert!(
+      (actual - expected).abs() < 1e-9,
+      "{} is not {}",
+      actual,
+      expected
+    );
+  }
+
+  /// An hour of heating, 20 litres of water and 20 ml from the first pump
+  fn washing(machine: &str) -> PeriodRow {
+    row(
+      machine,
+      &[
+        ("heating_time", 3600),
+        ("cold_water_time", 60),
+        ("warm_water_time", 120),
+        ("soap_time_1", 10),
+        ("soap_time_2", 5),
+      ],
+    )
+  }
+
+  #[test]
+  fn rates_times_counters() {
+    let estimate = estimate(&washing("m"), &rates());
+
+    assert_close(estimate.energy_kwh, 6.0);
+    assert_close(estimate.water_l, 20.0);
+    // The second pump has no rate
+    assert_eq!(estimate.detergent_ml.len(), 2);
+    assert_close(estimate.detergent_ml[0], 20.0);
+    assert_close(estimate.detergent_ml[1], 0.0);
+
+    assert_close(estimate.cost.energy, 1.5);
+    assert_close(estimate.cost.water, 0.04);
+    assert_close(estimate.cost.detergent, 0.08);
+    assert_close(estimate.cost.total, 1.62);
+  }
+
+  #[test]
+  fn period_with_a_single_sample() {
+    let row = row("m", &[("heating_time", 0), ("cold_water_time", 0)]);
+    let estimate = estimate(&row, &rates());
+    assert_close(estimate.energy_kwh, 0.0);
+    assert_close(estimate.water_l, 0.0);
+    assert!(estimate.detergent_ml.is_empty());
+    assert_close(estimate.cost.total, 0.0);
+  }
+
+  #[test]
+  fn empty_history() {
+    assert!(combine(&vec![], &table(), true).is_empty());
+    assert!(combine(&vec![], &table(), false).is_empty());
+  }
+
+  #[test]
+  fn machines_can_override_the_rates() {
+    let mut table = table();
+    table.machines.insert(
+      String::from("gas"),
+      ConsumptionRates {
+        heater_kw: 0.0,
+        ..rates()
+      },
+    );
+
+    let estimates = combine(&vec![washing("gas"), washing("electric")], &table, true);
+    assert_close(estimates[0].energy_kwh, 0.0);
+    assert_close(estimates[1].energy_kwh, 6.0);
+  }
+
+  #[test]
+  fn periods_are_kept_or_summed() {
+    let rows = vec![
+      washing("a"),
+      row("b", &[("cold_water_time", 6)]),
+      row("a", &[("heating_time", 1800), ("soap_time_1", 1)]),
+    ];
+
+    let periods = combine(&rows, &table(), true);
+    assert_eq!(periods.len(), 3);
+    assert!(periods
+      .iter()
+      .all(|estimate| estimate.period_start.is_some()));
+
+    let totals = combine(&rows, &table(), false);
+    assert_eq!(totals.len(), 2);
+    assert_eq!(totals[0].machine, "a");
+    assert!(totals[0].period_start.is_none());
+    assert_close(totals[0].energy_kwh, 9.0);
+    assert_close(totals[0].water_l, 20.0);
+    assert_close(totals[0].detergent_ml[0], 22.0);
+    assert_close(totals[0].cost.total, 1.62 + 0.75 + 0.008);
+    assert_eq!(totals[1].machine, "b");
+    assert_close(totals[1].water_l, 1.0);
+  }
+}
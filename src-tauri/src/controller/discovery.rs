@@ -4,15 +4,22 @@ use tokio;
 use tokio::{io, net::UdpSocket};
 
 const BROADCAST_ADDRESS: &str = "255.255.255.255:4040";
+/// Probe the machines answer to, on port 4040
+pub const DISCOVERY_PROBE: &str = "WS2020_ROTONDI_DISCOVERY";
 
 pub async fn poll() -> Result<Vec<(String, String)>, io::Error> {
+  poll_at(BROADCAST_ADDRESS).await
+}
+
+/// Sends the probe to a specific address instead of broadcasting it, e.g. to an emulator
+pub async fn poll_at(address: &str) -> Result<Vec<(String, String)>, io::Error> {
   let socket: UdpSocket = UdpSocket::bind("0.0.0.0:0").await?;
   socket.set_broadcast(true)?;
 
   let mut results: Vec<(String, String)> = vec![];
 
-  let call: Vec<u8> = "WS2020_ROTONDI_DISCOVERY".as_bytes().to_vec();
-  match socket.send_to(&call, BROADCAST_ADDRESS).await {
+  let call: Vec<u8> = DISCOVERY_PROBE.as_bytes().to_vec();
+  match socket.send_to(&call, address).await {
     Ok(n) => {
       if n != call.len() {
         return Err(io::Error::new(
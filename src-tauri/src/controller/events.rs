@@ -0,0 +1,304 @@
+use super::washing_machine::{alarms, Alarm, MachineData, StepType};
+
+/// Something that happened on a machine between two consecutive refreshes
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "event")]
+pub enum MachineEvent {
+  CycleStarted {
+    program: u16,
+  },
+  StepChanged {
+    step_number: u16,
+    step_code: StepType,
+  },
+  CycleCompleted {
+    program: u16,
+  },
+  CycleInterrupted {
+    program: u16,
+  },
+  AlarmRaised {
+    code: u16,
+    alarm: Alarm,
+  },
+  AlarmCleared {
+    code: u16,
+    alarm: Alarm,
+  },
+  PortholeOpened,
+  PortholeClosed,
+  CreditChanged {
+    previous: u16,
+    current: u16,
+  },
+}
+
+/// Compares two snapshots of the same machine and lists the transitions between them
+pub fn diff(previous: &MachineData, current: &MachineData) -> Vec<MachineEvent> {
+  use MachineEvent::*;
+
+  let mut events = vec![];
+  let (before, after) = (&previous.state, &current.state);
+  let was_running = before.state.is_running();
+  let is_running = after.state.is_running();
+
+  if !was_running && is_running {
+    events.push(CycleStarted {
+      program: after.cycle,
+    });
+  } else if was_running && !is_running {
+    let interrupted = if current.stats.interrupted_cycles > previous.stats.interrupted_cycles {
+      true
+    } else if current.stats.cycles > previous.stats.cycles {
+      false
+    } else {
+      // Counters are not always refreshed together with the state, fall back to the progress
+      // of the cycle as last seen
+      before.cycle_remaining > 0 && before.step_number.saturating_add(1) < before.step_count
+    };
+
+    events.push(if interrupted {
+      CycleInterrupted {
+        program: before.cycle,
+      }
+    } else {
+      CycleCompleted {
+        program: before.cycle,
+      }
+    });
+  }
+
+  if is_running && (before.step_number != after.step_number || before.step_code != after.step_code)
+  {
+    events.push(StepChanged {
+      step_number: after.step_number,
+      step_code: after.step_code,
+    });
+  }
+
+  if before.alarm_code != after.alarm_code {
+    if before.alarm_code != 0 {
+      events.push(AlarmCleared {
+        code: before.alarm_code,
+        alarm: alarms::describe(before.alarm_code),
+      });
+    }
+    if after.alarm_code != 0 {
+      events.push(AlarmRaised {
+        code: after.alarm_code,
+        alarm: alarms::describe(after.alarm_code),
+      });
+    }
+  }
+
+  if before.porthole_open != after.porthole_open {
+    events.push(if after.porthole_open {
+      PortholeOpened
+    } else {
+      PortholeClosed
+    });
+  }
+
+  if before.credit != after.credit {
+    events.push(CreditChanged {
+      previous: before.credit,
+      current: after.credit,
+    });
+  }
+
+  events
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::controller::testing::machine_data;
+  use crate::controller::washing_machine::{State, StateCode, Statistics};
+
+  fn snapshot(state: State) -> MachineData {
+    MachineData {
+      state,
+      ..machine_data(Statistics::default())
+    }
+  }
+
+  fn running(step_number: u16, step_count: u16) -> State {
+    State {
+      state: StateCode::Running,
+      cycle: 2,
+      step_number,
+      step_count,
+      step_code: StepType::Wash,
+      cycle_remaining: 600,
+      ..State::default()
+    }
+  }
+
+  #[test]
+  fn nothing_changed() {
+    let data = snapshot(running(1, 5));
+    assert!(diff(&data, &data).is_empty());
+  }
+
+  #[test]
+  fn cycle_started() {
+    let events = diff(&snapshot(State::default()), &snapshot(running(0, 5)));
+    assert!(matches!(
+      events[..],
+      [MachineEvent::CycleStarted { program: 2 }]
+    ));
+  }
+
+  #[test]
+  fn cycle_completed_by_counters() {
+    let before = snapshot(running(1, 5));
+    let mut after = snapshot(State::default());
+    after.stats.cycles = 1;
+    let events = diff(&before, &after);
+    assert!(matches!(
+      events[..],
+      [MachineEvent::CycleCompleted { program: 2 }]
+    ));
+  }
+
+  #[test]
+  fn cycle_interrupted_by_counters() {
+    let before = snapshot(running(4, 5));
+    let mut after = snapshot(State::default());
+    after.stats.interrupted_cycles = 1;
+    let events = diff(&before, &after);
+    assert!(matches!(
+      events[..],
+      [MachineEvent::CycleInterrupted { program: 2 }]
+    ));
+  }
+
+  #[test]
+  fn cycle_end_from_progress() {
+    let after = snapshot(State::default());
+
+    let events = diff(&snapshot(running(1, 5)), &after);
+    assert!(matches!(
+      events[..],
+      [MachineEvent::CycleInterrupted { .. }]
+    ));
+
+    let last_step = State {
+      cycle_remaining: 0,
+      ..running(4, 5)
+    };
+    let events = diff(&snapshot(last_step), &after);
+    assert!(matches!(events[..], [MachineEvent::CycleCompleted { .. }]));
+  }
+
+  #[test]
+  fn cycle_end_with_bogus_step_number() {
+    let events = diff(
+      &snapshot(running(u16::MAX, u16::MAX)),
+      &snapshot(State::default()),
+    );
+    assert!(matches!(events[..], [MachineEvent::CycleCompleted { .. }]));
+  }
+
+  #[test]
+  fn step_changed() {
+    let after = State {
+      step_code: StepType::Drain,
+      ..running(2, 5)
+    };
+    let events = diff(&snapshot(running(1, 5)), &snapshot(after));
+    assert!(matches!(
+      events[..],
+      [MachineEvent::StepChanged {
+        step_number: 2,
+        step_code: StepType::Drain
+      }]
+    ));
+  }
+
+  #[test]
+  fn steps_are_ignored_while_stopped() {
+    let after = State {
+      step_number: 3,
+      ..State::default()
+    };
+    assert!(diff(&snapshot(State::default()), &snapshot(after)).is_empty());
+  }
+
+  #[test]
+  fn alarm_raised_and_cleared() {
+    let idle = snapshot(State::default());
+    let alarm = snapshot(State {
+      alarm_code: 7,
+      ..State::default()
+    });
+
+    let events = diff(&idle, &alarm);
+    assert!(matches!(
+      events[..],
+      [MachineEvent::AlarmRaised { code: 7, .. }]
+    ));
+
+    let events = diff(&alarm, &idle);
+    assert!(matches!(
+      events[..],
+      [MachineEvent::AlarmCleared { code: 7, .. }]
+    ));
+  }
+
+  #[test]
+  fn alarm_replaced() {
+    let before = snapshot(State {
+      alarm_code: 7,
+      ..State::default()
+    });
+    let after = snapshot(State {
+      alarm_code: 9,
+      ..State::default()
+    });
+    let events = diff(&before, &after);
+    assert!(matches!(
+      events[..],
+      [
+        MachineEvent::AlarmCleared { code: 7, .. },
+        MachineEvent::AlarmRaised { code: 9, .. }
+      ]
+    ));
+  }
+
+  #[test]
+  fn porthole_opened_and_closed() {
+    let closed = snapshot(State::default());
+    let open = snapshot(State {
+      porthole_open: true,
+      ..State::default()
+    });
+    assert!(matches!(
+      diff(&closed, &open)[..],
+      [MachineEvent::PortholeOpened]
+    ));
+    assert!(matches!(
+      diff(&open, &closed)[..],
+      [MachineEvent::PortholeClosed]
+    ));
+  }
+
+  #[test]
+  fn credit_changed() {
+    let before = snapshot(State {
+      credit: 5,
+      ..State::default()
+    });
+    let after = snapshot(State {
+      credit: 3,
+      ..State::default()
+    });
+    assert!(matches!(
+      diff(&before, &after)[..],
+      [MachineEvent::CreditChanged {
+        previous: 5,
+        current: 3
+      }]
+    ));
+  }
+}
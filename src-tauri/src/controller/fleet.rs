@@ -0,0 +1,362 @@
+//! Rollout of one configuration archive to many machines at once.
+use super::washing_machine::{self as ws, WashingMachineConnection};
+use super::{Controller, Error, SharedConnection};
+use futures::future;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A machine to push the configuration to, either on the local network or through Things5
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum FleetTarget {
+  Local(String),
+  Things5(String),
+}
+
+impl FleetTarget {
+  /// Same id the backend uses for the machine once connected
+  pub fn id(self: &Self) -> &String {
+    match self {
+      FleetTarget::Local(ip) => ip,
+      FleetTarget::Things5(device_id) => device_id,
+    }
+  }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub enum Stage {
+  Connecting,
+  Uploading,
+  Selecting,
+  Done,
+  Failed(Error),
+}
+
+#[derive(Clone, serde::Serialize)]
+struct FleetProgress {
+  job: Option<u64>,
+  machine: String,
+  stage: Stage,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct FleetOutcome {
+  pub machine: String,
+  pub result: Result<(), Error>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct FleetReport {
+  pub job: Option<u64>,
+  pub succeeded: usize,
+  pub failed: usize,
+  pub outcomes: Vec<FleetOutcome>,
+}
+
+/// Uploads the archive to every target in parallel, optionally activating `select` afterwards.
+///
+/// Machines that are already connected reuse their connection, the others are connected just for
+/// the upload. Progress is emitted as `fleetProgress` for every machine and stage, the final
+/// report as `fleetReport`.
+pub(super) async fn push(
+  controller: Controller,
+  job: Option<u64>,
+  targets: Vec<FleetTarget>,
+  connected: HashMap<String, SharedConnection>,
+  token: Option<String>,
+  archive: Vec<u8>,
+  select: Option<String>,
+) -> FleetReport {
+  let progress = |machine: &String, stage: Stage| {
+    controller.emit(
+      "fleetProgress",
+      FleetProgress {
+        job,
+        machine: machine.clone(),
+        stage,
+      },
+    );
+  };
+  let finished = |machine: &String, name: Option<String>, result: &Result<(), Error>| {
+    controller.context.history.record_command(
+      machine,
+      name.unwrap_or_default(),
+      "FleetConfigurationPush",
+      result,
+    );
+  };
+
+  let outcomes = push_all(
+    targets, connected, token, archive, select, &progress, &finished,
+  )
+  .await;
+  let report = report(job, outcomes);
+
+  log::info!(
+    "Configuration pushed to {} machines, {} failed",
+    report.succeeded,
+    report.failed
+  );
+  controller.emit("fleetReport", &report);
+  report
+}
+
+/// Body of `push`, reporting the progress and the outcome of every machine to the callbacks
+async fn push_all(
+  targets: Vec<FleetTarget>,
+  connected: HashMap<String, SharedConnection>,
+  token: Option<String>,
+  archive: Vec<u8>,
+  select: Option<String>,
+  progress: &(impl Fn(&String, Stage) + Sync),
+  finished: &(impl Fn(&String, Option<String>, &Result<(), Error>) + Sync),
+) -> Vec<FleetOutcome> {
+  let archive = Arc::new(archive);
+
+  let uploads = targets.into_iter().map(|target| {
+    let connection = connected.get(target.id()).cloned();
+    let token = token.clone();
+    let archive = archive.clone();
+    let select = select.clone();
+
+    async move {
+      let machine = target.id().clone();
+      let progress = |stage: Stage| progress(&machine, stage);
+
+      let connection = match connection {
+        Some(connection) => Ok(connection),
+        None => {
+          progress(Stage::Connecting);
+          connect(&target, token).await
+        }
+      };
+      let (name, result) = match connection {
+        Ok(connection) => (
+          connection
+            .get_connection_state()
+            .data()
+            .map(|data| data.name.clone()),
+          upload(connection.as_ref(), archive.as_ref(), select, &progress).await,
+        ),
+        Err(e) => (None, Err(e)),
+      };
+      progress(match result {
+        Ok(()) => Stage::Done,
+        Err(ref e) => Stage::Failed(e.clone()),
+      });
+
+      finished(&machine, name, &result);
+      FleetOutcome { machine, result }
+    }
+  });
+
+  future::join_all(uploads).await
+}
+
+fn report(job: Option<u64>, outcomes: Vec<FleetOutcome>) -> FleetReport {
+  let succeeded = outcomes.iter().filter(|o| o.result.is_ok()).count();
+  FleetReport {
+    job,
+    succeeded,
+    failed: outcomes.len() - succeeded,
+    outcomes,
+  }
+}
+
+async fn connect(target: &FleetTarget, token: Option<String>) -> Result<SharedConnection, Error> {
+  let connection: SharedConnection = match target {
+    FleetTarget::Local(ip) => Arc::new(ws::local::Connection::new(ip.clone()).await),
+    FleetTarget::Things5(device_id) => {
+      Arc::new(ws::things5::Connection::new(token.ok_or(Error::Value)?, device_id.clone()).await)
+    }
+  };
+
+  if connection.get_connection_state().is_connected() {
+    Ok(connection)
+  } else {
+    Err(Error::Network(format!(
+      "Unable to connect to {}",
+      target.id()
+    )))
+  }
+}
+
+async fn upload(
+  connection: &dyn WashingMachineConnection,
+  archive: &Vec<u8>,
+  select: Option<String>,
+  progress: &(impl Fn(Stage) + Sync),
+) -> Result<(), Error> {
+  // Leave the machine untouched rather than with an archive that cannot be activated
+  if select.is_some() && !connection.capabilities().select_configuration {
+    return Err(Error::Unsupported);
+  }
+
+  progress(Stage::Uploading);
+  connection
+    .send_machine_configuration(archive.clone())
+    .await?;
+
+  if let Some(archive) = select {
+    progress(Stage::Selecting);
+    connection.select_machine_configuration(archive).await?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::controller::washing_machine::simulated;
+  use crate::emulator::{Emulator, Fault};
+  use std::sync::Mutex;
+
+  fn outcome(machine: &str, result: Result<(), Error>) -> FleetOutcome {
+    FleetOutcome {
+      machine: String::from(machine),
+      result,
+    }
+  }
+
+  fn simulated_machine() -> simulated::Connection {
+    simulated::Connection::new(
+      String::from("Simulated"),
+      simulated::Connection::demo_configuration(),
+    )
+  }
+
+  #[test]
+  fn report_of_no_machines() {
+    let report = report(Some(1), vec![]);
+    assert_eq!(report.job, Some(1));
+    assert_eq!(report.succeeded, 0);
+    assert_eq!(report.failed, 0);
+    assert!(report.outcomes.is_empty());
+  }
+
+  #[test]
+  fn report_counts_outcomes() {
+    let report = report(
+      None,
+      vec![
+        outcome("10.0.0.1", Ok(())),
+        outcome("10.0.0.2", Err(Error::Unsupported)),
+        outcome("device", Err(Error::NotConnected)),
+        outcome("10.0.0.3", Ok(())),
+      ],
+    );
+    assert_eq!(report.succeeded, 2);
+    assert_eq!(report.failed, 2);
+    assert_eq!(report.outcomes.len(), 4);
+    assert_eq!(report.outcomes[1].machine, "10.0.0.2");
+  }
+
+  #[tokio::test]
+  async fn upload_and_select() {
+    let emulator = Emulator::new(String::from("1"), 1);
+    let address = emulator.serve_http("127.0.0.1:0").await.unwrap();
+    let connection = ws::local::Connection::new(address.to_string()).await;
+
+    let stages = Mutex::new(vec![]);
+    let result = upload(
+      &connection,
+      &vec![1, 2, 3],
+      Some(String::from("winter")),
+      &|stage| stages.lock().unwrap().push(stage),
+    )
+    .await;
+
+    assert!(result.is_ok());
+    assert!(matches!(
+      stages.lock().unwrap()[..],
+      [Stage::Uploading, Stage::Selecting]
+    ));
+    assert_eq!(emulator.archive(), vec![1, 2, 3]);
+    assert_eq!(emulator.selected_archive(), Some(String::from("winter")));
+  }
+
+  #[tokio::test]
+  async fn boards_refusing_the_archive_are_failures() {
+    let mut emulators = vec![];
+    let mut targets = vec![];
+    for _ in 0..3 {
+      let emulator = Emulator::new(String::from("1"), 1);
+      let address = emulator.serve_http("127.0.0.1:0").await.unwrap();
+      targets.push(FleetTarget::Local(address.to_string()));
+      emulators.push(emulator);
+    }
+    emulators[0].script("machine", vec![Fault::Status(500)]);
+    emulators[1].script("select_machine", vec![Fault::Status(400)]);
+
+    let stages = Mutex::new(vec![]);
+    let recorded = Mutex::new(vec![]);
+    let outcomes = push_all(
+      targets.clone(),
+      HashMap::new(),
+      None,
+      vec![1, 2, 3],
+      Some(String::from("winter")),
+      &|machine: &String, stage| stages.lock().unwrap().push((machine.clone(), stage)),
+      &|machine: &String, _, result: &Result<(), Error>| {
+        recorded
+          .lock()
+          .unwrap()
+          .push((machine.clone(), result.is_ok()))
+      },
+    )
+    .await;
+    let report = report(Some(7), outcomes);
+
+    assert_eq!(report.job, Some(7));
+    assert_eq!(report.succeeded, 1);
+    assert_eq!(report.failed, 2);
+    assert!(matches!(report.outcomes[0].result, Err(Error::Server(_))));
+    assert!(matches!(report.outcomes[1].result, Err(Error::Server(_))));
+    assert!(report.outcomes[2].result.is_ok());
+    assert_eq!(
+      emulators[2].selected_archive(),
+      Some(String::from("winter"))
+    );
+    assert_eq!(emulators[1].selected_archive(), None);
+
+    let mut failed: Vec<String> = stages
+      .lock()
+      .unwrap()
+      .iter()
+      .filter(|(_, stage)| matches!(stage, Stage::Failed(_)))
+      .map(|(machine, _)| machine.clone())
+      .collect();
+    // Machines are pushed to in parallel, their stages come in any order
+    failed.sort();
+    let mut refused = vec![targets[0].id().clone(), targets[1].id().clone()];
+    refused.sort();
+    assert_eq!(failed, refused);
+    assert_eq!(recorded.lock().unwrap().len(), 3);
+  }
+
+  #[tokio::test]
+  async fn machines_that_cannot_select_are_left_untouched() {
+    let stages = Mutex::new(vec![]);
+    let result = upload(
+      &simulated_machine(),
+      &vec![1, 2, 3],
+      Some(String::from("winter")),
+      &|stage| stages.lock().unwrap().push(stage),
+    )
+    .await;
+
+    assert!(matches!(result, Err(Error::Unsupported)));
+    assert!(stages.lock().unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn failed_uploads_stop_at_the_upload() {
+    let stages = Mutex::new(vec![]);
+    let result = upload(&simulated_machine(), &vec![1, 2, 3], None, &|stage| {
+      stages.lock().unwrap().push(stage)
+    })
+    .await;
+
+    assert!(matches!(result, Err(Error::Unsupported)));
+    assert!(matches!(stages.lock().unwrap()[..], [Stage::Uploading]));
+  }
+}
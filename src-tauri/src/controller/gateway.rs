@@ -0,0 +1,215 @@
+//! Local HTTP/WebSocket gateway that exposes the connected machines to other in-house tools.
+//!
+//! The gateway only listens on loopback addresses and every request must carry the shared token
+//! from `prefs::gateway_token` as `Authorization: Bearer <token>`. No CORS headers are sent, so
+//! web pages cannot call it from a browser.
+//!
+//! - `GET /machines`: state of every connected machine
+//! - `GET /machines/{id}/state`, `GET /machines/{id}/statistics`
+//! - `POST /machines/{id}/start/{program}`
+//! - `POST /machines/{id}/{restart|pause|stop|clear_alarms}`
+//! - `GET /events`: WebSocket stream of state updates
+use super::washing_machine::ConnectionState;
+use super::{BackEndPortMessage, Error, Handle, MachineUpdate};
+use futures::{SinkExt, StreamExt};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+use warp::http::StatusCode;
+use warp::ws::{Message, WebSocket};
+use warp::Filter;
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+pub async fn serve(address: SocketAddr, token: String, handle: Handle) {
+  let routes = authorized(token).and(routes(handle)).recover(rejection);
+
+  match warp::serve(routes).try_bind_ephemeral(address) {
+    Ok((address, server)) => {
+      log::info!("Gateway listening on {}", address);
+      server.await;
+    }
+    Err(e) => log::error!("Unable to start the gateway on {}: {:?}", address, e),
+  }
+}
+
+/// Passes the requests carrying the token, rejecting the others
+fn authorized(token: String) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+  let expected = format!("Bearer {}", token);
+  warp::header::optional::<String>("authorization")
+    .and_then(move |header: Option<String>| {
+      let authorized = header.as_deref() == Some(expected.as_str());
+      async move {
+        if authorized {
+          Ok(())
+        } else {
+          Err(warp::reject::custom(Unauthorized))
+        }
+      }
+    })
+    .untuple_one()
+}
+
+async fn rejection(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+  if rejection.find::<Unauthorized>().is_some() {
+    Ok(warp::reply::with_status(
+      warp::reply::json(&"Unauthorized"),
+      StatusCode::UNAUTHORIZED,
+    ))
+  } else {
+    Err(rejection)
+  }
+}
+
+fn routes(
+  handle: Handle,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+  let with_handle = warp::any().map(move || handle.clone());
+
+  let machines = warp::path!("machines")
+    .and(warp::get())
+    .and(with_handle.clone())
+    .and_then(list_machines);
+
+  let state = warp::path!("machines" / String / "state")
+    .and(warp::get())
+    .and(with_handle.clone())
+    .and_then(machine_state);
+
+  let statistics = warp::path!("machines" / String / "statistics")
+    .and(warp::get())
+    .and(with_handle.clone())
+    .and_then(machine_statistics);
+
+  let start = warp::path!("machines" / String / "start" / u16)
+    .and(warp::post())
+    .and(with_handle.clone())
+    .and_then(start_program);
+
+  let command = warp::path!("machines" / String / String)
+    .and(warp::post())
+    .and(with_handle.clone())
+    .and_then(machine_command);
+
+  let events = warp::path!("events").and(warp::ws()).and(with_handle).map(
+    |ws: warp::ws::Ws, handle: Handle| {
+      let updates = handle.subscribe();
+      ws.on_upgrade(move |socket| stream_updates(socket, updates))
+    },
+  );
+
+  machines
+    .or(state)
+    .or(statistics)
+    .or(start)
+    .or(command)
+    .or(events)
+}
+
+fn reply<T: serde::Serialize>(
+  result: Result<T, Error>,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+  match result {
+    Ok(value) => warp::reply::with_status(warp::reply::json(&value), StatusCode::OK),
+    Err(e) => {
+      let status = match e {
+        Error::NotConnected => StatusCode::NOT_FOUND,
+        Error::Network(_) => StatusCode::BAD_GATEWAY,
+        Error::Unsupported => StatusCode::NOT_IMPLEMENTED,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+      };
+      warp::reply::with_status(warp::reply::json(&e), status)
+    }
+  }
+}
+
+async fn machine(handle: &Handle, id: String) -> Result<ConnectionState, Error> {
+  handle
+    .request::<Vec<MachineUpdate>>(Some(id), BackEndPortMessage::GetMachines)
+    .await?
+    .into_iter()
+    .next()
+    .and_then(|update| update.state)
+    .ok_or(Error::NotConnected)
+}
+
+async fn list_machines(handle: Handle) -> Result<impl warp::Reply, Infallible> {
+  Ok(reply(
+    handle
+      .request::<Vec<MachineUpdate>>(None, BackEndPortMessage::GetMachines)
+      .await,
+  ))
+}
+
+async fn machine_state(id: String, handle: Handle) -> Result<impl warp::Reply, Infallible> {
+  Ok(reply(machine(&handle, id).await))
+}
+
+async fn machine_statistics(id: String, handle: Handle) -> Result<impl warp::Reply, Infallible> {
+  Ok(reply(machine(&handle, id).await.and_then(
+    |state| match state.data() {
+      Some(data) => Ok(data.stats.clone()),
+      None => Err(Error::NotConnected),
+    },
+  )))
+}
+
+async fn start_program(
+  id: String,
+  program: u16,
+  handle: Handle,
+) -> Result<impl warp::Reply, Infallible> {
+  Ok(reply(
+    handle
+      .request::<()>(Some(id), BackEndPortMessage::StartProgram(program))
+      .await,
+  ))
+}
+
+async fn machine_command(
+  id: String,
+  command: String,
+  handle: Handle,
+) -> Result<warp::reply::WithStatus<warp::reply::Json>, warp::Rejection> {
+  let message = match command.as_str() {
+    "restart" => BackEndPortMessage::Restart,
+    "pause" => BackEndPortMessage::Pause,
+    "stop" => BackEndPortMessage::Stop,
+    "clear_alarms" => BackEndPortMessage::ClearAlarms,
+    _ => return Err(warp::reject::not_found()),
+  };
+
+  Ok(reply(handle.request::<()>(Some(id), message).await))
+}
+
+async fn stream_updates(socket: WebSocket, mut updates: broadcast::Receiver<MachineUpdate>) {
+  let (mut sink, mut stream) = socket.split();
+
+  loop {
+    tokio::select! {
+      update = updates.recv() => match update {
+        Ok(update) => {
+          let text = match serde_json::to_string(&update) {
+            Ok(text) => text,
+            Err(_) => continue,
+          };
+          if sink.send(Message::text(text)).await.is_err() {
+            break;
+          }
+        }
+        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+          log::warn!("Gateway client lagging, {} updates skipped", skipped)
+        }
+        Err(broadcast::error::RecvError::Closed) => break,
+      },
+
+      message = stream.next() => match message {
+        Some(Ok(message)) if !message.is_close() => (),
+        _ => break,
+      },
+    }
+  }
+}
@@ -0,0 +1,96 @@
+use super::history::HistoryStore;
+use super::{BackEndPortMessage, Error, MachineUpdate};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+pub type Reply = oneshot::Sender<Result<serde_json::Value, Error>>;
+
+/// A message for the backend loop, optionally carrying a channel for the reply
+pub struct Request {
+  pub id: Option<u64>,
+  pub machine: Option<String>,
+  pub message: BackEndPortMessage,
+  pub reply: Option<Reply>,
+}
+
+/// Sends the outcome of a request back to whoever is waiting for it, if anyone
+pub fn respond<T: serde::Serialize>(reply: Option<Reply>, result: Result<T, Error>) {
+  if let Some(reply) = reply {
+    let value =
+      result.and_then(|value| serde_json::to_value(value).map_err(|e| Error::Json(e.to_string())));
+    reply.send(value).ok();
+  }
+}
+
+/// Entry point to the backend loop for the Tauri commands.
+///
+/// The sender is replaced every time the supervisor restarts the loop, so clones of the handle
+/// stay valid across restarts.
+#[derive(Clone)]
+pub struct Handle {
+  sender: Arc<Mutex<Option<mpsc::UnboundedSender<Request>>>>,
+  updates: broadcast::Sender<MachineUpdate>,
+  history: Arc<HistoryStore>,
+}
+
+impl Default for Handle {
+  fn default() -> Self {
+    let (updates, _) = broadcast::channel(64);
+    Self {
+      sender: Arc::new(Mutex::new(None)),
+      updates,
+      history: Arc::new(HistoryStore::default()),
+    }
+  }
+}
+
+impl Handle {
+  pub(super) fn updates(self: &Self) -> broadcast::Sender<MachineUpdate> {
+    self.updates.clone()
+  }
+
+  /// Cycles, alarms and commands recorded for every machine the backend has been connected to
+  pub fn history(self: &Self) -> Arc<HistoryStore> {
+    self.history.clone()
+  }
+
+  /// Every state update sent to the frontend, for all connected machines
+  pub fn subscribe(self: &Self) -> broadcast::Receiver<MachineUpdate> {
+    self.updates.subscribe()
+  }
+
+  pub(super) fn attach(self: &Self, sender: mpsc::UnboundedSender<Request>) {
+    *self.sender.lock().unwrap() = Some(sender);
+  }
+
+  pub(super) fn send(self: &Self, request: Request) -> Result<(), Error> {
+    self
+      .sender
+      .lock()
+      .unwrap()
+      .as_ref()
+      .ok_or(Error::Server(String::from("Backend not running")))?
+      .send(request)
+      .map_err(|_| Error::Server(String::from("Backend not running")))
+  }
+
+  /// Sends a message to the backend loop and waits for its reply
+  pub async fn request<T: serde::de::DeserializeOwned>(
+    self: &Self,
+    machine: Option<String>,
+    message: BackEndPortMessage,
+  ) -> Result<T, Error> {
+    let (reply, response) = oneshot::channel();
+    self.send(Request {
+      id: None,
+      machine,
+      message,
+      reply: Some(reply),
+    })?;
+
+    let value = response
+      .await
+      .map_err(|_| Error::Server(String::from("Backend stopped before replying")))??;
+    serde_json::from_value(value).map_err(|e| Error::Json(e.to_string()))
+  }
+}
@@ -0,0 +1,509 @@
+//! Append-only log of what happened on the connected machines, kept in a JSON lines file in the
+//! application data directory.
+//!
+//! Once the file grows past `MAX_FILE_SIZE` it is moved aside, replacing the one moved aside
+//! before, so the history keeps between one and two files worth of the most recent entries.
+use super::events::MachineEvent;
+use super::washing_machine::MachineData;
+use super::Error;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const HISTORY_FILE: &str = "history.jsonl";
+/// Size past which the history file is rotated
+const MAX_FILE_SIZE: u64 = 8 * 1024 * 1024;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind")]
+pub enum Record {
+  Cycle {
+    program: u16,
+    program_name: Option<String>,
+    started: DateTime<Utc>,
+    duration_secs: i64,
+    completed: bool,
+  },
+  AlarmRaised {
+    code: u16,
+  },
+  AlarmCleared {
+    code: u16,
+    duration_secs: Option<i64>,
+  },
+  Command {
+    command: String,
+    error: Option<String>,
+  },
+}
+
+impl Record {
+  fn kind(self: &Self) -> &'static str {
+    match self {
+      Record::Cycle { .. } => "Cycle",
+      Record::AlarmRaised { .. } => "AlarmRaised",
+      Record::AlarmCleared { .. } => "AlarmCleared",
+      Record::Command { .. } => "Command",
+    }
+  }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct HistoryEntry {
+  pub timestamp: DateTime<Utc>,
+  pub machine: String,
+  pub machine_name: String,
+  #[serde(flatten)]
+  pub record: Record,
+}
+
+/// Filter and page for a history query; entries are returned newest first
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct HistoryQuery {
+  pub machine: Option<String>,
+  pub kinds: Option<Vec<String>>,
+  pub from: Option<DateTime<Utc>>,
+  pub to: Option<DateTime<Utc>>,
+  pub offset: usize,
+  pub limit: Option<usize>,
+}
+
+impl HistoryQuery {
+  fn matches(self: &Self, entry: &HistoryEntry) -> bool {
+    self.machine.as_ref().map_or(true, |m| *m == entry.machine)
+      && self
+        .kinds
+        .as_ref()
+        .map_or(true, |kinds| kinds.iter().any(|k| k == entry.record.kind()))
+      && self.from.map_or(true, |from| entry.timestamp >= from)
+      && self.to.map_or(true, |to| entry.timestamp <= to)
+  }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct HistoryPage {
+  pub total: usize,
+  pub entries: Vec<HistoryEntry>,
+}
+
+/// Cycles and alarms that are still in progress, waiting for their end to be recorded
+#[derive(Default)]
+struct Pending {
+  cycles: HashMap<String, (DateTime<Utc>, u16)>,
+  alarms: HashMap<(String, u16), DateTime<Utc>>,
+}
+
+pub struct HistoryStore {
+  path: Option<PathBuf>,
+  max_size: u64,
+  pending: Mutex<Pending>,
+  /// Held while appending, so that concurrent writers do not rotate the file under each other
+  writing: Mutex<()>,
+}
+
+/// Where the history file is moved when it is rotated
+fn rotated(path: &Path) -> PathBuf {
+  path.with_extension("jsonl.1")
+}
+
+impl Default for HistoryStore {
+  fn default() -> Self {
+    Self::new(super::prefs::data_file(HISTORY_FILE))
+  }
+}
+
+impl HistoryStore {
+  /// A store backed by the given file; without a path nothing is persisted
+  pub fn new(path: Option<PathBuf>) -> Self {
+    if path.is_none() {
+      log::warn!("No data directory available, history will not be saved");
+    }
+    Self {
+      path,
+      max_size: MAX_FILE_SIZE,
+      pending: Mutex::new(Pending::default()),
+      writing: Mutex::new(()),
+    }
+  }
+
+  /// Moves the file aside once it is full, dropping the one moved aside before
+  fn rotate(self: &Self, path: &Path) -> io::Result<()> {
+    match fs::metadata(path) {
+      Ok(metadata) if metadata.len() >= self.max_size => {
+        log::info!("Rotating history file {:?}", path);
+        fs::rename(path, rotated(path))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  fn append(self: &Self, entry: HistoryEntry) {
+    let path = match self.path {
+      Some(ref path) => path,
+      None => return,
+    };
+
+    let _writing = self.writing.lock().unwrap();
+    let result = path
+      .parent()
+      .map_or(Ok(()), fs::create_dir_all)
+      .and_then(|_| self.rotate(path))
+      .and_then(|_| OpenOptions::new().create(true).append(true).open(path))
+      .and_then(|mut file| {
+        let line = serde_json::to_string(&entry)?;
+        writeln!(file, "{}", line)
+      });
+
+    if let Err(e) = result {
+      log::error!("Unable to write history to {:?}: {:?}", path, e);
+    }
+  }
+
+  /// Records the entries matching a machine event, if it is one the history cares about
+  pub fn record_event(self: &Self, machine: &String, data: &MachineData, event: &MachineEvent) {
+    let now = Utc::now();
+    let mut pending = self.pending.lock().unwrap();
+
+    let record = match *event {
+      MachineEvent::CycleStarted { program } => {
+        pending.cycles.insert(machine.clone(), (now, program));
+        None
+      }
+      MachineEvent::CycleCompleted { program } | MachineEvent::CycleInterrupted { program } => {
+        let started = match pending.cycles.remove(machine) {
+          Some((started, _)) => started,
+          // The cycle was already running when we connected; the elapsed time is unknown
+          None => now,
+        };
+        Some(Record::Cycle {
+          program,
+          program_name: data
+            .configuration
+            .programs
+            .get(program as usize)
+            .map(|p| p.name.clone()),
+          started,
+          duration_secs: (now - started).num_seconds(),
+          completed: matches!(event, MachineEvent::CycleCompleted { .. }),
+        })
+      }
+      MachineEvent::AlarmRaised { code, .. } => {
+        pending.alarms.insert((machine.clone(), code), now);
+        Some(Record::AlarmRaised { code })
+      }
+      MachineEvent::AlarmCleared { code, .. } => Some(Record::AlarmCleared {
+        code,
+        duration_secs: pending
+          .alarms
+          .remove(&(machine.clone(), code))
+          .map(|raised| (now - raised).num_seconds()),
+      }),
+      _ => None,
+    };
+    drop(pending);
+
+    if let Some(record) = record {
+      self.append(HistoryEntry {
+        timestamp: now,
+        machine: machine.clone(),
+        machine_name: data.name.clone(),
+        record,
+      });
+    }
+  }
+
+  pub fn record_command(
+    self: &Self,
+    machine: &String,
+    machine_name: String,
+    command: &str,
+    result: &Result<(), Error>,
+  ) {
+    self.append(HistoryEntry {
+      timestamp: Utc::now(),
+      machine: machine.clone(),
+      machine_name,
+      record: Record::Command {
+        command: String::from(command),
+        error: result.as_ref().err().map(|e| format!("{:?}", e)),
+      },
+    });
+  }
+
+  /// Reads both the rotated and the current file, keeping in memory only the entries of the page
+  pub fn query(self: &Self, query: &HistoryQuery) -> Result<HistoryPage, Error> {
+    let path = match self.path {
+      Some(ref path) => path,
+      None => {
+        return Ok(HistoryPage {
+          total: 0,
+          entries: vec![],
+        })
+      }
+    };
+
+    // Entries are read oldest first, the newest `wanted` ones make up the page and the ones
+    // skipped by the offset
+    let wanted = query
+      .offset
+      .saturating_add(query.limit.unwrap_or(usize::MAX));
+    let mut total = 0;
+    let mut newest = VecDeque::new();
+
+    for path in [rotated(path), path.clone()].iter().filter(|p| p.exists()) {
+      let file = File::open(path).map_err(|e| Error::Server(e.to_string()))?;
+      let entries = BufReader::new(file)
+        .lines()
+        .filter_map(|line| line.ok())
+        .filter_map(|line| serde_json::from_str::<HistoryEntry>(line.as_str()).ok())
+        .filter(|entry| query.matches(entry));
+
+      for entry in entries {
+        total += 1;
+        newest.push_back(entry);
+        if newest.len() > wanted {
+          newest.pop_front();
+        }
+      }
+    }
+
+    let entries = newest.into_iter().rev().skip(query.offset).collect();
+    Ok(HistoryPage { total, entries })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::controller::testing::machine_data;
+  use crate::controller::washing_machine::{alarms, simulated, Statistics};
+
+  /// A store writing to a file of its own in the temporary directory
+  fn store(name: &str) -> HistoryStore {
+    let path = std::env::temp_dir().join(format!("history-{}-{}.jsonl", name, std::process::id()));
+    fs::remove_file(&path).ok();
+    fs::remove_file(rotated(&path)).ok();
+    HistoryStore::new(Some(path))
+  }
+
+  fn remove(store: HistoryStore) {
+    let path = store.path.unwrap();
+    fs::remove_file(rotated(&path)).ok();
+    fs::remove_file(path).ok();
+  }
+
+  fn data() -> MachineData {
+    MachineData {
+      configuration: simulated::Connection::demo_configuration(),
+      ..machine_data(Statistics::default())
+    }
+  }
+
+  fn all(store: &HistoryStore) -> Vec<HistoryEntry> {
+    store.query(&HistoryQuery::default()).unwrap().entries
+  }
+
+  fn command(store: &HistoryStore, machine: &str, command: &str) {
+    store.record_command(&String::from(machine), String::new(), command, &Ok(()));
+  }
+
+  /// Commands of the page, oldest first
+  fn commands(page: &HistoryPage) -> Vec<String> {
+    page
+      .entries
+      .iter()
+      .rev()
+      .map(|entry| match entry.record {
+        Record::Command { ref command, .. } => command.clone(),
+        ref record => panic!("Unexpected record {:?}", record),
+      })
+      .collect()
+  }
+
+  #[test]
+  fn cycles() {
+    let store = store("cycles");
+    let machine = String::from("10.0.0.1");
+
+    store.record_event(
+      &machine,
+      &data(),
+      &MachineEvent::CycleStarted { program: 1 },
+    );
+    assert!(all(&store).is_empty());
+    store.record_event(
+      &machine,
+      &data(),
+      &MachineEvent::CycleCompleted { program: 1 },
+    );
+    // Connected while the cycle was running
+    store.record_event(
+      &machine,
+      &data(),
+      &MachineEvent::CycleInterrupted { program: 9 },
+    );
+
+    let entries = all(&store);
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[1].machine, machine);
+    assert_eq!(entries[1].machine_name, "Lavanderia");
+    assert!(matches!(
+      entries[1].record,
+      Record::Cycle {
+        program: 1,
+        program_name: Some(_),
+        duration_secs: 0,
+        completed: true,
+        ..
+      }
+    ));
+    assert!(matches!(
+      entries[0].record,
+      Record::Cycle {
+        program: 9,
+        program_name: None,
+        completed: false,
+        ..
+      }
+    ));
+    remove(store);
+  }
+
+  #[test]
+  fn alarms() {
+    let store = store("alarms");
+    let machine = String::from("10.0.0.1");
+    let raised = |code| MachineEvent::AlarmRaised {
+      code,
+      alarm: alarms::describe(code),
+    };
+    let cleared = |code| MachineEvent::AlarmCleared {
+      code,
+      alarm: alarms::describe(code),
+    };
+
+    store.record_event(&machine, &data(), &raised(3));
+    store.record_event(&machine, &data(), &cleared(3));
+    store.record_event(&machine, &data(), &cleared(4));
+    // Events that are not worth keeping
+    store.record_event(&machine, &data(), &MachineEvent::PortholeOpened);
+
+    let entries = all(&store);
+    assert_eq!(entries.len(), 3);
+    assert!(matches!(entries[2].record, Record::AlarmRaised { code: 3 }));
+    assert!(matches!(
+      entries[1].record,
+      Record::AlarmCleared {
+        code: 3,
+        duration_secs: Some(0)
+      }
+    ));
+    assert!(matches!(
+      entries[0].record,
+      Record::AlarmCleared {
+        code: 4,
+        duration_secs: None
+      }
+    ));
+    remove(store);
+  }
+
+  #[test]
+  fn queries() {
+    let store = store("queries");
+    assert_eq!(store.query(&HistoryQuery::default()).unwrap().total, 0);
+
+    for i in 0..5 {
+      command(&store, "10.0.0.1", &format!("A{}", i));
+      command(&store, "10.0.0.2", &format!("B{}", i));
+    }
+    store.record_event(
+      &String::from("10.0.0.1"),
+      &data(),
+      &MachineEvent::AlarmRaised {
+        code: 3,
+        alarm: alarms::describe(3),
+      },
+    );
+
+    let page = store
+      .query(&HistoryQuery {
+        machine: Some(String::from("10.0.0.1")),
+        kinds: Some(vec![String::from("Command")]),
+        offset: 1,
+        limit: Some(2),
+        ..HistoryQuery::default()
+      })
+      .unwrap();
+    assert_eq!(page.total, 5);
+    assert_eq!(commands(&page), vec!["A2", "A3"]);
+
+    let page = store
+      .query(&HistoryQuery {
+        kinds: Some(vec![String::from("AlarmRaised")]),
+        ..HistoryQuery::default()
+      })
+      .unwrap();
+    assert_eq!(page.total, 1);
+    assert_eq!(page.entries.len(), 1);
+
+    // Past the end
+    let page = store
+      .query(&HistoryQuery {
+        offset: 20,
+        ..HistoryQuery::default()
+      })
+      .unwrap();
+    assert_eq!(page.total, 11);
+    assert!(page.entries.is_empty());
+
+    let oldest = all(&store).last().unwrap().timestamp;
+    let page = store
+      .query(&HistoryQuery {
+        to: Some(oldest - chrono::Duration::seconds(1)),
+        ..HistoryQuery::default()
+      })
+      .unwrap();
+    assert_eq!(page.total, 0);
+    remove(store);
+  }
+
+  #[test]
+  fn full_files_are_rotated() {
+    let mut store = store("rotation");
+    command(&store, "10.0.0.1", "C0");
+    let size = fs::metadata(store.path.as_ref().unwrap()).unwrap().len();
+    // Full after three commands, whatever the length of their timestamps
+    store.max_size = size * 5 / 2;
+
+    for i in 1..5 {
+      command(&store, "10.0.0.1", &format!("C{}", i));
+    }
+    assert!(rotated(store.path.as_ref().unwrap()).exists());
+    let page = store.query(&HistoryQuery::default()).unwrap();
+    assert_eq!(page.total, 5);
+    assert_eq!(commands(&page), vec!["C0", "C1", "C2", "C3", "C4"]);
+
+    // Pages span both files
+    let page = store
+      .query(&HistoryQuery {
+        offset: 1,
+        limit: Some(3),
+        ..HistoryQuery::default()
+      })
+      .unwrap();
+    assert_eq!(commands(&page), vec!["C1", "C2", "C3"]);
+
+    // The second rotation drops the oldest entries
+    for i in 5..7 {
+      command(&store, "10.0.0.1", &format!("C{}", i));
+    }
+    let page = store.query(&HistoryQuery::default()).unwrap();
+    assert_eq!(commands(&page), vec!["C3", "C4", "C5", "C6"]);
+    remove(store);
+  }
+}
@@ -0,0 +1,33 @@
+//! HTTP client shared by every connection, so that requests to the machines and to Things5 all
+//! use the same settings and the same pool of connections.
+//!
+//! Pooled connections are driven by the runtime that opened them, while `supervise` rebuilds the
+//! backend runtime after a crash. Every new runtime therefore calls `renew`, so that no request
+//! ends up on a connection whose runtime is gone; connections created before keep the client they
+//! were given and go away with their runtime.
+use once_cell::sync::Lazy;
+use reqwest::{Client, ClientBuilder};
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Default timeout of a request; single requests can override it
+pub const TIMEOUT: Duration = Duration::from_secs(4);
+
+static CLIENT: Lazy<RwLock<Client>> = Lazy::new(|| RwLock::new(build()));
+
+fn build() -> Client {
+  ClientBuilder::new()
+    .timeout(TIMEOUT)
+    .build()
+    .expect("Failed to build HTTP client")
+}
+
+/// Handle to the shared client; clones are cheap and share the pool
+pub fn client() -> Client {
+  CLIENT.read().unwrap().clone()
+}
+
+/// Replaces the shared client with one with an empty pool, for a new runtime
+pub fn renew() {
+  *CLIENT.write().unwrap() = build();
+}
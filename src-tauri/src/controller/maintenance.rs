@@ -0,0 +1,498 @@
+//! Service intervals based on the machine counters, e.g. "door gasket every 20 000 porthole
+//! closings". The plan is saved in the application data directory together with the counter
+//! value at the last service of every machine.
+use super::washing_machine::{MachineData, Statistics};
+use super::Error;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+const MAINTENANCE_FILE: &str = "maintenance.json";
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ServiceCounter {
+  Cycles,
+  OnTime,
+  WorkTime,
+  RotationTime,
+  HeatingTime,
+  PortholeOpenings,
+  PortholeClosings,
+}
+
+impl ServiceCounter {
+  pub fn value(self: &Self, stats: &Statistics) -> u32 {
+    match self {
+      ServiceCounter::Cycles => stats.cycles,
+      ServiceCounter::OnTime => stats.on_time,
+      ServiceCounter::WorkTime => stats.work_time,
+      ServiceCounter::RotationTime => stats.rotation_time,
+      ServiceCounter::HeatingTime => stats.heating_time,
+      ServiceCounter::PortholeOpenings => stats.porthole_openings,
+      ServiceCounter::PortholeClosings => stats.porthole_closings,
+    }
+  }
+}
+
+/// A service to perform every `interval` units of a counter, in the unit the machine reports
+/// the counter in
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MaintenanceRule {
+  /// Assigned by the backend; 0 when adding a new rule
+  #[serde(default)]
+  pub id: u64,
+  pub name: String,
+  /// Machine the rule applies to, every machine if missing
+  #[serde(default)]
+  pub machine: Option<String>,
+  pub counter: ServiceCounter,
+  pub interval: u32,
+  /// How early before the threshold the service is reported as due
+  #[serde(default)]
+  pub warning: u32,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ServiceRecord {
+  pub rule: u64,
+  pub machine: String,
+  pub counter_value: u32,
+  pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize)]
+pub enum ServiceLevel {
+  Ok,
+  Due,
+  Overdue,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MaintenanceStatus {
+  pub rule: MaintenanceRule,
+  pub machine: String,
+  pub level: ServiceLevel,
+  /// Counter value at the last recorded service, 0 if there is none
+  pub last_service: u32,
+  pub current: u32,
+  /// Units left before the service; negative once it is overdue
+  pub remaining: i64,
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Plan {
+  next_id: u64,
+  rules: Vec<MaintenanceRule>,
+  services: Vec<ServiceRecord>,
+}
+
+pub struct Maintenance {
+  path: Option<PathBuf>,
+  plan: Plan,
+  /// Last level reported for every rule and machine, to alert only on changes
+  levels: HashMap<(u64, String), ServiceLevel>,
+}
+
+impl Maintenance {
+  pub fn load() -> Self {
+    let path = super::prefs::data_file(MAINTENANCE_FILE);
+    let plan = path
+      .as_ref()
+      .and_then(|path| fs::read_to_string(path).ok())
+      .and_then(|content| match serde_json::from_str(content.as_str()) {
+        Ok(plan) => Some(plan),
+        Err(e) => {
+          log::error!("Invalid maintenance file, discarding it: {:?}", e);
+          None
+        }
+      })
+      .unwrap_or_default();
+
+    Self {
+      path,
+      plan,
+      levels: HashMap::new(),
+    }
+  }
+
+  fn save(self: &Self) {
+    let path = match self.path {
+      Some(ref path) => path,
+      None => return,
+    };
+
+    let result = path
+      .parent()
+      .map_or(Ok(()), fs::create_dir_all)
+      .and_then(|_| {
+        let content = serde_json::to_string(&self.plan)?;
+        fs::write(path, content)
+      });
+
+    if let Err(e) = result {
+      log::error!("Unable to save maintenance plan to {:?}: {:?}", path, e);
+    }
+  }
+
+  pub fn rules(self: &Self) -> Vec<MaintenanceRule> {
+    self.plan.rules.clone()
+  }
+
+  /// Adds a new rule, or replaces the one with the same id
+  pub fn set_rule(self: &mut Self, mut rule: MaintenanceRule) -> Result<MaintenanceRule, Error> {
+    if rule.interval == 0 || rule.warning >= rule.interval {
+      return Err(Error::Value);
+    }
+
+    if rule.id == 0 {
+      self.plan.next_id += 1;
+      rule.id = self.plan.next_id;
+      self.plan.rules.push(rule.clone());
+    } else {
+      match self.plan.rules.iter_mut().find(|r| r.id == rule.id) {
+        Some(existing) => *existing = rule.clone(),
+        None => return Err(Error::Value),
+      }
+    }
+
+    self.levels.retain(|(id, _), _| *id != rule.id);
+    self.save();
+    Ok(rule)
+  }
+
+  pub fn remove_rule(self: &mut Self, id: u64) -> Result<(), Error> {
+    let count = self.plan.rules.len();
+    self.plan.rules.retain(|rule| rule.id != id);
+    if self.plan.rules.len() == count {
+      return Err(Error::Value);
+    }
+    self.plan.services.retain(|service| service.rule != id);
+    self.levels.retain(|(rule, _), _| *rule != id);
+    self.save();
+    Ok(())
+  }
+
+  /// Marks the service of a rule as done on a machine at its current counter value
+  pub fn record_service(
+    self: &mut Self,
+    id: u64,
+    machine: &String,
+    data: &MachineData,
+  ) -> Result<MaintenanceStatus, Error> {
+    let rule = self
+      .plan
+      .rules
+      .iter()
+      .find(|rule| rule.id == id && applies(rule, machine))
+      .cloned()
+      .ok_or(Error::Value)?;
+
+    self
+      .plan
+      .services
+      .retain(|service| !(service.rule == id && service.machine == *machine));
+    self.plan.services.push(ServiceRecord {
+      rule: id,
+      machine: machine.clone(),
+      counter_value: rule.counter.value(&data.stats),
+      timestamp: Utc::now(),
+    });
+    self.save();
+
+    let status = self.status_of(&rule, machine, data);
+    self.levels.insert((id, machine.clone()), status.level);
+    Ok(status)
+  }
+
+  fn status_of(
+    self: &Self,
+    rule: &MaintenanceRule,
+    machine: &String,
+    data: &MachineData,
+  ) -> MaintenanceStatus {
+    let last_service = self
+      .plan
+      .services
+      .iter()
+      .find(|service| service.rule == rule.id && service.machine == *machine)
+      .map_or(0, |service| service.counter_value);
+    let current = rule.counter.value(&data.stats);
+    // Counters restart from zero when the machine memory is reset, the service interval then
+    // covers everything counted since; `check` rebases the service record once it sees it
+    let used = if current < last_service {
+      current
+    } else {
+      current - last_service
+    };
+    let remaining = rule.interval as i64 - used as i64;
+
+    MaintenanceStatus {
+      rule: rule.clone(),
+      machine: machine.clone(),
+      level: if remaining <= 0 {
+        ServiceLevel::Overdue
+      } else if remaining <= rule.warning as i64 {
+        ServiceLevel::Due
+      } else {
+        ServiceLevel::Ok
+      },
+      last_service,
+      current,
+      remaining,
+    }
+  }
+
+  /// State of every rule that applies to the machine
+  pub fn status(self: &Self, machine: &String, data: &MachineData) -> Vec<MaintenanceStatus> {
+    self
+      .plan
+      .rules
+      .iter()
+      .filter(|rule| applies(rule, machine))
+      .map(|rule| self.status_of(rule, machine, data))
+      .collect()
+  }
+
+  /// Rules that became due or overdue since the last check of the machine
+  pub fn check(self: &mut Self, machine: &String, data: &MachineData) -> Vec<MaintenanceStatus> {
+    self.rebase_resets(machine, data);
+
+    let mut alerts = vec![];
+    for status in self.status(machine, data) {
+      let previous = self
+        .levels
+        .insert((status.rule.id, machine.clone()), status.level);
+      if status.level != ServiceLevel::Ok && previous.map_or(true, |level| status.level > level) {
+        alerts.push(status);
+      }
+    }
+    alerts
+  }
+
+  /// Services recorded above the current counter value predate a reset of the machine memory:
+  /// they are moved to zero, where the counter started again
+  fn rebase_resets(self: &mut Self, machine: &String, data: &MachineData) {
+    let rules = &self.plan.rules;
+    let mut rebased = false;
+    for service in self
+      .plan
+      .services
+      .iter_mut()
+      .filter(|service| service.machine == *machine)
+    {
+      let current = match rules.iter().find(|rule| rule.id == service.rule) {
+        Some(rule) => rule.counter.value(&data.stats),
+        None => continue,
+      };
+      if current < service.counter_value {
+        log::info!(
+          "Counter of {} went back from {} to {}, rebasing the service of rule {}",
+          machine,
+          service.counter_value,
+          current,
+          service.rule
+        );
+        service.counter_value = 0;
+        rebased = true;
+      }
+    }
+
+    if rebased {
+      self.save();
+    }
+  }
+}
+
+fn applies(rule: &MaintenanceRule, machine: &String) -> bool {
+  rule.machine.as_ref().map_or(true, |m| m == machine)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::controller::testing::machine_data;
+
+  fn maintenance() -> Maintenance {
+    Maintenance {
+      path: None,
+      plan: Plan::default(),
+      levels: HashMap::new(),
+    }
+  }
+
+  fn rule(machine: Option<&str>) -> MaintenanceRule {
+    MaintenanceRule {
+      id: 0,
+      name: String::from("Door gasket"),
+      machine: machine.map(String::from),
+      counter: ServiceCounter::Cycles,
+      interval: 1000,
+      warning: 100,
+    }
+  }
+
+  /// Data of a machine whose rules all follow the cycle counter
+  fn data(cycles: u32) -> MachineData {
+    machine_data(Statistics {
+      cycles,
+      ..Statistics::default()
+    })
+  }
+
+  fn levels(maintenance: &Maintenance, cycles: u32) -> Vec<(ServiceLevel, i64)> {
+    maintenance
+      .status(&String::from("m"), &data(cycles))
+      .iter()
+      .map(|status| (status.level, status.remaining))
+      .collect()
+  }
+
+  #[test]
+  fn rules_are_validated() {
+    let mut maintenance = maintenance();
+    let invalid = vec![
+      MaintenanceRule {
+        interval: 0,
+        warning: 0,
+        ..rule(None)
+      },
+      MaintenanceRule {
+        warning: 1000,
+        ..rule(None)
+      },
+      MaintenanceRule {
+        id: 5,
+        ..rule(None)
+      },
+    ];
+    for rule in invalid {
+      assert!(matches!(maintenance.set_rule(rule), Err(Error::Value)));
+    }
+    assert!(maintenance.rules().is_empty());
+
+    assert_eq!(maintenance.set_rule(rule(None)).unwrap().id, 1);
+    assert_eq!(maintenance.set_rule(rule(None)).unwrap().id, 2);
+    let renamed = MaintenanceRule {
+      id: 1,
+      name: String::from("Drum bearings"),
+      ..rule(None)
+    };
+    maintenance.set_rule(renamed).unwrap();
+    assert_eq!(maintenance.rules()[0].name, "Drum bearings");
+    assert_eq!(maintenance.rules().len(), 2);
+  }
+
+  #[test]
+  fn levels_at_the_thresholds() {
+    let mut maintenance = maintenance();
+    maintenance.set_rule(rule(None)).unwrap();
+
+    assert_eq!(levels(&maintenance, 0), vec![(ServiceLevel::Ok, 1000)]);
+    assert_eq!(levels(&maintenance, 899), vec![(ServiceLevel::Ok, 101)]);
+    assert_eq!(levels(&maintenance, 900), vec![(ServiceLevel::Due, 100)]);
+    assert_eq!(levels(&maintenance, 999), vec![(ServiceLevel::Due, 1)]);
+    assert_eq!(levels(&maintenance, 1000), vec![(ServiceLevel::Overdue, 0)]);
+    assert_eq!(
+      levels(&maintenance, 1200),
+      vec![(ServiceLevel::Overdue, -200)]
+    );
+  }
+
+  #[test]
+  fn services_restart_the_interval() {
+    let mut maintenance = maintenance();
+    let id = maintenance.set_rule(rule(None)).unwrap().id;
+    let machine = String::from("m");
+
+    let status = maintenance
+      .record_service(id, &machine, &data(1200))
+      .unwrap();
+    assert_eq!(status.last_service, 1200);
+    assert_eq!(status.level, ServiceLevel::Ok);
+    assert_eq!(levels(&maintenance, 2150), vec![(ServiceLevel::Due, 50)]);
+
+    assert!(matches!(
+      maintenance.record_service(id + 1, &machine, &data(1200)),
+      Err(Error::Value)
+    ));
+  }
+
+  #[test]
+  fn counters_that_reset() {
+    let mut maintenance = maintenance();
+    let id = maintenance.set_rule(rule(None)).unwrap().id;
+    maintenance
+      .record_service(id, &String::from("m"), &data(5000))
+      .unwrap();
+
+    assert_eq!(levels(&maintenance, 30), vec![(ServiceLevel::Ok, 970)]);
+    assert_eq!(levels(&maintenance, 950), vec![(ServiceLevel::Due, 50)]);
+
+    // Once the drop has been seen the interval keeps counting past the old service value
+    maintenance.check(&String::from("m"), &data(30));
+    assert_eq!(maintenance.plan.services[0].counter_value, 0);
+    assert_eq!(
+      levels(&maintenance, 5100),
+      vec![(ServiceLevel::Overdue, -4100)]
+    );
+    maintenance.check(&String::from("m"), &data(5100));
+    assert_eq!(
+      levels(&maintenance, 5100),
+      vec![(ServiceLevel::Overdue, -4100)]
+    );
+  }
+
+  #[test]
+  fn rules_for_other_machines_are_ignored() {
+    let mut maintenance = maintenance();
+    assert!(levels(&maintenance, 2000).is_empty());
+
+    let id = maintenance.set_rule(rule(Some("other"))).unwrap().id;
+    assert!(levels(&maintenance, 2000).is_empty());
+    assert!(matches!(
+      maintenance.record_service(id, &String::from("m"), &data(2000)),
+      Err(Error::Value)
+    ));
+  }
+
+  #[test]
+  fn alerts_only_when_the_level_rises() {
+    let mut maintenance = maintenance();
+    let id = maintenance.set_rule(rule(None)).unwrap().id;
+    let machine = String::from("m");
+    let alerts = |maintenance: &mut Maintenance, cycles: u32| -> Vec<ServiceLevel> {
+      maintenance
+        .check(&machine, &data(cycles))
+        .iter()
+        .map(|status| status.level)
+        .collect()
+    };
+
+    assert!(alerts(&mut maintenance, 0).is_empty());
+    assert_eq!(alerts(&mut maintenance, 900), vec![ServiceLevel::Due]);
+    assert!(alerts(&mut maintenance, 950).is_empty());
+    assert_eq!(alerts(&mut maintenance, 1000), vec![ServiceLevel::Overdue]);
+    assert!(alerts(&mut maintenance, 1100).is_empty());
+
+    maintenance
+      .record_service(id, &machine, &data(1100))
+      .unwrap();
+    assert!(alerts(&mut maintenance, 1500).is_empty());
+    assert_eq!(alerts(&mut maintenance, 2000), vec![ServiceLevel::Due]);
+  }
+
+  #[test]
+  fn removing_a_rule_forgets_its_services() {
+    let mut maintenance = maintenance();
+    let id = maintenance.set_rule(rule(None)).unwrap().id;
+    maintenance
+      .record_service(id, &String::from("m"), &data(10))
+      .unwrap();
+
+    assert!(matches!(maintenance.remove_rule(id + 1), Err(Error::Value)));
+    maintenance.remove_rule(id).unwrap();
+    assert!(maintenance.rules().is_empty());
+    assert!(maintenance.plan.services.is_empty());
+  }
+}
@@ -1,33 +1,99 @@
-mod discovery;
-mod prefs;
-mod things5_api;
-mod washing_machine;
+pub mod consumption;
+pub mod discovery;
+pub mod events;
+pub mod fleet;
+#[cfg(feature = "gateway")]
+mod gateway;
+mod handle;
+pub mod history;
+mod http;
+pub mod maintenance;
+pub mod notifications;
+pub mod prefs;
+pub mod report;
+pub mod scheduler;
+pub mod statistics;
+#[cfg(test)]
+mod testing;
+pub mod things5_api;
+pub mod washing_machine;
 
-use futures::future::FutureExt;
+use events::MachineEvent;
+use fleet::FleetTarget;
+pub use handle::Handle;
+use handle::{respond, Reply, Request};
+use history::HistoryStore;
+use maintenance::{Maintenance, MaintenanceRule, MaintenanceStatus};
+use notifications::Notifier;
+use scheduler::Scheduler;
 use serde_json;
-use serde_json::json;
-use std::sync::mpsc;
+use statistics::StatisticsLog;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
 use std::time::{Duration, Instant};
-use tauri::Window;
+use tauri::{EventHandler, Manager, Window};
+use tokio::sync::{broadcast, mpsc};
 use washing_machine as ws;
-use washing_machine::WashingMachineConnection;
 
-#[derive(Debug)]
+/// Minimum time between two reconnections to the machine of a scheduled start
+const RECONNECTION_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub enum Error {
   Network(String),
   Json(String),
   Protocol,
   Server(String),
   Value,
+  NotConnected,
+  /// The connection does not support the operation
+  Unsupported,
 }
 
 #[derive(Clone, serde::Deserialize)]
-enum BackEndPortMessage {
+pub enum BackEndPortMessage {
   Refresh,
-  Things5Login { username: String, password: String },
+  GetMachines,
+  Things5Login {
+    username: String,
+    password: String,
+  },
   WashingMachineHttpConnect(String),
-  WashingMachineThings5Connect { token: String, device_id: String },
+  WashingMachineThings5Connect {
+    token: String,
+    device_id: String,
+  },
+  /// Connects to a Modbus TCP controller; the register map defaults to the saved one
+  WashingMachineModbusConnect {
+    address: String,
+    #[serde(default)]
+    map: Option<ws::modbus::RegisterMap>,
+  },
+  /// Adds a simulated machine with the demo programs, its clock sped up by the given factor, at
+  /// most `simulated::MAX_SPEEDUP`
+  WashingMachineSimulatedConnect {
+    name: String,
+    #[serde(default)]
+    speedup: Option<u32>,
+  },
+  /// Injects an event into a simulated machine
+  Simulate(ws::simulated::SimulatedEvent),
+  /// Adds a machine playing back a recorded session, sped up by the given factor within the same
+  /// bounds
+  WashingMachineReplayConnect {
+    path: PathBuf,
+    #[serde(default)]
+    speedup: Option<u32>,
+  },
+  /// Logs the polls and commands of the machine to a file, until the recording is stopped
+  StartRecording(PathBuf),
+  StopRecording,
   SearchMachines,
+  SelectMachine(String),
+  Disconnect,
   SendCurrentMachineConfiguration(Vec<u8>),
   GetCurrentMachineConfiguration,
   SelectMachineConfiguration(String),
@@ -36,21 +102,202 @@ enum BackEndPortMessage {
   Pause,
   Stop,
   ClearAlarms,
+  ScheduleStart {
+    program: u16,
+    at: chrono::DateTime<chrono::Utc>,
+  },
+  CancelScheduledStart(u64),
+  GetScheduledStarts,
+  PushConfigurationToFleet {
+    archive: Vec<u8>,
+    targets: Vec<FleetTarget>,
+    select: Option<String>,
+    token: Option<String>,
+  },
+  /// Seconds between two samples of the machine statistics, at least `statistics::MIN_INTERVAL`
+  SetStatisticsInterval(u64),
+  GetMaintenanceRules,
+  /// Adds a rule when its id is 0, replaces the existing one otherwise
+  SetMaintenanceRule(MaintenanceRule),
+  RemoveMaintenanceRule(u64),
+  /// Records that the service of a rule has just been done on the machine
+  RecordService(u64),
+  GetMaintenanceStatus,
+  /// Saves the service report of the machine as HTML, and also as PDF if a path is given
+  GenerateReport {
+    path: PathBuf,
+    pdf: Option<PathBuf>,
+  },
+}
+
+/// A message coming from the frontend, optionally addressed to a specific machine and carrying
+/// a correlation id that is echoed back in the corresponding `commandResult` event.
+/// Untargeted messages apply to the currently selected machine.
+#[derive(Clone, serde::Deserialize)]
+#[serde(untagged)]
+enum PortMessage {
+  Envelope {
+    #[serde(default)]
+    id: Option<u64>,
+    #[serde(default)]
+    machine: Option<String>,
+    message: BackEndPortMessage,
+  },
+  Bare(BackEndPortMessage),
+}
+
+impl From<PortMessage> for Request {
+  fn from(message: PortMessage) -> Self {
+    match message {
+      PortMessage::Envelope {
+        id,
+        machine,
+        message,
+      } => Request {
+        id,
+        machine,
+        message,
+        reply: None,
+      },
+      PortMessage::Bare(message) => Request {
+        id: None,
+        machine: None,
+        message,
+        reply: None,
+      },
+    }
+  }
+}
+
+/// Identifies a command sent to a machine, for reporting its outcome
+#[derive(Clone, serde::Serialize)]
+struct CommandInfo {
+  id: Option<u64>,
+  machine: Option<String>,
+  command: &'static str,
+}
+
+#[derive(serde::Serialize)]
+struct CommandResult {
+  #[serde(flatten)]
+  info: CommandInfo,
+  result: ws::Result<()>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Things5Session {
+  pub token: String,
+  pub devices: Vec<things5_api::Device>,
+}
+
+/// Events produced by the workers spawned from the backend loop
+enum InternalMessage {
+  Connected(String, SharedConnection, Option<Reply>),
+  Refreshed(String, SharedConnection),
+  CommandCompleted(String),
+}
+
+type SharedConnection = Arc<dyn ws::WashingMachineConnection>;
+
+/// Id prefixes of the machines that only exist in the app
+const SIMULATED_PREFIX: &str = "simulated:";
+const REPLAY_PREFIX: &str = "replay:";
+
+/// Simulated and replayed machines are shown like the others, but leave no trace in the
+/// history, the statistics and the maintenance plan, and raise no notifications
+fn is_virtual(id: &String) -> bool {
+  id.starts_with(SIMULATED_PREFIX) || id.starts_with(REPLAY_PREFIX)
+}
+
+/// A connected washing machine along with its refresh bookkeeping
+struct Machine {
+  connection: SharedConnection,
+  update_ts: Instant,
+  quick_update_ts: Option<Instant>,
+  refreshing: bool,
+  /// Last fresh snapshot, used to detect state transitions
+  last_data: Option<ws::MachineData>,
+}
+
+impl Machine {
+  fn new(connection: SharedConnection) -> Self {
+    let last_data = connection.get_connection_state().data().cloned();
+    Self {
+      connection,
+      update_ts: Instant::now(),
+      quick_update_ts: None,
+      refreshing: false,
+      last_data,
+    }
+  }
+
+  /// Compares the current state with the last one, returning the transitions in between; the
+  /// new snapshot is left in `last_data`
+  fn transitions(self: &mut Self) -> Vec<MachineEvent> {
+    match self.connection.get_connection_state() {
+      ws::ConnectionState::Connected(data) => {
+        let events = match self.last_data {
+          Some(ref previous) => events::diff(previous, &data),
+          None => vec![],
+        };
+        self.last_data = Some(data);
+        events
+      }
+      _ => vec![],
+    }
+  }
+
+  fn refresh_due(self: &Self) -> bool {
+    if self.refreshing {
+      false
+    } else if let Some(ts) = self.quick_update_ts {
+      ts.elapsed() > Duration::from_millis(300)
+    } else {
+      self.update_ts.elapsed() > self.connection.suggested_refresh_period()
+    }
+  }
 }
 
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct MachineUpdate {
+  pub machine: Option<String>,
+  pub selected: bool,
+  pub state: Option<ws::ConnectionState>,
+  pub capabilities: Option<ws::Capabilities>,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct MachineEventMessage {
+  machine: String,
+  #[serde(flatten)]
+  event: MachineEvent,
+}
+
+#[derive(Clone)]
 pub struct Context {
   window: Window,
+  updates: broadcast::Sender<MachineUpdate>,
+  history: Arc<HistoryStore>,
+  notifier: Arc<Notifier>,
 }
 
+#[derive(Clone)]
 pub struct Controller {
   context: Context,
 }
 
 impl Controller {
-  pub fn new(window: Window) -> Self {
+  pub fn new(window: Window, handle: &Handle) -> Self {
     log::info!("New controller");
+    let identifier = window.config().tauri.bundle.identifier.clone();
+    let notifier = Notifier::new(identifier, window.clone(), handle.clone());
     Controller {
-      context: Context { window },
+      context: Context {
+        window,
+        updates: handle.updates(),
+        history: handle.history(),
+        notifier: Arc::new(notifier),
+      },
     }
   }
 
@@ -59,231 +306,1035 @@ impl Controller {
   }
 
   fn emit(self: &Self, topic: &str, message: impl serde::Serialize) {
-    self.window().emit(topic, message).unwrap();
+    if let Err(e) = self.window().emit(topic, message) {
+      log::warn!("Unable to emit {}: {:?}", topic, e);
+    }
   }
 
   fn snackbar_message(self: &Self, message: &str) {
     self.emit("notificationMessage", message);
   }
 
-  fn emit_update(self: &Self, state: impl serde::Serialize) {
-    log::debug!("update {}", serde_json::ser::to_string(&state).unwrap());
-    self.emit("stateUpdate", state);
+  fn emit_update(self: &Self, update: MachineUpdate) {
+    log::debug!("update {}", serde_json::ser::to_string(&update).unwrap());
+    // Nobody might be listening, which is fine
+    self.context.updates.send(update.clone()).ok();
+    self.emit("stateUpdate", update);
   }
 
-  fn send_state(self: &Self, connection: &Option<Box<dyn ws::WashingMachineConnection>>) {
-    match connection {
-      Some(ref connection) => self.emit_update(connection.get_connection_state()),
-      None => self.emit_update(json!("null")),
+  fn command_result(self: &Self, info: CommandInfo, result: ws::Result<()>) {
+    if let Err(ref e) = result {
+      log::warn!(
+        "Command {} to {:?} failed: {:?}",
+        info.command,
+        info.machine,
+        e
+      );
+    }
+    self.emit("commandResult", CommandResult { info, result });
+  }
+
+  fn machine_event(self: &Self, id: &String, data: &ws::MachineData, event: MachineEvent) {
+    log::info!("{}: {:?}", id, event);
+    if !is_virtual(id) {
+      self.context.history.record_event(id, data, &event);
+      self.context.notifier.notify(id, data, &event);
+    }
+    self.emit(
+      "machineEvent",
+      MachineEventMessage {
+        machine: id.clone(),
+        event,
+      },
+    );
+  }
+
+  fn maintenance_alert(self: &Self, data: &ws::MachineData, status: MaintenanceStatus) {
+    log::info!(
+      "{}: maintenance {:?} for {}",
+      status.machine,
+      status.level,
+      status.rule.name
+    );
+    self
+      .context
+      .notifier
+      .notify_maintenance(&status.machine, data, &status);
+    self.emit("maintenanceAlert", status);
+  }
+
+  fn send_state(self: &Self, id: &String, machine: &Machine, selected: &Option<String>) {
+    self.emit_update(machine_update(id, machine, selected));
+  }
+
+  fn send_all_states(self: &Self, machines: &HashMap<String, Machine>, selected: &Option<String>) {
+    if machines.is_empty() {
+      self.emit_update(MachineUpdate {
+        machine: None,
+        selected: true,
+        state: None,
+        capabilities: None,
+      });
+    } else {
+      for (id, machine) in machines {
+        self.send_state(id, machine, selected);
+      }
+    }
+  }
+}
+
+fn machine_update(id: &String, machine: &Machine, selected: &Option<String>) -> MachineUpdate {
+  MachineUpdate {
+    machine: Some(id.clone()),
+    selected: selected.as_ref() == Some(id),
+    state: Some(machine.connection.get_connection_state()),
+    capabilities: Some(machine.connection.capabilities()),
+  }
+}
+
+/// Runs a connection operation in its own task, reporting its outcome to the frontend and
+/// notifying the loop once it is done. Commands without a connected target machine fail right
+/// away with `Error::NotConnected`.
+fn spawn_command<F, R, T>(
+  info: CommandInfo,
+  target: Option<(String, SharedConnection)>,
+  reply: Option<Reply>,
+  controller: &Controller,
+  internal_tx: &mpsc::UnboundedSender<InternalMessage>,
+  command: F,
+) where
+  F: FnOnce(SharedConnection) -> R + Send + 'static,
+  R: Future<Output = ws::Result<T>> + Send + 'static,
+  T: serde::Serialize + Send + 'static,
+{
+  let (id, connection) = match target {
+    Some(target) => target,
+    None => {
+      if let Some(machine) = info.machine.as_ref().filter(|id| !is_virtual(id)) {
+        controller.context.history.record_command(
+          machine,
+          String::new(),
+          info.command,
+          &Err(Error::NotConnected),
+        );
+      }
+      controller.command_result(info, Err(Error::NotConnected));
+      respond::<()>(reply, Err(Error::NotConnected));
+      return;
+    }
+  };
+
+  let controller = controller.clone();
+  let internal_tx = internal_tx.clone();
+  tokio::spawn(async move {
+    let result = command(connection.clone()).await;
+    let name = connection
+      .get_connection_state()
+      .data()
+      .map(|data| data.name.clone());
+    let outcome = result.as_ref().map(|_| ()).map_err(Clone::clone);
+    if !is_virtual(&id) {
+      controller.context.history.record_command(
+        &id,
+        name.unwrap_or_default(),
+        info.command,
+        &outcome,
+      );
+    }
+    controller.command_result(info, outcome);
+    respond(reply, result);
+    internal_tx.send(InternalMessage::CommandCompleted(id)).ok();
+  });
+}
+
+/// How a machine was reached, so that it can be reached again after a restart of the app
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum ConnectionTarget {
+  Http(String),
+  /// Things5 device id; reconnections use the token saved by the last login
+  Things5(String),
+  Modbus {
+    address: String,
+    map: Option<ws::modbus::RegisterMap>,
+  },
+}
+
+impl ConnectionTarget {
+  /// Id of the machine once connected
+  pub fn id(self: &Self) -> String {
+    match self {
+      ConnectionTarget::Http(ip) => ip.clone(),
+      ConnectionTarget::Things5(device_id) => device_id.clone(),
+      ConnectionTarget::Modbus { address, .. } => address.clone(),
+    }
+  }
+}
+
+async fn connect_modbus(
+  address: &str,
+  map: Option<ws::modbus::RegisterMap>,
+) -> ws::Result<SharedConnection> {
+  let address = address.parse().map_err(|_| Error::Value)?;
+  let map = match map {
+    Some(map) => {
+      map.validate()?;
+      map
+    }
+    None => ws::modbus::RegisterMap::load(),
+  };
+  Ok(Arc::new(ws::modbus::Connection::new(address, map).await))
+}
+
+/// Connects to a machine in its own task, handing the connection over to the loop once the
+/// machine answered. Things5 connections use the saved token unless one is given.
+fn spawn_connect(
+  target: ConnectionTarget,
+  token: Option<String>,
+  reply: Option<Reply>,
+  controller: &Controller,
+  internal_tx: &mpsc::UnboundedSender<InternalMessage>,
+) {
+  let controller = controller.clone();
+  let internal_tx = internal_tx.clone();
+  tokio::spawn(async move {
+    let id = target.id();
+    let connection: ws::Result<SharedConnection> = match target {
+      ConnectionTarget::Http(ip) => {
+        log::info!("connecting to {}...", ip);
+        Ok(Arc::new(ws::local::Connection::new(ip).await))
+      }
+      ConnectionTarget::Things5(device_id) => {
+        log::info!("connecting to things5 device {}", device_id);
+        match token.or_else(prefs::get_token) {
+          Some(token) => Ok(Arc::new(
+            ws::things5::Connection::new(token, device_id).await,
+          )),
+          None => Err(Error::NotConnected),
+        }
+      }
+      ConnectionTarget::Modbus { address, map } => {
+        log::info!("connecting to modbus controller {}", address);
+        connect_modbus(address.as_str(), map).await
+      }
     };
+
+    match connection {
+      Ok(connection) if connection.get_connection_state().is_connected() => {
+        internal_tx
+          .send(InternalMessage::Connected(id, connection, reply))
+          .ok();
+      }
+      Ok(_) => {
+        controller.snackbar_message("ConnessioneFallita");
+        respond::<()>(
+          reply,
+          Err(Error::Network(format!("Unable to connect to {}", id))),
+        );
+      }
+      Err(e) => {
+        controller.snackbar_message("ConnessioneFallita");
+        respond::<()>(reply, Err(e));
+      }
+    }
+  });
+}
+
+/// Runs the backend loop on its own thread, restarting it whenever it dies
+pub fn supervise(window: Window, handle: Handle) {
+  loop {
+    let task_window = window.clone();
+    let task_handle = handle.clone();
+    let worker = thread::Builder::new()
+      .name("backend".into())
+      .spawn(move || task(task_window, task_handle));
+
+    match worker.map(|worker| worker.join()) {
+      Ok(Ok(())) => {
+        log::info!("Backend thread terminated");
+        break;
+      }
+      Ok(Err(e)) => log::error!("Backend thread panicked: {:?}, restarting", e),
+      Err(e) => log::error!("Unable to spawn backend thread: {:?}, retrying", e),
+    }
+
+    thread::sleep(Duration::from_secs(1));
   }
 }
 
-pub fn task(window: Window) {
+/// Removes the frontend listener when the backend loop goes away, so that a restarted loop
+/// does not leave stale handlers behind
+struct ListenerGuard {
+  window: Window,
+  handler: EventHandler,
+}
+
+impl Drop for ListenerGuard {
+  fn drop(self: &mut Self) {
+    self.window.unlisten(self.handler);
+  }
+}
+
+pub fn task(window: Window, handle: Handle) {
   let rt = tokio::runtime::Runtime::new().expect("Failed to build pool");
-  let mut connection: Option<Box<dyn ws::WashingMachineConnection>> = None;
-  let controller = Controller::new(window);
+  http::renew();
+  let controller = Controller::new(window, &handle);
 
-  let timeout = Duration::from_millis(100);
-  let (tx, rx) = mpsc::channel::<BackEndPortMessage>();
+  let (tx, rx) = mpsc::unbounded_channel::<Request>();
+  handle.attach(tx);
+
+  #[cfg(feature = "gateway")]
+  let _gateway = prefs::get_gateway_address().and_then(|address| match prefs::gateway_token() {
+    Ok(token) => Some(rt.spawn(gateway::serve(address, token, handle.clone()))),
+    Err(e) => {
+      log::error!("Gateway disabled, no access token: {:?}", e);
+      None
+    }
+  });
+
+  let listener_controller = controller.clone();
+  let handler = controller.window().listen("backendPort", move |event| {
+    let invalid = CommandInfo {
+      id: None,
+      machine: None,
+      command: "Invalid",
+    };
 
-  let tx_clone = tx.clone();
-  controller.window().listen("backendPort", move |event| {
     if let Some(str) = event.payload() {
-      match serde_json::from_str::<BackEndPortMessage>(str) {
+      match serde_json::from_str::<PortMessage>(str) {
         Ok(message) => {
-          tx_clone.send(message).ok();
+          if let Err(e) = handle.send(message.into()) {
+            listener_controller.command_result(invalid, Err(e));
+          }
+        }
+        Err(e) => {
+          log::error!("Error while parsing json from port: {}\n--> {:?}", str, e);
+          listener_controller.command_result(invalid, Err(Error::Json(e.to_string())));
         }
-        Err(e) => panic!("Error while parsing json from port: {}\n--> {:?}", str, e),
       };
     } else {
-      panic!("Event without payload!");
+      log::error!("Event without payload!");
+      listener_controller.command_result(invalid, Err(Error::Protocol));
     }
   });
 
-  if let Some(token) = prefs::get_token() {
-    match things5_api::get_devices(&token) {
-      Ok(devices) => {
-        controller
-          .window()
-          .emit("things5Login", token.clone())
-          .unwrap();
-        controller.window().emit("things5Devices", devices).unwrap();
-      }
-      Err(_) => (),
-    }
-  }
+  let _guard = ListenerGuard {
+    window: controller.window(),
+    handler,
+  };
 
-  log::info!("Starting backend loop");
-  let mut update_ts = Instant::now();
-  let mut quick_update_ts: Option<Instant> = None;
+  rt.block_on(backend_loop(controller, rx));
+}
 
-  loop {
+/// State of the backend loop, updated by the requests, the messages of the workers and the ticks
+struct Backend {
+  controller: Controller,
+  machines: HashMap<String, Machine>,
+  selected: Option<String>,
+  /// Simulated machines are also kept with their concrete type, to inject events into them
+  simulations: HashMap<String, Arc<ws::simulated::Connection>>,
+  /// Same for the machines being recorded, to unwrap them when the recording stops
+  recordings: HashMap<String, Arc<ws::recording::Recorder>>,
+  /// How the machines connected so far were reached, to reach them again for scheduled starts
+  targets: HashMap<String, ConnectionTarget>,
+  /// Last reconnection attempt made for a scheduled start, per machine
+  reconnections: HashMap<String, Instant>,
+  scheduler: Scheduler,
+  statistics_log: StatisticsLog,
+  maintenance: Maintenance,
+  internal_tx: mpsc::UnboundedSender<InternalMessage>,
+}
+
+impl Backend {
+  fn handle_request(self: &mut Self, request: Request) {
     use BackEndPortMessage::*;
-    match rx.recv_timeout(timeout) {
-      Ok(WashingMachineHttpConnect(ip)) => {
-        log::info!("connecting...");
-        let http_connection = ws::local::Connection::new(ip);
-        match http_connection.get_connection_state() {
-          ws::ConnectionState::Connected {
-            name: _,
-            active: _,
-            state: _,
-            configuration: _,
-            stats: _,
-          } => {
-            controller.snackbar_message("Connesso");
-            connection = Some(Box::new(http_connection));
-            controller.send_state(&connection);
-            update_ts = Instant::now();
+
+    let Backend {
+      controller,
+      machines,
+      selected,
+      simulations,
+      recordings,
+      targets,
+      scheduler,
+      statistics_log,
+      maintenance,
+      internal_tx,
+      ..
+    } = self;
+    let Request {
+      id: request_id,
+      machine,
+      message,
+      reply,
+    } = request;
+    let requested_id = machine.clone();
+    let target_id = machine.or_else(|| selected.clone());
+    let info = |command: &'static str| CommandInfo {
+      id: request_id,
+      machine: target_id.clone(),
+      command,
+    };
+
+    // The connected machine the message is addressed to, if any
+    let target = target_id.clone().and_then(|id| {
+      machines
+        .get(&id)
+        .map(|machine| (id, machine.connection.clone()))
+    });
+
+    match message {
+      WashingMachineHttpConnect(ip) => {
+        let target = ConnectionTarget::Http(ip);
+        targets.insert(target.id(), target.clone());
+        spawn_connect(target, None, reply, controller, internal_tx);
+      }
+
+      WashingMachineThings5Connect { token, device_id } => {
+        let target = ConnectionTarget::Things5(device_id);
+        targets.insert(target.id(), target.clone());
+        spawn_connect(target, Some(token), reply, controller, internal_tx);
+      }
+
+      WashingMachineModbusConnect { address, map } => {
+        let target = ConnectionTarget::Modbus { address, map };
+        targets.insert(target.id(), target.clone());
+        spawn_connect(target, None, reply, controller, internal_tx);
+      }
+
+      WashingMachineSimulatedConnect { name, speedup } => {
+        log::info!("starting simulated machine {}", name);
+        let id = format!("{}{}", SIMULATED_PREFIX, name);
+        let simulation = Arc::new(ws::simulated::Connection::with_speedup(
+          name,
+          ws::simulated::Connection::demo_configuration(),
+          speedup.unwrap_or(1),
+        ));
+        simulations.insert(id.clone(), simulation.clone());
+        internal_tx
+          .send(InternalMessage::Connected(id, simulation, reply))
+          .ok();
+      }
+
+      Simulate(event) => {
+        simulations.retain(|id, _| machines.contains_key(id));
+        let result = match target {
+          Some((id, _)) => match simulations.get(&id) {
+            Some(simulation) => simulation.inject(event),
+            None => Err(Error::Unsupported),
+          },
+          None => Err(Error::NotConnected),
+        };
+        controller.command_result(info("Simulate"), result.clone());
+        respond(reply, result);
+      }
+
+      WashingMachineReplayConnect { path, speedup } => {
+        log::info!("replaying {:?}", path);
+        let id = format!(
+          "{}{}",
+          REPLAY_PREFIX,
+          path.file_stem().unwrap_or_default().to_string_lossy()
+        );
+        match ws::replay::Connection::with_speedup(&path, speedup.unwrap_or(1)) {
+          Ok(replay) => {
+            internal_tx
+              .send(InternalMessage::Connected(id, Arc::new(replay), reply))
+              .ok();
+          }
+          Err(e) => {
+            log::error!("Unable to replay {:?}: {:?}", path, e);
+            controller.snackbar_message("ConnessioneFallita");
+            respond::<()>(reply, Err(e));
           }
-          ws::ConnectionState::Error => controller.snackbar_message("ConnessioneFallita"),
         }
       }
 
-      Ok(WashingMachineThings5Connect { token, device_id }) => {
-        log::info!("connecting to things5");
-        let things5_connection = ws::things5::Connection::new(token, device_id);
-        match things5_connection.get_connection_state() {
-          ws::ConnectionState::Connected {
-            name: _,
-            active: _,
-            state: _,
-            configuration: _,
-            stats: _,
-          } => {
-            controller.snackbar_message("Connesso");
-            connection = Some(Box::new(things5_connection));
-            controller.send_state(&connection);
-            update_ts = Instant::now();
+      StartRecording(path) => {
+        recordings.retain(|id, _| machines.contains_key(id));
+        let result = match target {
+          Some((id, connection)) => {
+            // Recording again starts a new file instead of nesting the recorders
+            let connection = recordings
+              .remove(&id)
+              .map_or(connection, |recorder| recorder.inner());
+            match ws::recording::Recorder::new(connection, path) {
+              Ok(recorder) => {
+                let recorder = Arc::new(recorder);
+                log::info!("Recording {} to {:?}", id, recorder.path());
+                if let Some(machine) = machines.get_mut(&id) {
+                  // A refresh in flight on the old connection is discarded when it ends
+                  machine.connection = recorder.clone();
+                  machine.refreshing = false;
+                }
+                recordings.insert(id, recorder);
+                Ok(())
+              }
+              Err(e) => Err(Error::Server(e.to_string())),
+            }
           }
-          ws::ConnectionState::Error => controller.snackbar_message("ConnessioneFallita"),
-        }
+          None => Err(Error::NotConnected),
+        };
+        controller.command_result(info("StartRecording"), result.clone());
+        respond(reply, result);
+      }
+
+      StopRecording => {
+        recordings.retain(|id, _| machines.contains_key(id));
+        let result = match target {
+          Some((id, _)) => match recordings.remove(&id) {
+            Some(recorder) => {
+              log::info!("Recording of {} saved to {:?}", id, recorder.path());
+              if let Some(machine) = machines.get_mut(&id) {
+                machine.connection = recorder.inner();
+                machine.refreshing = false;
+              }
+              Ok(())
+            }
+            None => Err(Error::Value),
+          },
+          None => Err(Error::NotConnected),
+        };
+        controller.command_result(info("StopRecording"), result.clone());
+        respond(reply, result);
       }
 
-      Ok(Things5Login { username, password }) => {
+      Things5Login { username, password } => {
         log::info!("Login attempt");
-        match things5_api::authorize(username.as_str(), password.as_str()) {
-          Ok(token) => {
-            log::info!("Login successful!");
-            controller
-              .window()
-              .emit("things5Login", token.clone())
-              .unwrap();
-            if let Ok(devices) = things5_api::get_devices(token.as_str()) {
-              controller.window().emit("things5Devices", devices).unwrap();
-              prefs::set_token(token);
+        let closure_controller = controller.clone();
+        tokio::spawn(async move {
+          let result = match things5_api::authorize(username.as_str(), password.as_str()).await {
+            Ok(token) => {
+              log::info!("Login successful!");
+              closure_controller.emit("things5Login", token.clone());
+              things5_api::get_devices(token.as_str())
+                .await
+                .map(|devices| {
+                  closure_controller.emit("things5Devices", &devices);
+                  if let Err(e) = prefs::set_token(token.clone()) {
+                    log::warn!("Unable to remember the Things5 token: {:?}", e);
+                  }
+                  Things5Session { token, devices }
+                })
             }
+            Err(e) => Err(e),
+          };
+
+          match result {
+            Err(Error::Value) => closure_controller.snackbar_message("CredenzialiNonValide"),
+            Err(_) => closure_controller.snackbar_message("ErroreDiRete"),
+            Ok(_) => (),
           }
-          Err(Error::Value) => controller.snackbar_message("CredenzialiNonValide"),
-          _ => controller.snackbar_message("ErroreDiRete"),
-        }
+          respond(reply, result);
+        });
       }
 
-      Ok(Refresh) => controller.send_state(&connection),
+      Refresh => match target {
+        Some((id, _)) => {
+          let update = machine_update(&id, &machines[&id], &selected);
+          controller.emit_update(update.clone());
+          respond(reply, Ok(vec![update]));
+        }
+        None => {
+          controller.send_all_states(&machines, &selected);
+          respond(
+            reply,
+            Ok(
+              machines
+                .iter()
+                .map(|(id, machine)| machine_update(id, machine, &selected))
+                .collect::<Vec<MachineUpdate>>(),
+            ),
+          );
+        }
+      },
 
-      Ok(SearchMachines) => {
+      GetMachines => match requested_id {
+        Some(id) => match machines.get(&id) {
+          Some(machine) => respond(reply, Ok(vec![machine_update(&id, machine, &selected)])),
+          None => respond::<()>(reply, Err(Error::NotConnected)),
+        },
+        None => respond(
+          reply,
+          Ok(
+            machines
+              .iter()
+              .map(|(id, machine)| machine_update(id, machine, &selected))
+              .collect::<Vec<MachineUpdate>>(),
+          ),
+        ),
+      },
+
+      SearchMachines => {
         log::info!("Searching for machines...");
-        let closure_window = controller.window();
-        rt.spawn(discovery::poll().then(|res| async move {
-          match res {
-            Ok(addresses) => {
+        let closure_controller = controller.clone();
+        tokio::spawn(async move {
+          let result = discovery::poll().await;
+          match result {
+            Ok(ref addresses) => {
               log::info!("Found {:?}", addresses);
-              closure_window.emit("ipAddresses", addresses).unwrap();
+              closure_controller.emit("ipAddresses", addresses);
             }
-            Err(e) => {
+            Err(ref e) => {
               log::warn!("{}", e);
             }
           }
-        }));
+          respond(reply, result.map_err(|e| Error::Network(e.to_string())));
+        });
       }
 
-      Ok(SendCurrentMachineConfiguration(bytes)) => {
-        match connection
-          .as_ref()
-          .unwrap()
-          .send_machine_configuration(bytes.into())
-        {
-          Ok(()) => controller.snackbar_message("ConfigurazioneCaricata"),
-          Err(e) => {
-            log::error!("Unable to put machine config: {:?}", e);
-            controller.snackbar_message("NonSonoRiuscitoACaricareLaConfigurazione");
+      SelectMachine(id) => {
+        if machines.contains_key(&id) {
+          *selected = Some(id);
+          controller.send_all_states(&machines, &selected);
+          respond(reply, Ok(()));
+        } else {
+          respond::<()>(reply, Err(Error::NotConnected));
+        }
+      }
+
+      Disconnect => match target {
+        Some((id, _)) => {
+          log::info!("Disconnecting from {}", id);
+          machines.remove(&id);
+          if selected.as_ref() == Some(&id) {
+            *selected = machines.keys().next().cloned();
           }
+          controller.command_result(info("Disconnect"), Ok(()));
+          controller.send_all_states(&machines, &selected);
+          respond(reply, Ok(()));
+        }
+        None => {
+          controller.command_result(info("Disconnect"), Err(Error::NotConnected));
+          respond::<()>(reply, Err(Error::NotConnected));
         }
-        quick_update_ts = Some(Instant::now());
+      },
+
+      SendCurrentMachineConfiguration(bytes) => {
+        let closure_controller = controller.clone();
+        spawn_command(
+          info("SendCurrentMachineConfiguration"),
+          target,
+          reply,
+          &controller,
+          &internal_tx,
+          move |connection| async move {
+            let result = connection.send_machine_configuration(bytes).await;
+            match result {
+              Ok(()) => closure_controller.snackbar_message("ConfigurazioneCaricata"),
+              Err(ref e) => {
+                log::error!("Unable to put machine config: {:?}", e);
+                closure_controller.snackbar_message("NonSonoRiuscitoACaricareLaConfigurazione");
+              }
+            }
+            result
+          },
+        );
+      }
+
+      GetCurrentMachineConfiguration => {
+        let closure_controller = controller.clone();
+        spawn_command(
+          info("GetCurrentMachineConfiguration"),
+          target,
+          reply,
+          &controller,
+          &internal_tx,
+          move |connection| async move {
+            match connection.get_machine_configuration().await {
+              Ok(bytes) => {
+                closure_controller.emit("remoteMachineLoaded", &bytes);
+                closure_controller.snackbar_message("ConfigurazioneScaricata");
+                Ok(bytes)
+              }
+              Err(e) => {
+                log::error!("Unable to get machine config: {:?}", e);
+                closure_controller.snackbar_message("NonSonoRiuscitoAScaricareLaConfigurazione");
+                Err(e)
+              }
+            }
+          },
+        );
+      }
+
+      SelectMachineConfiguration(archive) => {
+        let closure_controller = controller.clone();
+        spawn_command(
+          info("SelectMachineConfiguration"),
+          target,
+          reply,
+          &controller,
+          &internal_tx,
+          move |connection| async move {
+            let result = connection.select_machine_configuration(archive).await;
+            match result {
+              Ok(()) => closure_controller.snackbar_message("Successo"),
+              Err(Error::Unsupported) => {
+                closure_controller.snackbar_message("OperazioneNonSupportata")
+              }
+              Err(_) => closure_controller.snackbar_message("Fallimento"),
+            }
+            result
+          },
+        );
+      }
+
+      StartProgram(program) => {
+        spawn_command(
+          info("StartProgram"),
+          target,
+          reply,
+          &controller,
+          &internal_tx,
+          move |connection| async move { connection.start_program(program).await },
+        );
       }
 
-      Ok(GetCurrentMachineConfiguration) => {
-        match connection.as_ref().unwrap().get_machine_configuration() {
-          Ok(bytes) => {
-            controller.window().emit("remoteMachineLoaded", bytes).ok();
-            controller.snackbar_message("ConfigurazioneScaricata");
+      ScheduleStart { program, at } => {
+        let programs = target
+          .as_ref()
+          .and_then(|(_, connection)| connection.get_connection_state().data().cloned())
+          .map(|data| data.configuration.programs.len());
+        match (target, programs) {
+          (Some((id, _)), Some(programs)) if (program as usize) < programs => {
+            let target = targets.get(&id).cloned();
+            match scheduler.add(id, program, at, target) {
+              Ok(start) => {
+                log::info!("Scheduled {:?}", start);
+                controller.emit("scheduledStarts", scheduler.list());
+                respond(reply, Ok(start));
+              }
+              Err(e) => respond::<()>(reply, Err(e)),
+            }
           }
-          Err(e) => {
-            log::error!("Unable to get machine config: {:?}", e);
-            controller.snackbar_message("NonSonoRiuscitoAScaricareLaConfigurazione");
+          (Some(_), Some(_)) => respond::<()>(reply, Err(Error::Value)),
+          _ => respond::<()>(reply, Err(Error::NotConnected)),
+        }
+      }
+
+      CancelScheduledStart(start_id) => {
+        let result = scheduler.cancel(start_id);
+        if result.is_ok() {
+          controller.emit("scheduledStarts", scheduler.list());
+        }
+        respond(reply, result);
+      }
+
+      GetScheduledStarts => respond(reply, Ok(scheduler.list())),
+
+      GetMaintenanceRules => respond(reply, Ok(maintenance.rules())),
+
+      SetMaintenanceRule(rule) => respond(reply, maintenance.set_rule(rule)),
+
+      RemoveMaintenanceRule(rule) => respond(reply, maintenance.remove_rule(rule)),
+
+      RecordService(rule) => {
+        let data = target
+          .as_ref()
+          .and_then(|(_, connection)| connection.get_connection_state().data().cloned());
+        match (target, data) {
+          (Some((id, _)), Some(data)) => {
+            respond(reply, maintenance.record_service(rule, &id, &data))
           }
-        };
+          _ => respond::<()>(reply, Err(Error::NotConnected)),
+        }
+      }
+
+      GetMaintenanceStatus => {
+        let status: Vec<MaintenanceStatus> = machines
+          .iter()
+          .filter(|(id, _)| {
+            requested_id
+              .as_ref()
+              .map_or(true, |requested| requested == *id)
+          })
+          .filter_map(|(id, machine)| {
+            machine
+              .last_data
+              .as_ref()
+              .map(|data| maintenance.status(id, data))
+          })
+          .flatten()
+          .collect();
+        respond(reply, Ok(status));
       }
 
-      Ok(SelectMachineConfiguration(archive)) => {
-        match connection
+      GenerateReport { path, pdf } => {
+        let data = target
           .as_ref()
-          .unwrap()
-          .select_machine_configuration(archive)
-        {
-          Ok(()) => {
-            controller.snackbar_message("Successo");
-            quick_update_ts = Some(Instant::now());
+          .and_then(|(_, connection)| connection.get_connection_state().data().cloned());
+        match (target, data) {
+          (Some((id, _)), Some(data)) => {
+            let history = controller.context.history.clone();
+            tokio::task::spawn_blocking(move || {
+              let alarms = report::recent_alarms(&history, &id);
+              let html = report::render(&id, &data, &alarms);
+              let result = report::save(html.as_str(), &path, pdf.as_deref());
+              if let Err(ref e) = result {
+                log::error!("Unable to save the report of {}: {:?}", id, e);
+              }
+              respond(reply, result);
+            });
           }
-          Err(_) => controller.snackbar_message("Fallimento"),
-        };
+          _ => respond::<()>(reply, Err(Error::NotConnected)),
+        }
       }
 
-      Ok(StartProgram(program)) => {
-        if let Some(ref mut unwrapped_connection) = connection {
-          unwrapped_connection.start_program(program).ok();
-          quick_update_ts = Some(Instant::now());
+      SetStatisticsInterval(secs) => {
+        let interval = Duration::from_secs(secs);
+        let result = if interval < statistics::MIN_INTERVAL {
+          Err(Error::Value)
+        } else {
+          prefs::set_statistics_interval(interval)
+        };
+        if result.is_ok() {
+          statistics_log.set_interval(interval);
         }
+        respond(reply, result);
       }
 
-      Ok(Restart) => {
-        connection.as_deref_mut().unwrap().restart().ok();
-        quick_update_ts = Some(Instant::now());
+      PushConfigurationToFleet {
+        archive,
+        targets,
+        select,
+        token,
+      } => {
+        log::info!("Pushing configuration to {} machines", targets.len());
+        let connected = machines
+          .iter()
+          .map(|(id, machine)| (id.clone(), machine.connection.clone()))
+          .collect();
+        let token = token.or_else(prefs::get_token);
+        let controller = controller.clone();
+        tokio::spawn(async move {
+          let report = fleet::push(
+            controller, request_id, targets, connected, token, archive, select,
+          )
+          .await;
+          respond(reply, Ok(report));
+        });
       }
 
-      Ok(Pause) => {
-        connection.as_deref_mut().unwrap().pause().ok();
-        quick_update_ts = Some(Instant::now());
+      Restart => {
+        spawn_command(
+          info("Restart"),
+          target,
+          reply,
+          &controller,
+          &internal_tx,
+          |connection| async move { connection.restart().await },
+        );
       }
 
-      Ok(Stop) => {
-        connection.as_deref_mut().unwrap().stop().ok();
-        quick_update_ts = Some(Instant::now());
+      Pause => {
+        spawn_command(
+          info("Pause"),
+          target,
+          reply,
+          &controller,
+          &internal_tx,
+          |connection| async move { connection.pause().await },
+        );
       }
 
-      Ok(ClearAlarms) => {
-        connection.as_deref_mut().unwrap().clear_alarms().ok();
-        quick_update_ts = Some(Instant::now());
+      Stop => {
+        spawn_command(
+          info("Stop"),
+          target,
+          reply,
+          &controller,
+          &internal_tx,
+          |connection| async move { connection.stop().await },
+        );
       }
 
-      Err(mpsc::RecvTimeoutError::Disconnected) => panic!("Disconnected from queue!"),
-      Err(mpsc::RecvTimeoutError::Timeout) => (),
+      ClearAlarms => {
+        spawn_command(
+          info("ClearAlarms"),
+          target,
+          reply,
+          &controller,
+          &internal_tx,
+          |connection| async move { connection.clear_alarms().await },
+        );
+      }
     }
+  }
+
+  fn handle_internal(self: &mut Self, message: InternalMessage) {
+    let Backend {
+      controller,
+      machines,
+      selected,
+      statistics_log,
+      maintenance,
+      ..
+    } = self;
+
+    match message {
+      InternalMessage::Connected(id, connection, reply) => {
+        log::info!("Connected to {}", id);
+        controller.snackbar_message("Connesso");
+        // Connecting to a machine that is already known replaces the previous connection
+        machines.insert(id.clone(), Machine::new(connection));
+        *selected = Some(id.clone());
+        controller.send_all_states(&machines, &selected);
+        respond(reply, Ok(id));
+      }
+
+      InternalMessage::Refreshed(id, connection) => {
+        if let Some(machine) = machines.get_mut(&id) {
+          // The machine might have been replaced while the refresh was in flight
+          if Arc::ptr_eq(&machine.connection, &connection) {
+            machine.refreshing = false;
+            machine.update_ts = Instant::now();
+            let events = machine.transitions();
+            if let Some(ref data) = machine.last_data {
+              for event in events {
+                controller.machine_event(&id, data, event);
+              }
+              if machine.connection.get_connection_state().is_connected() && !is_virtual(&id) {
+                statistics_log.sample(&id, data);
+                for status in maintenance.check(&id, data) {
+                  controller.maintenance_alert(data, status);
+                }
+              }
+            }
+            controller.send_state(&id, machine, &selected);
+          }
+        }
+      }
 
-    if let Some(ref mut unwrapped_connection) = connection {
-      if let Some(ts) = quick_update_ts {
-        if ts.elapsed() > Duration::from_millis(300) {
-          unwrapped_connection.refresh_data();
-          controller.send_state(&connection);
-          update_ts = Instant::now();
-          quick_update_ts = None;
+      InternalMessage::CommandCompleted(id) => {
+        if let Some(machine) = machines.get_mut(&id) {
+          machine.quick_update_ts = Some(Instant::now());
         }
-      } else if update_ts.elapsed() > unwrapped_connection.suggested_refresh_period() {
-        unwrapped_connection.refresh_data();
-        controller.send_state(&connection);
-        update_ts = Instant::now();
       }
     }
   }
+
+  /// Reconnects the machines of the starts about to come due, and fires the starts that came due
+  fn fire_scheduled_starts(self: &mut Self) {
+    let Backend {
+      controller,
+      machines,
+      reconnections,
+      scheduler,
+      internal_tx,
+      ..
+    } = self;
+
+    for (machine, target) in scheduler.awaiting_connection(|id| machines.contains_key(id)) {
+      let attempted = reconnections
+        .get(&machine)
+        .map_or(false, |ts| ts.elapsed() < RECONNECTION_INTERVAL);
+      if !attempted {
+        log::info!("Reconnecting to {} for a scheduled start", machine);
+        reconnections.insert(machine, Instant::now());
+        spawn_connect(target, None, None, controller, internal_tx);
+      }
+    }
+
+    let due = scheduler.take_due(|id| machines.contains_key(id));
+    if !due.is_empty() {
+      controller.emit("scheduledStarts", scheduler.list());
+    }
+    for start in due {
+      log::info!("Firing {:?}", start);
+      let program = start.program;
+      let target = machines
+        .get(&start.machine)
+        .map(|machine| (start.machine.clone(), machine.connection.clone()));
+      // The id of the scheduled start doubles as correlation id of the command result
+      let info = CommandInfo {
+        id: Some(start.id),
+        machine: Some(start.machine),
+        command: "ScheduledStart",
+      };
+      spawn_command(
+        info,
+        target,
+        None,
+        &controller,
+        &internal_tx,
+        move |connection| async move { connection.start_program(program).await },
+      );
+    }
+  }
+
+  /// Spawns a refresh of every machine that needs one
+  fn refresh_machines(self: &mut Self) {
+    let Backend {
+      machines,
+      internal_tx,
+      ..
+    } = self;
+
+    for (id, machine) in machines.iter_mut() {
+      if !machine.refresh_due() {
+        continue;
+      }
+
+      machine.refreshing = true;
+      machine.quick_update_ts = None;
+
+      let id = id.clone();
+      let connection = machine.connection.clone();
+      let internal_tx = internal_tx.clone();
+      // Every machine is refreshed in its own task, so a slow one does not hold back the others
+      tokio::spawn(async move {
+        connection.refresh_data().await;
+        internal_tx
+          .send(InternalMessage::Refreshed(id, connection))
+          .ok();
+      });
+    }
+  }
+}
+
+async fn backend_loop(controller: Controller, mut rx: mpsc::UnboundedReceiver<Request>) {
+  let (internal_tx, mut internal_rx) = mpsc::unbounded_channel::<InternalMessage>();
+
+  if let Some(token) = prefs::get_token() {
+    let closure_controller = controller.clone();
+    tokio::spawn(async move {
+      if let Ok(devices) = things5_api::get_devices(&token).await {
+        closure_controller.emit("things5Login", token.clone());
+        closure_controller.emit("things5Devices", devices);
+      }
+    });
+  }
+
+  let mut backend = Backend {
+    controller,
+    machines: HashMap::new(),
+    selected: None,
+    simulations: HashMap::new(),
+    recordings: HashMap::new(),
+    targets: HashMap::new(),
+    reconnections: HashMap::new(),
+    scheduler: Scheduler::load(),
+    statistics_log: StatisticsLog::new(prefs::get_statistics_interval()),
+    maintenance: Maintenance::load(),
+    internal_tx,
+  };
+
+  log::info!("Starting backend loop");
+  let mut ticker = tokio::time::interval(Duration::from_millis(100));
+
+  loop {
+    tokio::select! {
+      request = rx.recv() => match request {
+        Some(request) => backend.handle_request(request),
+        None => {
+          log::warn!("Frontend port closed, stopping backend loop");
+          break;
+        }
+      },
+      Some(message) = internal_rx.recv() => backend.handle_internal(message),
+      _ = ticker.tick() => (),
+    }
+
+    backend.fire_scheduled_starts();
+    backend.refresh_machines();
+  }
 }
@@ -0,0 +1,403 @@
+//! Native desktop notifications for the events operators should not miss while the app is
+//! minimised.
+//!
+//! Where the desktop reports clicks (the freedesktop notification servers on Linux), clicking a
+//! notification brings up the main window with the machine it is about selected. Elsewhere the
+//! notification API of Tauri is used, and its title names the machine instead.
+//!
+//! The texts come from the translation table of the frontend, in the language the frontend is
+//! shown in.
+use super::events::MachineEvent;
+use super::handle::Handle;
+use super::maintenance::{MaintenanceStatus, ServiceLevel};
+use super::prefs;
+use super::washing_machine::MachineData;
+use super::Error;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tauri::Window;
+
+/// Translation table of the frontend, one row per text and one column per language
+const STRINGS: &str = include_str!("../../../assets/translations/strings.csv");
+
+/// Language of the frontend, as named in the header of the translation table
+static LANGUAGE: Lazy<RwLock<String>> = Lazy::new(|| RwLock::new(String::from("English")));
+
+/// Settings in use, read from the preferences once and then kept up to date by `set_settings`
+static SETTINGS: Lazy<RwLock<NotificationSettings>> =
+  Lazy::new(|| RwLock::new(prefs::get_notification_settings()));
+
+/// Which events raise a notification
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct NotificationRules {
+  pub alarm_raised: bool,
+  pub cycle_completed: bool,
+  pub cycle_interrupted: bool,
+  pub maintenance: bool,
+}
+
+impl Default for NotificationRules {
+  fn default() -> Self {
+    Self {
+      alarm_raised: true,
+      cycle_completed: true,
+      cycle_interrupted: true,
+      maintenance: true,
+    }
+  }
+}
+
+/// Rules applied to every machine, unless overridden for a specific one
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct NotificationSettings {
+  pub defaults: NotificationRules,
+  pub machines: HashMap<String, NotificationRules>,
+}
+
+impl NotificationSettings {
+  pub fn rules(self: &Self, machine: &String) -> &NotificationRules {
+    self.machines.get(machine).unwrap_or(&self.defaults)
+  }
+}
+
+pub fn settings() -> NotificationSettings {
+  SETTINGS.read().unwrap().clone()
+}
+
+/// Saves the settings, which apply to the next notifications
+pub fn set_settings(settings: NotificationSettings) -> Result<(), Error> {
+  prefs::set_notification_settings(&settings)?;
+  *SETTINGS.write().unwrap() = settings;
+  Ok(())
+}
+
+fn rules(machine: &String) -> NotificationRules {
+  SETTINGS.read().unwrap().rules(machine).clone()
+}
+
+/// Language of the next notifications, `Italiano` or `English`
+pub fn set_language(language: String) {
+  *LANGUAGE.write().unwrap() = language;
+}
+
+fn language() -> String {
+  LANGUAGE.read().unwrap().clone()
+}
+
+/// Text of `id` in the translation table, in Italian when the language is unknown
+fn translate(id: &str, language: &str) -> String {
+  let mut rows = STRINGS
+    .lines()
+    .map(|line| line.split(',').map(str::trim).collect::<Vec<_>>());
+  let column = rows
+    .next()
+    .and_then(|header| header.iter().position(|l| *l == language))
+    .unwrap_or(1);
+
+  rows
+    .find(|row| row[0] == id)
+    .and_then(|row| row.get(column).or_else(|| row.get(1)).copied())
+    .unwrap_or(id)
+    .to_string()
+}
+
+/// Text of the notification for the event, `None` if the rules leave the event out
+fn event_body(
+  rules: &NotificationRules,
+  data: &MachineData,
+  event: &MachineEvent,
+  language: &str,
+) -> Option<String> {
+  match *event {
+    MachineEvent::AlarmRaised { code, ref alarm } if rules.alarm_raised => {
+      let description = if language == "English" {
+        &alarm.description.en
+      } else {
+        &alarm.description.it
+      };
+      Some(format!(
+        "{} {}: {}",
+        translate("Allarme", language),
+        code,
+        description
+      ))
+    }
+    MachineEvent::CycleCompleted { program } if rules.cycle_completed => Some(format!(
+      "{}: {}",
+      translate("CicloCompletato", language),
+      program_name(data, program)
+    )),
+    MachineEvent::CycleInterrupted { program } if rules.cycle_interrupted => Some(format!(
+      "{}: {}",
+      translate("CicloInterrotto", language),
+      program_name(data, program)
+    )),
+    _ => None,
+  }
+}
+
+/// Text of the notification for a service that became due, `None` if the rules leave it out
+fn maintenance_body(
+  rules: &NotificationRules,
+  status: &MaintenanceStatus,
+  language: &str,
+) -> Option<String> {
+  if !rules.maintenance {
+    return None;
+  }
+
+  let id = match status.level {
+    ServiceLevel::Overdue => "ManutenzioneScaduta",
+    _ => "ManutenzioneInScadenza",
+  };
+  Some(format!("{}: {}", translate(id, language), status.rule.name))
+}
+
+// The window and the handle are only used on the desktops that report clicks
+#[cfg_attr(not(all(unix, not(target_os = "macos"))), allow(dead_code))]
+pub struct Notifier {
+  identifier: String,
+  window: Window,
+  handle: Handle,
+}
+
+impl Notifier {
+  /// `identifier` is the bundle identifier of the application, `window` the one brought up by a
+  /// click on a notification
+  pub fn new(identifier: String, window: Window, handle: Handle) -> Self {
+    Self {
+      identifier,
+      window,
+      handle,
+    }
+  }
+
+  /// Shows a notification for the event, if the settings for the machine ask for it
+  pub fn notify(self: &Self, machine: &String, data: &MachineData, event: &MachineEvent) {
+    if let Some(body) = event_body(&rules(machine), data, event, &language()) {
+      self.show(machine, data, body);
+    }
+  }
+
+  /// Shows a notification for a service that became due, if the settings for the machine ask
+  /// for it
+  pub fn notify_maintenance(
+    self: &Self,
+    machine: &String,
+    data: &MachineData,
+    status: &MaintenanceStatus,
+  ) {
+    if let Some(body) = maintenance_body(&rules(machine), status, &language()) {
+      self.show(machine, data, body);
+    }
+  }
+
+  fn show(self: &Self, machine: &String, data: &MachineData, body: String) {
+    let title = if data.name.is_empty() {
+      machine.clone()
+    } else {
+      data.name.clone()
+    };
+    self.show_native(machine, title, body);
+  }
+
+  /// Shows the notification and waits for the click on a thread of its own, as the notification
+  /// server keeps the notification until it is dismissed
+  #[cfg(all(unix, not(target_os = "macos")))]
+  fn show_native(self: &Self, machine: &String, title: String, body: String) {
+    let identifier = self.identifier.clone();
+    let window = self.window.clone();
+    let handle = self.handle.clone();
+    let machine = machine.clone();
+
+    std::thread::spawn(move || {
+      let shown = notify_rust::Notification::new()
+        .appname(&identifier)
+        .summary(&title)
+        .body(&body)
+        .action("default", &title)
+        .show();
+      match shown {
+        Ok(notification) => notification.wait_for_action(|action| {
+          if action == "default" {
+            bring_up(&window, &handle, machine);
+          }
+        }),
+        Err(e) => log::warn!("Unable to show notification: {:?}", e),
+      }
+    });
+  }
+
+  #[cfg(not(all(unix, not(target_os = "macos"))))]
+  fn show_native(self: &Self, _machine: &String, title: String, body: String) {
+    if let Err(e) = tauri::api::notification::Notification::new(self.identifier.clone())
+      .title(title)
+      .body(body)
+      .show()
+    {
+      log::warn!("Unable to show notification: {:?}", e);
+    }
+  }
+}
+
+/// Shows the main window with the machine selected, in the backend and in the frontend
+#[cfg(all(unix, not(target_os = "macos")))]
+fn bring_up(window: &Window, handle: &Handle, machine: String) {
+  use super::{handle::Request, BackEndPortMessage};
+
+  for result in [window.show(), window.unminimize(), window.set_focus()] {
+    if let Err(e) = result {
+      log::warn!("Unable to bring up the window: {:?}", e);
+    }
+  }
+
+  let request = Request {
+    id: None,
+    machine: None,
+    message: BackEndPortMessage::SelectMachine(machine.clone()),
+    reply: None,
+  };
+  if let Err(e) = handle.send(request) {
+    log::warn!("Unable to select {}: {:?}", machine, e);
+  }
+  if let Err(e) = window.emit("selectMachine", machine) {
+    log::warn!("Unable to emit selectMachine: {:?}", e);
+  }
+}
+
+fn program_name(data: &MachineData, program: u16) -> String {
+  data
+    .configuration
+    .programs
+    .get(program as usize)
+    .map_or_else(|| format!("{}", program), |p| p.name.clone())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::controller::maintenance::{MaintenanceRule, ServiceCounter};
+  use crate::controller::testing::machine_data;
+  use crate::controller::washing_machine::{alarms, Statistics};
+
+  fn data() -> MachineData {
+    machine_data(Statistics::default())
+  }
+
+  fn status(level: ServiceLevel) -> MaintenanceStatus {
+    MaintenanceStatus {
+      rule: MaintenanceRule {
+        id: 1,
+        name: String::from("Filtro"),
+        machine: None,
+        counter: ServiceCounter::Cycles,
+        interval: 1000,
+        warning: 100,
+      },
+      machine: String::from("10.0.0.1"),
+      level,
+      last_service: 0,
+      current: 1000,
+      remaining: 0,
+    }
+  }
+
+  #[test]
+  fn machines_override_the_defaults() {
+    let quiet = NotificationRules {
+      cycle_completed: false,
+      ..NotificationRules::default()
+    };
+    let settings = NotificationSettings {
+      defaults: NotificationRules::default(),
+      machines: vec![(String::from("10.0.0.2"), quiet)]
+        .into_iter()
+        .collect(),
+    };
+
+    assert!(settings.rules(&String::from("10.0.0.1")).cycle_completed);
+    assert!(!settings.rules(&String::from("10.0.0.2")).cycle_completed);
+    assert!(settings.rules(&String::from("10.0.0.2")).alarm_raised);
+  }
+
+  #[test]
+  fn events_left_out_by_the_rules() {
+    let rules = NotificationRules {
+      alarm_raised: false,
+      cycle_interrupted: false,
+      ..NotificationRules::default()
+    };
+    let alarm = MachineEvent::AlarmRaised {
+      code: 3,
+      alarm: alarms::describe(3),
+    };
+
+    assert!(event_body(&rules, &data(), &alarm, "English").is_none());
+    assert!(event_body(
+      &rules,
+      &data(),
+      &MachineEvent::CycleInterrupted { program: 0 },
+      "English"
+    )
+    .is_none());
+    assert!(event_body(
+      &rules,
+      &data(),
+      &MachineEvent::CycleCompleted { program: 0 },
+      "English"
+    )
+    .is_some());
+    // Only some events are worth a notification
+    assert!(event_body(
+      &NotificationRules::default(),
+      &data(),
+      &MachineEvent::PortholeOpened,
+      "English"
+    )
+    .is_none());
+
+    let rules = NotificationRules {
+      maintenance: false,
+      ..NotificationRules::default()
+    };
+    assert!(maintenance_body(&rules, &status(ServiceLevel::Overdue), "English").is_none());
+  }
+
+  #[test]
+  fn texts_follow_the_language() {
+    let rules = NotificationRules::default();
+    let alarm = MachineEvent::AlarmRaised {
+      code: 9999,
+      alarm: alarms::describe(9999),
+    };
+
+    assert_eq!(
+      event_body(&rules, &data(), &alarm, "English"),
+      Some(String::from("Alarm 9999: Unknown alarm 9999"))
+    );
+    assert_eq!(
+      event_body(&rules, &data(), &alarm, "Italiano"),
+      Some(String::from("Allarme 9999: Allarme sconosciuto 9999"))
+    );
+    assert_eq!(
+      event_body(
+        &rules,
+        &data(),
+        &MachineEvent::CycleCompleted { program: 42 },
+        "English"
+      ),
+      Some(String::from("Cycle completed: 42"))
+    );
+    assert_eq!(
+      maintenance_body(&rules, &status(ServiceLevel::Overdue), "Italiano"),
+      Some(String::from("Manutenzione scaduta: Filtro"))
+    );
+    assert_eq!(
+      maintenance_body(&rules, &status(ServiceLevel::Due), "English"),
+      Some(String::from("Maintenance due: Filtro"))
+    );
+  }
+}
@@ -1,6 +1,13 @@
+use super::consumption::RatesTable;
+use super::notifications::NotificationSettings;
+use super::Error;
 use log::warn;
 use preferences::{AppInfo, Preferences, PreferencesMap};
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AppPreferences {
@@ -13,33 +20,122 @@ const APP_INFO: AppInfo = AppInfo {
   author: "HSW",
 };
 const TOKEN_PREF: &str = "token";
+const GATEWAY_ADDRESS_PREF: &str = "gateway_address";
+const GATEWAY_TOKEN_PREF: &str = "gateway_token";
+const GATEWAY_TOKEN_LENGTH: usize = 32;
+const NOTIFICATIONS_PREF: &str = "notifications";
+const STATISTICS_INTERVAL_PREF: &str = "statistics_interval";
+const CONSUMPTION_RATES_PREF: &str = "consumption_rates";
 const PREFERENCES_KEY: &str = "laundry-control-preferences";
+const DATA_DIR: &str = "laundry-control";
 
-pub fn set_token(token: String) {
-  // Create a new preferences key-value map
+fn load() -> PreferencesMap<String> {
+  match PreferencesMap::<String>::load(&APP_INFO, PREFERENCES_KEY) {
+    Ok(map) => map,
+    Err(e) => {
+      warn!("Error while loading preferences: {:?}", e);
+      PreferencesMap::new()
+    }
+  }
+}
+
+fn set(key: &str, value: String) -> Result<(), Error> {
+  // Load the existing preferences first so that other keys are preserved
   // (Under the hood: HashMap<String, String>)
-  let mut faves: PreferencesMap<String> = PreferencesMap::new();
+  let mut faves: PreferencesMap<String> = load();
 
   // Edit the preferences (std::collections::HashMap)
-  faves.insert(TOKEN_PREF.into(), token);
+  faves.insert(key.into(), value);
 
   // Store the user's preferences
-  let save_result = faves.save(&APP_INFO, PREFERENCES_KEY);
-  assert!(save_result.is_ok());
+  faves
+    .save(&APP_INFO, PREFERENCES_KEY)
+    .map_err(|e| Error::Server(format!("Unable to save preferences: {:?}", e)))
+}
+
+fn get(key: &str) -> Option<String> {
+  load().get(key).map(String::from)
+}
+
+pub fn set_token(token: String) -> Result<(), Error> {
+  set(TOKEN_PREF, token)
 }
 
 pub fn get_token() -> Option<String> {
-  match PreferencesMap::<String>::load(&APP_INFO, PREFERENCES_KEY) {
-    Ok(map) => {
-      if let Some(token) = map.get(TOKEN_PREF) {
-        Some(String::from(token))
-      } else {
-        None
-      }
-    }
-    Err(e) => {
-      warn!("Error while loading preferences: {:?}", e);
-      None
+  get(TOKEN_PREF)
+}
+
+pub fn set_gateway_address(address: Option<SocketAddr>) -> Result<(), Error> {
+  set(
+    GATEWAY_ADDRESS_PREF,
+    address.map(|a| a.to_string()).unwrap_or_default(),
+  )
+}
+
+/// Address the local gateway should listen on, if it is enabled; only loopback addresses are
+/// accepted, so that the machines cannot be driven from the rest of the network
+pub fn get_gateway_address() -> Option<SocketAddr> {
+  get(GATEWAY_ADDRESS_PREF)
+    .and_then(|address| address.parse::<SocketAddr>().ok())
+    .filter(|address| address.ip().is_loopback())
+}
+
+/// Token that clients of the gateway must send as `Authorization: Bearer <token>`, generated the
+/// first time it is needed
+pub fn gateway_token() -> Result<String, Error> {
+  match get(GATEWAY_TOKEN_PREF).filter(|token| !token.is_empty()) {
+    Some(token) => Ok(token),
+    None => {
+      let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(GATEWAY_TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+      set(GATEWAY_TOKEN_PREF, token.clone())?;
+      Ok(token)
     }
   }
 }
+
+pub fn set_notification_settings(settings: &NotificationSettings) -> Result<(), Error> {
+  set(
+    NOTIFICATIONS_PREF,
+    serde_json::to_string(settings).unwrap_or_default(),
+  )
+}
+
+pub fn get_notification_settings() -> NotificationSettings {
+  get(NOTIFICATIONS_PREF)
+    .and_then(|settings| serde_json::from_str(settings.as_str()).ok())
+    .unwrap_or_default()
+}
+
+pub fn set_statistics_interval(interval: Duration) -> Result<(), Error> {
+  set(STATISTICS_INTERVAL_PREF, interval.as_secs().to_string())
+}
+
+/// How often the counters of the connected machines are sampled
+pub fn get_statistics_interval() -> Duration {
+  get(STATISTICS_INTERVAL_PREF)
+    .and_then(|secs| secs.parse().ok())
+    .map_or(super::statistics::DEFAULT_INTERVAL, Duration::from_secs)
+    .max(super::statistics::MIN_INTERVAL)
+}
+
+pub fn set_consumption_rates(rates: &RatesTable) -> Result<(), Error> {
+  set(
+    CONSUMPTION_RATES_PREF,
+    serde_json::to_string(rates).unwrap_or_default(),
+  )
+}
+
+pub fn get_consumption_rates() -> RatesTable {
+  get(CONSUMPTION_RATES_PREF)
+    .and_then(|rates| serde_json::from_str(rates.as_str()).ok())
+    .unwrap_or_default()
+}
+
+/// Location of a file in the application data directory, if the platform has one
+pub fn data_file(name: &str) -> Option<PathBuf> {
+  tauri::api::path::data_dir().map(|dir| dir.join(DATA_DIR).join(name))
+}
@@ -0,0 +1,241 @@
+//! Self-contained service report of a machine, left to the customer after a visit.
+//!
+//! The report is a single HTML file with inline styles; the PDF version is produced by
+//! `wkhtmltopdf`, which has to be installed separately. [`pdf_available`] tells whether it is,
+//! so that PDF export is only offered when it can work.
+use super::history::{HistoryEntry, HistoryQuery, HistoryStore, Record};
+use super::statistics;
+use super::washing_machine::{alarms, MachineData};
+use super::Error;
+use chrono::Local;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const RECENT_ALARMS: usize = 20;
+const PDF_CONVERTER: &str = "wkhtmltopdf";
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; color: #222; }
+h1 { margin-bottom: 0; }
+h2 { margin-top: 1.5em; border-bottom: 1px solid #ccc; }
+table { border-collapse: collapse; }
+td, th { padding: 0.2em 1em 0.2em 0; text-align: left; }
+.subtitle { color: #666; }";
+
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+fn table(rows: Vec<(String, String)>) -> String {
+  let rows: Vec<String> = rows
+    .into_iter()
+    .map(|(key, value)| {
+      format!(
+        "<tr><th>{}</th><td>{}</td></tr>",
+        escape(&key),
+        escape(&value)
+      )
+    })
+    .collect();
+  format!("<table>{}</table>", rows.join(""))
+}
+
+/// Alarms recorded for the machine, newest first
+pub fn recent_alarms(history: &HistoryStore, machine: &String) -> Vec<HistoryEntry> {
+  let query = HistoryQuery {
+    machine: Some(machine.clone()),
+    kinds: Some(vec![
+      String::from("AlarmRaised"),
+      String::from("AlarmCleared"),
+    ]),
+    limit: Some(RECENT_ALARMS),
+    ..HistoryQuery::default()
+  };
+
+  match history.query(&query) {
+    Ok(page) => page.entries,
+    Err(e) => {
+      log::warn!("Unable to read the alarm history of {}: {:?}", machine, e);
+      vec![]
+    }
+  }
+}
+
+pub fn render(machine: &String, data: &MachineData, alarms: &Vec<HistoryEntry>) -> String {
+  let configuration = &data.configuration;
+  let state = &data.state;
+
+  let identity = table(vec![
+    (String::from("Machine"), machine.clone()),
+    (String::from("Name"), data.name.clone()),
+    (String::from("Configuration"), configuration.name.clone()),
+    (
+      String::from("Application version"),
+      configuration.app_version.clone(),
+    ),
+  ]);
+
+  let alarm = match state.alarm {
+    Some(ref alarm) => format!(
+      "{} - {} ({})",
+      alarm.code, alarm.description.en, alarm.remedy.en
+    ),
+    None => String::from("None"),
+  };
+
+  let current = table(vec![
+    (String::from("State"), state.state.to_string()),
+    (
+      String::from("Program"),
+      configuration
+        .programs
+        .get(state.cycle as usize)
+        .map_or_else(
+          || state.cycle.to_string(),
+          |program| format!("{} - {}", state.cycle + 1, program.name),
+        ),
+    ),
+    (
+      String::from("Step"),
+      format!("{} / {}", state.step_number, state.step_count),
+    ),
+    (String::from("Step type"), state.step_code.to_string()),
+    (
+      String::from("Cycle remaining"),
+      format!("{} s", state.cycle_remaining),
+    ),
+    (String::from("Alarm"), alarm),
+    (
+      String::from("Porthole open"),
+      state.porthole_open.to_string(),
+    ),
+    (String::from("Temperature"), state.temperature.to_string()),
+    (String::from("Level"), state.level.to_string()),
+    (String::from("Speed"), state.speed.to_string()),
+    (String::from("Credit"), state.credit.to_string()),
+  ]);
+
+  let stats = table(
+    statistics::counters(&data.stats)
+      .into_iter()
+      .map(|(name, value)| (name, value.to_string()))
+      .collect(),
+  );
+
+  let alarms = if alarms.is_empty() {
+    String::from("<p>No alarms recorded.</p>")
+  } else {
+    table(
+      alarms
+        .iter()
+        .filter_map(|entry| {
+          let description = match entry.record {
+            Record::AlarmRaised { code } => format!(
+              "Alarm {} raised: {}",
+              code,
+              alarms::describe(code).description.en
+            ),
+            Record::AlarmCleared {
+              code,
+              duration_secs: Some(secs),
+            } => format!("Alarm {} cleared after {} s", code, secs),
+            Record::AlarmCleared { code, .. } => format!("Alarm {} cleared", code),
+            _ => return None,
+          };
+          let timestamp = entry.timestamp.with_timezone(&Local);
+          Some((
+            timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+            description,
+          ))
+        })
+        .collect(),
+    )
+  };
+
+  let programs = table(
+    configuration
+      .programs
+      .iter()
+      .enumerate()
+      .map(|(i, program)| ((i + 1).to_string(), program.name.clone()))
+      .collect(),
+  );
+
+  format!(
+    "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>{title}</title>
+<style>{style}</style>
+</head>
+<body>
+<h1>{title}</h1>
+<p class=\"subtitle\">{generated}</p>
+<h2>Identity</h2>
+{identity}
+<h2>Current state</h2>
+{current}
+<h2>Statistics</h2>
+{stats}
+<h2>Recent alarms</h2>
+{alarms}
+<h2>Programs</h2>
+{programs}
+</body>
+</html>
+",
+    title = escape(format!("Service report - {}", data.name).as_str()),
+    style = STYLE,
+    generated = Local::now().format("%Y-%m-%d %H:%M"),
+    identity = identity,
+    current = current,
+    stats = stats,
+    alarms = alarms,
+    programs = programs,
+  )
+}
+
+/// Whether the PDF converter is installed and can be run
+pub fn pdf_available() -> bool {
+  Command::new(PDF_CONVERTER)
+    .arg("--version")
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .status()
+    .map_or(false, |status| status.success())
+}
+
+/// Writes the report as HTML and, when asked, converts it to PDF as well.
+///
+/// Nothing is written when a PDF is requested but the converter is missing.
+pub fn save(html: &str, path: &Path, pdf: Option<&Path>) -> Result<(), Error> {
+  if pdf.is_some() && !pdf_available() {
+    return Err(Error::Server(format!(
+      "PDF export needs {}, which was not found on the PATH",
+      PDF_CONVERTER
+    )));
+  }
+
+  fs::write(path, html).map_err(|e| Error::Server(e.to_string()))?;
+
+  if let Some(pdf) = pdf {
+    let status = Command::new(PDF_CONVERTER)
+      .arg("--quiet")
+      .arg(path)
+      .arg(pdf)
+      .status()
+      .map_err(|e| Error::Server(format!("Unable to run {}: {}", PDF_CONVERTER, e)))?;
+    if !status.success() {
+      return Err(Error::Server(format!(
+        "{} failed with {}",
+        PDF_CONVERTER, status
+      )));
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,259 @@
+//! Program starts planned ahead of time, saved on disk so that they survive a restart of the app.
+//!
+//! Every start also remembers how its machine was reached, so that the machine can be connected
+//! again shortly before the start when the app was restarted in the meantime.
+use super::{ConnectionTarget, Error};
+use chrono::{DateTime, Duration, Utc};
+use std::fs;
+use std::path::PathBuf;
+
+const SCHEDULE_FILE: &str = "schedule.json";
+
+/// How long a start can wait for its machine to be connected before it is given up
+const GRACE_PERIOD_MINUTES: i64 = 10;
+/// How long before a start its machine is connected, if it is not already
+const RECONNECTION_LEAD_MINUTES: i64 = 2;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ScheduledStart {
+  pub id: u64,
+  pub machine: String,
+  /// Index in `Configuration.programs`
+  pub program: u16,
+  pub at: DateTime<Utc>,
+  /// How to reach the machine if it is not connected when the start comes due
+  #[serde(default)]
+  pub target: Option<ConnectionTarget>,
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Schedule {
+  next_id: u64,
+  starts: Vec<ScheduledStart>,
+}
+
+pub struct Scheduler {
+  path: Option<PathBuf>,
+  schedule: Schedule,
+}
+
+impl Scheduler {
+  /// Loads the pending starts saved by the previous run, if any
+  pub fn load() -> Self {
+    let path = super::prefs::data_file(SCHEDULE_FILE);
+    let schedule = path
+      .as_ref()
+      .and_then(|path| fs::read_to_string(path).ok())
+      .and_then(|content| match serde_json::from_str(content.as_str()) {
+        Ok(schedule) => Some(schedule),
+        Err(e) => {
+          log::error!("Invalid schedule file, discarding it: {:?}", e);
+          None
+        }
+      })
+      .unwrap_or_default();
+
+    Self { path, schedule }
+  }
+
+  fn save(self: &Self) {
+    let path = match self.path {
+      Some(ref path) => path,
+      None => return,
+    };
+
+    let result = path
+      .parent()
+      .map_or(Ok(()), fs::create_dir_all)
+      .and_then(|_| {
+        let content = serde_json::to_string(&self.schedule)?;
+        fs::write(path, content)
+      });
+
+    if let Err(e) = result {
+      log::error!("Unable to save the schedule to {:?}: {:?}", path, e);
+    }
+  }
+
+  pub fn list(self: &Self) -> Vec<ScheduledStart> {
+    self.schedule.starts.clone()
+  }
+
+  /// Plans a start, which must be in the future
+  pub fn add(
+    self: &mut Self,
+    machine: String,
+    program: u16,
+    at: DateTime<Utc>,
+    target: Option<ConnectionTarget>,
+  ) -> Result<ScheduledStart, Error> {
+    if at <= Utc::now() {
+      return Err(Error::Value);
+    }
+
+    self.schedule.next_id += 1;
+    let start = ScheduledStart {
+      id: self.schedule.next_id,
+      machine,
+      program,
+      at,
+      target,
+    };
+    self.schedule.starts.push(start.clone());
+    self.schedule.starts.sort_by_key(|start| start.at);
+    self.save();
+    Ok(start)
+  }
+
+  pub fn cancel(self: &mut Self, id: u64) -> Result<(), Error> {
+    let count = self.schedule.starts.len();
+    self.schedule.starts.retain(|start| start.id != id);
+    if self.schedule.starts.len() == count {
+      Err(Error::Value)
+    } else {
+      self.save();
+      Ok(())
+    }
+  }
+
+  /// Machines of the starts about to come due that are not connected, with how to reach them
+  pub fn awaiting_connection(
+    self: &Self,
+    connected: impl Fn(&String) -> bool,
+  ) -> Vec<(String, ConnectionTarget)> {
+    let horizon = Utc::now() + Duration::minutes(RECONNECTION_LEAD_MINUTES);
+    self
+      .schedule
+      .starts
+      .iter()
+      .filter(|start| start.at <= horizon && !connected(&start.machine))
+      .filter_map(|start| {
+        start
+          .target
+          .clone()
+          .map(|target| (start.machine.clone(), target))
+      })
+      .collect()
+  }
+
+  /// Removes and returns the starts that have to be handled now: those whose time has come and
+  /// whose machine is connected, and those that waited for their machine longer than the grace
+  /// period
+  pub fn take_due(self: &mut Self, connected: impl Fn(&String) -> bool) -> Vec<ScheduledStart> {
+    let now = Utc::now();
+    let (due, pending): (Vec<_>, Vec<_>) = self.schedule.starts.drain(..).partition(|start| {
+      start.at <= now
+        && (connected(&start.machine) || now - start.at > Duration::minutes(GRACE_PERIOD_MINUTES))
+    });
+
+    self.schedule.starts = pending;
+    if !due.is_empty() {
+      self.save();
+    }
+    due
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn start(id: u64, machine: &str, at: DateTime<Utc>) -> ScheduledStart {
+    ScheduledStart {
+      id,
+      machine: String::from(machine),
+      program: 1,
+      at,
+      target: Some(ConnectionTarget::Http(String::from(machine))),
+    }
+  }
+
+  /// A scheduler that keeps its starts in memory only
+  fn scheduler(starts: Vec<ScheduledStart>) -> Scheduler {
+    Scheduler {
+      path: None,
+      schedule: Schedule {
+        next_id: starts.len() as u64,
+        starts,
+      },
+    }
+  }
+
+  fn ids(starts: &[ScheduledStart]) -> Vec<u64> {
+    starts.iter().map(|start| start.id).collect()
+  }
+
+  #[test]
+  fn past_starts_are_refused() {
+    let mut scheduler = scheduler(vec![]);
+    assert!(matches!(
+      scheduler.add(String::from("10.0.0.1"), 1, Utc::now(), None),
+      Err(Error::Value)
+    ));
+
+    let later = Utc::now() + Duration::hours(1);
+    let added = scheduler
+      .add(String::from("10.0.0.1"), 1, later, None)
+      .unwrap();
+    assert_eq!(added.id, 1);
+    assert!(matches!(scheduler.cancel(2), Err(Error::Value)));
+    assert!(scheduler.cancel(1).is_ok());
+    assert!(scheduler.list().is_empty());
+  }
+
+  #[test]
+  fn due_starts_of_connected_machines() {
+    let now = Utc::now();
+    let mut scheduler = scheduler(vec![
+      start(1, "10.0.0.1", now - Duration::seconds(5)),
+      start(2, "10.0.0.1", now + Duration::hours(1)),
+    ]);
+
+    let due = scheduler.take_due(|_| true);
+    assert_eq!(ids(&due), vec![1]);
+    assert_eq!(ids(&scheduler.list()), vec![2]);
+    assert!(scheduler.take_due(|_| true).is_empty());
+  }
+
+  #[test]
+  fn starts_wait_for_their_machine() {
+    let now = Utc::now();
+    let mut scheduler = scheduler(vec![
+      start(1, "10.0.0.1", now - Duration::minutes(1)),
+      start(2, "10.0.0.2", now + Duration::minutes(1)),
+      start(3, "10.0.0.3", now + Duration::hours(1)),
+    ]);
+
+    // Machines are reconnected a little before their start, and only if they are not connected
+    let awaiting = scheduler.awaiting_connection(|machine| machine == "10.0.0.2");
+    assert_eq!(awaiting.len(), 1);
+    assert_eq!(awaiting[0].0, "10.0.0.1");
+    assert!(matches!(awaiting[0].1, ConnectionTarget::Http(ref ip) if ip == "10.0.0.1"));
+
+    // Held while the machine is away, fired once it is back
+    assert!(scheduler.take_due(|_| false).is_empty());
+    assert_eq!(ids(&scheduler.list()), vec![1, 2, 3]);
+    assert_eq!(ids(&scheduler.take_due(|_| true)), vec![1]);
+  }
+
+  #[test]
+  fn starts_missed_past_the_grace_period() {
+    let now = Utc::now();
+    let missed = now - Duration::minutes(GRACE_PERIOD_MINUTES + 1);
+    let mut scheduler = scheduler(vec![
+      start(1, "10.0.0.1", missed),
+      start(2, "10.0.0.2", now - Duration::minutes(1)),
+    ]);
+
+    // Given up even though the machine never came back, the other one is still waiting
+    let due = scheduler.take_due(|_| false);
+    assert_eq!(ids(&due), vec![1]);
+    assert_eq!(ids(&scheduler.list()), vec![2]);
+
+    // Starts without a way to reach the machine are left to wait
+    let mut unreachable = start(3, "10.0.0.3", now);
+    unreachable.target = None;
+    let scheduler = self::scheduler(vec![unreachable]);
+    assert!(scheduler.awaiting_connection(|_| false).is_empty());
+  }
+}
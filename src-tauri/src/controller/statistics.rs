@@ -0,0 +1,657 @@
+//! Time series of the machine counters, sampled while the machines are connected and kept in a
+//! JSON lines file in the application data directory.
+use super::washing_machine::{MachineData, Statistics};
+use super::Error;
+use chrono::{DateTime, Datelike, Duration as Days, Local, TimeZone, Timelike, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const STATISTICS_FILE: &str = "statistics.jsonl";
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Shortest interval accepted, so that the file does not grow with every refresh
+pub const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Sample {
+  pub timestamp: DateTime<Utc>,
+  pub machine: String,
+  pub machine_name: String,
+  pub stats: Statistics,
+}
+
+/// Writes a sample for every connected machine once per interval
+pub struct StatisticsLog {
+  path: Option<PathBuf>,
+  interval: Duration,
+  last_sample: HashMap<String, Instant>,
+}
+
+impl StatisticsLog {
+  pub fn new(interval: Duration) -> Self {
+    Self {
+      path: super::prefs::data_file(STATISTICS_FILE),
+      interval,
+      last_sample: HashMap::new(),
+    }
+  }
+
+  pub fn set_interval(self: &mut Self, interval: Duration) {
+    self.interval = interval;
+  }
+
+  /// Records the data just read from a machine, unless it was sampled recently
+  pub fn sample(self: &mut Self, machine: &String, data: &MachineData) {
+    let interval = self.interval;
+    if let Some(last) = self.last_sample.get(machine) {
+      if last.elapsed() < interval {
+        return;
+      }
+    }
+    self.last_sample.insert(machine.clone(), Instant::now());
+
+    let path = match self.path {
+      Some(ref path) => path,
+      None => return,
+    };
+
+    let sample = Sample {
+      timestamp: Utc::now(),
+      machine: machine.clone(),
+      machine_name: data.name.clone(),
+      stats: data.stats.clone(),
+    };
+
+    let result = path
+      .parent()
+      .map_or(Ok(()), fs::create_dir_all)
+      .and_then(|_| OpenOptions::new().create(true).append(true).open(path))
+      .and_then(|mut file| {
+        let line = serde_json::to_string(&sample)?;
+        writeln!(file, "{}", line)
+      });
+
+    if let Err(e) = result {
+      log::error!("Unable to write statistics to {:?}: {:?}", path, e);
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Period {
+  Hour,
+  Day,
+  Week,
+  Month,
+}
+
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+pub enum ExportFormat {
+  Csv,
+  Json,
+}
+
+/// Samples to look at; without a machine the whole fleet is covered, without a period every
+/// sample is a row of its own
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct StatisticsRange {
+  pub machine: Option<String>,
+  pub from: Option<DateTime<Utc>>,
+  pub to: Option<DateTime<Utc>>,
+  pub period: Option<Period>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct StatisticsExport {
+  #[serde(flatten)]
+  pub range: StatisticsRange,
+  pub format: ExportFormat,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Counter {
+  pub name: String,
+  /// Value at the end of the period
+  pub total: u32,
+  /// Increase since the end of the previous period
+  pub delta: u32,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct PeriodRow {
+  pub machine: String,
+  pub machine_name: String,
+  pub period_start: DateTime<Local>,
+  pub samples: usize,
+  pub counters: Vec<Counter>,
+}
+
+/// Every counter of the statistics along with its name, soap pumps included
+pub(super) fn counters(stats: &Statistics) -> Vec<(String, u32)> {
+  let mut counters: Vec<(String, u32)> = vec![
+    ("cycles", stats.cycles),
+    ("interrupted_cycles", stats.interrupted_cycles),
+    ("loop_cycles", stats.loop_cycles),
+    ("on_time", stats.on_time),
+    ("work_time", stats.work_time),
+    ("rotation_time", stats.rotation_time),
+    ("heating_time", stats.heating_time),
+    ("cold_water_time", stats.cold_water_time),
+    ("warm_water_time", stats.warm_water_time),
+    ("recovery_water_time", stats.recovery_water_time),
+    ("flux_water_time", stats.flux_water_time),
+    ("porthole_closings", stats.porthole_closings),
+    ("porthole_openings", stats.porthole_openings),
+  ]
+  .into_iter()
+  .map(|(name, value)| (String::from(name), value))
+  .collect();
+
+  for (i, value) in stats.soap_times.iter().enumerate() {
+    counters.push((format!("soap_time_{}", i + 1), *value));
+  }
+  counters
+}
+
+fn period_start<Tz: TimeZone>(
+  timestamp: DateTime<Utc>,
+  period: Option<Period>,
+  zone: &Tz,
+) -> DateTime<Tz> {
+  let local = timestamp.with_timezone(zone);
+  let date = local.naive_local().date();
+  let start = match period {
+    None => return local,
+    Some(Period::Hour) => date.and_hms(local.hour(), 0, 0),
+    Some(Period::Day) => date.and_hms(0, 0, 0),
+    Some(Period::Week) => {
+      (date - Days::days(date.weekday().num_days_from_monday() as i64)).and_hms(0, 0, 0)
+    }
+    Some(Period::Month) => date.with_day(1).unwrap_or(date).and_hms(0, 0, 0),
+  };
+  // Daylight saving time repeats or skips some local times: take the first instant of a
+  // repeated one, and read a skipped one as UTC, i.e. the first instant after the gap
+  zone
+    .from_local_datetime(&start)
+    .earliest()
+    .unwrap_or_else(|| zone.from_utc_datetime(&start))
+}
+
+/// Growth of a counter between two samples; counters go back to zero when the machine memory is
+/// reset, everything counted since then is new
+fn growth(before: u32, after: u32) -> u32 {
+  if after < before {
+    after
+  } else {
+    after - before
+  }
+}
+
+/// Row of a period, from its samples in chronological order
+fn row(start: DateTime<Local>, samples: &[&Sample], baseline: Option<&Sample>) -> PeriodRow {
+  let last = samples[samples.len() - 1];
+  let series: Vec<Vec<(String, u32)>> = baseline
+    .into_iter()
+    .chain(samples.iter().copied())
+    .map(|sample| counters(&sample.stats))
+    .collect();
+
+  PeriodRow {
+    machine: last.machine.clone(),
+    machine_name: last.machine_name.clone(),
+    period_start: start,
+    samples: samples.len(),
+    counters: counters(&last.stats)
+      .into_iter()
+      .map(|(name, total)| {
+        // Summed sample by sample, so that a reset within the period loses nothing
+        let values: Vec<u32> = series
+          .iter()
+          .filter_map(|counters| counters.iter().find(|(n, _)| *n == name))
+          .map(|(_, value)| *value)
+          .collect();
+        let delta = values.windows(2).fold(0, |delta: u32, pair| {
+          delta.saturating_add(growth(pair[0], pair[1]))
+        });
+        Counter { name, total, delta }
+      })
+      .collect(),
+  }
+}
+
+fn read_samples() -> Result<Vec<Sample>, Error> {
+  let path = match super::prefs::data_file(STATISTICS_FILE) {
+    Some(path) if path.exists() => path,
+    _ => return Ok(vec![]),
+  };
+
+  let file = File::open(path).map_err(|e| Error::Server(e.to_string()))?;
+  Ok(
+    BufReader::new(file)
+      .lines()
+      .filter_map(|line| line.ok())
+      .filter_map(|line| serde_json::from_str::<Sample>(line.as_str()).ok())
+      .collect(),
+  )
+}
+
+/// Groups the samples by machine and period, computing how much every counter grew
+pub fn periods(query: &StatisticsRange) -> Result<Vec<PeriodRow>, Error> {
+  Ok(group(read_samples()?, query))
+}
+
+fn group(samples: Vec<Sample>, query: &StatisticsRange) -> Vec<PeriodRow> {
+  let mut by_machine: BTreeMap<String, Vec<Sample>> = BTreeMap::new();
+  for sample in samples {
+    let selected = query
+      .machine
+      .as_ref()
+      .map_or(true, |m| *m == sample.machine)
+      && query.to.map_or(true, |to| sample.timestamp <= to);
+    if selected {
+      by_machine
+        .entry(sample.machine.clone())
+        .or_default()
+        .push(sample);
+    }
+  }
+
+  let mut rows = vec![];
+  for (_, mut samples) in by_machine {
+    samples.sort_by_key(|sample| sample.timestamp);
+
+    // The last sample before the range is the reference for the first delta
+    let mut baseline: Option<&Sample> = None;
+    let mut periods: Vec<(DateTime<Local>, Vec<&Sample>)> = vec![];
+    for sample in samples.iter() {
+      if query.from.map_or(false, |from| sample.timestamp < from) {
+        baseline = Some(sample);
+        continue;
+      }
+
+      let start = period_start(sample.timestamp, query.period, &Local);
+      match periods.last_mut() {
+        Some((key, period)) if *key == start => period.push(sample),
+        _ => periods.push((start, vec![sample])),
+      }
+    }
+
+    for (start, period) in periods.iter() {
+      rows.push(row(*start, period, baseline));
+      baseline = period.last().copied();
+    }
+  }
+
+  rows
+}
+
+fn csv_field(value: &str) -> String {
+  if value.contains(|c| c == ',' || c == '"' || c == '\n') {
+    format!("\"{}\"", value.replace('"', "\"\""))
+  } else {
+    String::from(value)
+  }
+}
+
+fn to_csv(rows: &Vec<PeriodRow>) -> String {
+  // Machines might have a different number of soap pumps, use the widest set of counters
+  let names: Vec<String> = rows
+    .iter()
+    .max_by_key(|row| row.counters.len())
+    .map(|row| row.counters.iter().map(|c| c.name.clone()).collect())
+    .unwrap_or_default();
+
+  let mut header = vec![
+    String::from("machine"),
+    String::from("machine_name"),
+    String::from("period_start"),
+    String::from("samples"),
+  ];
+  for name in names.iter() {
+    header.push(name.clone());
+    header.push(format!("{}_delta", name));
+  }
+
+  let mut csv = header.join(",");
+  csv.push('\n');
+  for row in rows {
+    let mut fields = vec![
+      csv_field(row.machine.as_str()),
+      csv_field(row.machine_name.as_str()),
+      row.period_start.to_rfc3339(),
+      row.samples.to_string(),
+    ];
+    for name in names.iter() {
+      match row.counters.iter().find(|c| c.name == *name) {
+        Some(counter) => {
+          fields.push(counter.total.to_string());
+          fields.push(counter.delta.to_string());
+        }
+        None => fields.extend(vec![String::new(), String::new()]),
+      }
+    }
+    csv.push_str(fields.join(",").as_str());
+    csv.push('\n');
+  }
+  csv
+}
+
+/// Renders the requested statistics in the requested format
+pub fn export(query: &StatisticsExport) -> Result<String, Error> {
+  let rows = periods(&query.range)?;
+  match query.format {
+    ExportFormat::Csv => Ok(to_csv(&rows)),
+    ExportFormat::Json => {
+      serde_json::to_string_pretty(&rows).map_err(|e| Error::Json(e.to_string()))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::controller::testing::machine_data;
+  use chrono::{FixedOffset, LocalResult, NaiveDate, NaiveDateTime};
+
+  /// Data of a machine with two soap pumps
+  fn data(cycles: u32) -> MachineData {
+    machine_data(Statistics {
+      cycles,
+      soap_times: vec![0; 2],
+      ..Statistics::default()
+    })
+  }
+
+  /// Local time of a day of June 2022; the 13th is a Monday
+  fn at(day: u32, hour: u32) -> DateTime<Utc> {
+    Local
+      .ymd(2022, 6, day)
+      .and_hms(hour, 0, 0)
+      .with_timezone(&Utc)
+  }
+
+  fn sample(machine: &str, timestamp: DateTime<Utc>, cycles: u32) -> Sample {
+    Sample {
+      timestamp,
+      machine: String::from(machine),
+      machine_name: String::from("Lavanderia"),
+      stats: data(cycles).stats,
+    }
+  }
+
+  fn by(period: Option<Period>) -> StatisticsRange {
+    StatisticsRange {
+      period,
+      ..StatisticsRange::default()
+    }
+  }
+
+  /// Total and delta of the cycle counter
+  fn cycles(row: &PeriodRow) -> (u32, u32) {
+    let counter = row.counters.iter().find(|c| c.name == "cycles").unwrap();
+    (counter.total, counter.delta)
+  }
+
+  #[test]
+  fn samples_once_per_interval() {
+    let path = std::env::temp_dir().join(format!("statistics-{}.jsonl", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut log = StatisticsLog {
+      path: Some(path.clone()),
+      interval: Duration::from_secs(60),
+      last_sample: HashMap::new(),
+    };
+    let machine = String::from("10.0.0.1");
+
+    log.sample(&machine, &data(1));
+    log.sample(&machine, &data(2));
+    log.sample(&String::from("10.0.0.2"), &data(3));
+    log.set_interval(Duration::from_secs(0));
+    log.sample(&machine, &data(4));
+
+    let samples: Vec<Sample> = fs::read_to_string(&path)
+      .unwrap()
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+    let _ = fs::remove_file(&path);
+
+    let cycles: Vec<u32> = samples.iter().map(|s| s.stats.cycles).collect();
+    assert_eq!(cycles, vec![1, 3, 4]);
+    assert_eq!(samples[0].machine, "10.0.0.1");
+    assert_eq!(samples[0].machine_name, "Lavanderia");
+  }
+
+  #[test]
+  fn counters_include_every_soap_pump() {
+    let names: Vec<String> = counters(&data(0).stats)
+      .into_iter()
+      .map(|(name, _)| name)
+      .collect();
+    assert_eq!(names.len(), 15);
+    assert_eq!(names[13], "soap_time_1");
+    assert_eq!(names[14], "soap_time_2");
+  }
+
+  #[test]
+  fn empty_history() {
+    assert!(group(vec![], &by(Some(Period::Day))).is_empty());
+    assert!(to_csv(&vec![]).starts_with("machine,machine_name,period_start,samples\n"));
+  }
+
+  #[test]
+  fn period_with_a_single_sample() {
+    let rows = group(vec![sample("m", at(13, 10), 7)], &by(Some(Period::Day)));
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].samples, 1);
+    assert_eq!(
+      rows[0].period_start,
+      Local.ymd(2022, 6, 13).and_hms(0, 0, 0)
+    );
+    assert_eq!(cycles(&rows[0]), (7, 0));
+  }
+
+  #[test]
+  fn deltas_between_periods() {
+    let samples = vec![
+      sample("m", at(14, 20), 20),
+      sample("m", at(13, 10), 10),
+      sample("m", at(14, 9), 15),
+      sample("m", at(13, 18), 12),
+    ];
+    let rows = group(samples, &by(Some(Period::Day)));
+
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].samples, 2);
+    assert_eq!(cycles(&rows[0]), (12, 2));
+    assert_eq!(
+      rows[1].period_start,
+      Local.ymd(2022, 6, 14).and_hms(0, 0, 0)
+    );
+    assert_eq!(cycles(&rows[1]), (20, 8));
+  }
+
+  #[test]
+  fn samples_before_the_range_are_the_baseline() {
+    let samples = vec![
+      sample("m", at(13, 10), 10),
+      sample("m", at(14, 9), 15),
+      sample("m", at(15, 9), 18),
+    ];
+    let range = StatisticsRange {
+      from: Some(at(14, 0)),
+      to: Some(at(14, 23)),
+      period: Some(Period::Day),
+      ..StatisticsRange::default()
+    };
+    let rows = group(samples, &range);
+
+    assert_eq!(rows.len(), 1);
+    assert_eq!(cycles(&rows[0]), (15, 5));
+  }
+
+  #[test]
+  fn counters_that_reset() {
+    let samples = vec![
+      sample("m", at(13, 10), 100),
+      sample("m", at(14, 10), 3),
+      sample("m", at(15, 10), 5),
+    ];
+    let rows = group(samples, &by(Some(Period::Day)));
+
+    let deltas: Vec<(u32, u32)> = rows.iter().map(cycles).collect();
+    assert_eq!(deltas, vec![(100, 0), (3, 3), (5, 2)]);
+  }
+
+  #[test]
+  fn counters_that_reset_within_a_period() {
+    let samples = vec![
+      sample("m", at(13, 8), 90),
+      sample("m", at(14, 8), 100),
+      sample("m", at(14, 10), 0),
+      sample("m", at(14, 12), 120),
+      sample("m", at(14, 14), 150),
+    ];
+    let rows = group(samples, &by(Some(Period::Day)));
+
+    assert_eq!(rows[1].samples, 4);
+    assert_eq!(cycles(&rows[1]), (150, 160));
+  }
+
+  #[test]
+  fn every_sample_is_a_row_without_period() {
+    let samples = vec![
+      sample("m", at(13, 10), 1),
+      sample("m", at(13, 11), 2),
+      sample("m", at(13, 12), 4),
+    ];
+    let rows = group(samples, &by(None));
+
+    let deltas: Vec<(u32, u32)> = rows.iter().map(cycles).collect();
+    assert_eq!(deltas, vec![(1, 0), (2, 1), (4, 2)]);
+    assert_eq!(rows[1].period_start, at(13, 11).with_timezone(&Local));
+  }
+
+  #[test]
+  fn machines_are_kept_apart() {
+    let samples = vec![
+      sample("b", at(13, 10), 50),
+      sample("a", at(13, 10), 1),
+      sample("a", at(13, 12), 3),
+      sample("b", at(13, 12), 51),
+    ];
+    let rows = group(samples.clone(), &by(Some(Period::Month)));
+    let machines: Vec<&str> = rows.iter().map(|row| row.machine.as_str()).collect();
+    assert_eq!(machines, vec!["a", "b"]);
+    assert_eq!(cycles(&rows[0]), (3, 2));
+    assert_eq!(cycles(&rows[1]), (51, 1));
+
+    let range = StatisticsRange {
+      machine: Some(String::from("b")),
+      period: Some(Period::Month),
+      ..StatisticsRange::default()
+    };
+    assert_eq!(group(samples, &range).len(), 1);
+  }
+
+  #[test]
+  fn period_starts() {
+    let timestamp = at(15, 10);
+    let day = Local.ymd(2022, 6, 15);
+    let start = |period| period_start(timestamp, Some(period), &Local);
+    assert_eq!(start(Period::Hour), day.and_hms(10, 0, 0));
+    assert_eq!(start(Period::Day), day.and_hms(0, 0, 0));
+    assert_eq!(start(Period::Week), Local.ymd(2022, 6, 13).and_hms(0, 0, 0));
+    assert_eq!(start(Period::Month), Local.ymd(2022, 6, 1).and_hms(0, 0, 0));
+  }
+
+  /// UTC in winter and UTC+1 in summer, with both changes at local midnight: 2022-03-27 has no
+  /// 00:00-01:00, 2022-10-30 has it twice
+  #[derive(Clone, Copy, Debug)]
+  struct Dst;
+
+  impl Dst {
+    const OFFSETS: [i32; 2] = [3600, 0];
+
+    fn summer(utc: &NaiveDateTime) -> bool {
+      *utc >= NaiveDate::from_ymd(2022, 3, 27).and_hms(0, 0, 0)
+        && *utc < NaiveDate::from_ymd(2022, 10, 30).and_hms(0, 0, 0)
+    }
+  }
+
+  impl TimeZone for Dst {
+    type Offset = FixedOffset;
+
+    fn from_offset(_offset: &FixedOffset) -> Self {
+      Dst
+    }
+
+    fn offset_from_utc_datetime(self: &Self, utc: &NaiveDateTime) -> FixedOffset {
+      FixedOffset::east(if Dst::summer(utc) { 3600 } else { 0 })
+    }
+
+    fn offset_from_utc_date(self: &Self, utc: &NaiveDate) -> FixedOffset {
+      self.offset_from_utc_datetime(&utc.and_hms(0, 0, 0))
+    }
+
+    fn offset_from_local_datetime(self: &Self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+      // Earliest instant first, i.e. the largest offset
+      let offsets: Vec<FixedOffset> = Dst::OFFSETS
+        .iter()
+        .map(|secs| FixedOffset::east(*secs))
+        .filter(|offset| {
+          let utc = *local - Days::seconds(offset.local_minus_utc() as i64);
+          self.offset_from_utc_datetime(&utc) == *offset
+        })
+        .collect();
+      match offsets[..] {
+        [] => LocalResult::None,
+        [offset] => LocalResult::Single(offset),
+        [earliest, latest, ..] => LocalResult::Ambiguous(earliest, latest),
+      }
+    }
+
+    fn offset_from_local_date(self: &Self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+      self.offset_from_local_datetime(&local.and_hms(0, 0, 0))
+    }
+  }
+
+  fn utc(month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+    Utc.ymd(2022, month, day).and_hms(hour, minute, 0)
+  }
+
+  #[test]
+  fn period_starts_across_daylight_saving_time() {
+    let start = |timestamp, period| period_start(timestamp, Some(period), &Dst).with_timezone(&Utc);
+
+    // Local 00:30 is seen twice on 2022-10-30, both fall in the hour that started first
+    let first = utc(10, 29, 23, 30);
+    let second = utc(10, 30, 0, 30);
+    assert_eq!(start(first, Period::Hour), utc(10, 29, 23, 0));
+    assert_eq!(start(second, Period::Hour), utc(10, 29, 23, 0));
+    assert_eq!(start(second, Period::Day), utc(10, 29, 23, 0));
+
+    // Midnight does not exist on 2022-03-27, the day starts at 01:00
+    let morning = utc(3, 27, 10, 0);
+    assert_eq!(start(morning, Period::Day), utc(3, 27, 0, 0));
+    assert_eq!(start(morning, Period::Week), utc(3, 21, 0, 0));
+    assert_eq!(start(morning, Period::Month), utc(3, 1, 0, 0));
+    assert_eq!(start(morning, Period::Hour), utc(3, 27, 10, 0));
+  }
+
+  #[test]
+  fn csv_rows() {
+    let mut samples = vec![sample("m", at(13, 10), 1), sample("m", at(13, 12), 3)];
+    samples[1].machine_name = String::from("Via Roma, 1");
+    let csv = to_csv(&group(samples, &by(Some(Period::Day))));
+
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("machine,machine_name,period_start,samples,cycles,cycles_delta,"));
+    assert!(lines[1].starts_with("m,\"Via Roma, 1\","));
+    assert!(lines[1].contains(",2,3,2,"));
+  }
+}
@@ -0,0 +1,13 @@
+//! Fixtures shared by the unit tests of the controller
+use super::washing_machine::{Configuration, MachineData, State, Statistics};
+
+/// A machine at rest with the given counters
+pub fn machine_data(stats: Statistics) -> MachineData {
+  MachineData {
+    active: true,
+    name: String::from("Lavanderia"),
+    state: State::default(),
+    configuration: Configuration::default(),
+    stats,
+  }
+}
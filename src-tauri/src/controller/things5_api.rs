@@ -1,15 +1,15 @@
+use super::http;
 use super::washing_machine::{
   Configuration, ProgramPreview, State as WashingMachineState, Statistics,
 };
 use super::Error;
 use reqwest;
-use reqwest::blocking::{Client, ClientBuilder};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::collections::HashMap;
 use std::time::Duration;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Device {
   pub id: String,
   pub name: String,
@@ -43,7 +43,7 @@ struct WriteResponse {
   errors: Vec<String>,
 }
 
-pub fn refresh_data_ingestion(token: &str, device_id: &str) -> Result<(), Error> {
+pub async fn refresh_data_ingestion(token: &str, device_id: &str) -> Result<(), Error> {
   let value: serde_json::Value = serde_json::json!({
       "assets" : [
           {
@@ -68,10 +68,11 @@ pub fn refresh_data_ingestion(token: &str, device_id: &str) -> Result<(), Error>
     token,
     value,
   )
+  .await
   .map(|_| ())
 }
 
-pub fn get_current_machine(token: &str, device_id: &str) -> Result<Vec<u8>, Error> {
+pub async fn get_current_machine(token: &str, device_id: &str) -> Result<Vec<u8>, Error> {
   let value: serde_json::Value = serde_json::json!({
       "assets" : [
           {
@@ -95,7 +96,8 @@ pub fn get_current_machine(token: &str, device_id: &str) -> Result<Vec<u8>, Erro
     .as_str(),
     token,
     value,
-  )?;
+  )
+  .await?;
 
   let json_response: WriteResponse =
     serde_json::from_str(response.as_str()).map_err(|e| Error::Json(e.to_string()))?;
@@ -113,7 +115,11 @@ pub fn get_current_machine(token: &str, device_id: &str) -> Result<Vec<u8>, Erro
   Err(Error::Protocol)
 }
 
-pub fn put_current_machine(token: &str, device_id: &str, machine: Vec<u8>) -> Result<(), Error> {
+pub async fn put_current_machine(
+  token: &str,
+  device_id: &str,
+  machine: Vec<u8>,
+) -> Result<(), Error> {
   let encoded = base64::encode(machine);
 
   let value: serde_json::Value = serde_json::json!({
@@ -139,7 +145,8 @@ pub fn put_current_machine(token: &str, device_id: &str, machine: Vec<u8>) -> Re
     .as_str(),
     token,
     value,
-  )?;
+  )
+  .await?;
 
   let json_response: WriteResponse =
     serde_json::from_str(response.as_str()).map_err(|e| Error::Json(e.to_string()))?;
@@ -151,7 +158,10 @@ pub fn put_current_machine(token: &str, device_id: &str, machine: Vec<u8>) -> Re
   }
 }
 
-pub fn get_preview_configuration(token: &str, device_id: &str) -> Result<Configuration, Error> {
+pub async fn get_preview_configuration(
+  token: &str,
+  device_id: &str,
+) -> Result<Configuration, Error> {
   fn get_value<'a>(
     key: &str,
     value: &'a serde_json::Value,
@@ -174,7 +184,8 @@ pub fn get_preview_configuration(token: &str, device_id: &str) -> Result<Configu
     )
     .as_str(),
     token,
-  )?;
+  )
+  .await?;
 
   let value = get_named_item(
     &serde_json::from_value::<Vec<serde_json::Value>>(get_value("assets", &response)?.clone())
@@ -216,7 +227,7 @@ pub fn get_preview_configuration(token: &str, device_id: &str) -> Result<Configu
   Ok(config)
 }
 
-pub fn get_state_and_statistics(
+pub async fn get_state_and_statistics(
   token: &str,
   device_id: &str,
 ) -> Result<(WashingMachineState, Statistics), Error> {
@@ -236,7 +247,7 @@ pub fn get_state_and_statistics(
   )
   .unwrap();
 
-  let tmp = get_request(url.as_str(), token)?;
+  let tmp = get_request(url.as_str(), token).await?;
   log::info!(
     "{:?}",
     serde_json::from_value::<Vec<State>>(tmp.get("data").ok_or(Error::Protocol)?.clone())
@@ -248,7 +259,7 @@ pub fn get_state_and_statistics(
   let mut state = WashingMachineState::default();
   for s in states {
     if !s.end_time.is_none() {
-        continue;
+      continue;
     }
     log::info!("{:?}", s);
 
@@ -270,9 +281,9 @@ pub fn get_state_and_statistics(
       "credit" => state.credit = s.value.parse().unwrap_or(0),
       "name" => state.name = s.value.clone(),
       "porthole_open" => state.porthole_open = s.value.parse().unwrap_or(false),
-      "state" => state.state = s.value.parse().unwrap_or(0),
+      "state" => state.state = s.value.parse::<u16>().unwrap_or(0).into(),
       "cycle" => state.cycle = s.value.parse().unwrap_or(0),
-      "step_code" => state.step_code = s.value.parse().unwrap_or(0),
+      "step_code" => state.step_code = s.value.parse::<u16>().unwrap_or(0).into(),
       "step_number" => state.step_number = s.value.parse().unwrap_or(0),
       "cycle_remaining" => state.cycle_remaining = s.value.parse().unwrap_or(0),
       "step_count" => state.step_count = s.value.parse().unwrap_or(0),
@@ -287,11 +298,12 @@ pub fn get_state_and_statistics(
   Ok((state, statistics))
 }
 
-pub fn get_device_details(token: &str, device_id: &str) -> Result<(String, bool), Error> {
+pub async fn get_device_details(token: &str, device_id: &str) -> Result<(String, bool), Error> {
   let value: serde_json::Value = get_request(
     format!("https://api.things5.digital/v1/devices/{}", device_id).as_str(),
     token,
-  )?;
+  )
+  .await?;
   if let (Some(connected), Some(name)) = (
     value
       .get("data")
@@ -308,8 +320,9 @@ pub fn get_device_details(token: &str, device_id: &str) -> Result<(String, bool)
   }
 }
 
-pub fn get_devices(token: &str) -> Result<Vec<Device>, Error> {
-  let value: serde_json::Value = get_request("https://api.things5.digital/v1/devices", token)?;
+pub async fn get_devices(token: &str) -> Result<Vec<Device>, Error> {
+  let value: serde_json::Value =
+    get_request("https://api.things5.digital/v1/devices", token).await?;
   if let Some(data) = value.get("data") {
     let devices: Vec<Device> = serde_json::from_value(data.clone()).map_err(|_| Error::Protocol)?;
     Ok(devices)
@@ -318,12 +331,7 @@ pub fn get_devices(token: &str) -> Result<Vec<Device>, Error> {
   }
 }
 
-pub fn authorize(user: &str, password: &str) -> Result<String, Error> {
-  let agent: Client = ClientBuilder::new()
-    .timeout(std::time::Duration::from_secs(8))
-    .build()
-    .unwrap();
-
+pub async fn authorize(user: &str, password: &str) -> Result<String, Error> {
   let mut params = HashMap::new();
   params.insert("client_id", "api");
   params.insert("grant_type", "password");
@@ -331,100 +339,100 @@ pub fn authorize(user: &str, password: &str) -> Result<String, Error> {
   params.insert("username", user);
   params.insert("password", password);
 
-  agent
+  let response = http::client()
     .post("https://auth.things5.digital/auth/realms/hswsnc/protocol/openid-connect/token")
+    .timeout(Duration::from_secs(8))
     .form(&params)
     .send()
-    .map_err(|e| Error::Network(e.to_string()))
-    .and_then(|response| {
-      log::debug!("Things5 auth resp {:?}", response);
-      if response.status().is_success() {
-        let content = response.text().map_err(|_| Error::Protocol)?;
-        let value: serde_json::Value =
-          serde_json::from_str(content.as_str()).map_err(|_| Error::Protocol)?;
-        if let Some(token) = value.get("access_token").and_then(|t| t.as_str()) {
-          Ok(String::from(token))
-        } else {
-          Err(Error::Value)
-        }
-      } else {
-        Err(Error::Value)
-      }
-    })
+    .await
+    .map_err(|e| Error::Network(e.to_string()))?;
+
+  log::debug!("Things5 auth resp {:?}", response);
+  if response.status().is_success() {
+    let content = response.text().await.map_err(|_| Error::Protocol)?;
+    let value: serde_json::Value =
+      serde_json::from_str(content.as_str()).map_err(|_| Error::Protocol)?;
+    if let Some(token) = value.get("access_token").and_then(|t| t.as_str()) {
+      Ok(String::from(token))
+    } else {
+      Err(Error::Value)
+    }
+  } else {
+    Err(Error::Value)
+  }
 }
 
-pub fn start(token: &str, device_id: &str, program: u16) -> Result<(), Error> {
+pub async fn start(token: &str, device_id: &str, program: u16) -> Result<(), Error> {
   send_command(
     token,
     device_id,
     "start",
     String::from(format!("{}", program)).as_str(),
   )
+  .await
 }
 
-pub fn restart(token: &str, device_id: &str) -> Result<(), Error> {
-  send_command(token, device_id, "start", "")
+pub async fn restart(token: &str, device_id: &str) -> Result<(), Error> {
+  send_command(token, device_id, "start", "").await
 }
 
-pub fn pause(token: &str, device_id: &str) -> Result<(), Error> {
-  send_command(token, device_id, "pause", "")
+pub async fn pause(token: &str, device_id: &str) -> Result<(), Error> {
+  send_command(token, device_id, "pause", "").await
 }
 
-pub fn stop(token: &str, device_id: &str) -> Result<(), Error> {
-  send_command(token, device_id, "stop", "")
+pub async fn stop(token: &str, device_id: &str) -> Result<(), Error> {
+  send_command(token, device_id, "stop", "").await
 }
 
-pub fn clear_alarms(token: &str, device_id: &str) -> Result<(), Error> {
-  send_command(token, device_id, "clear_alarms", "")
+pub async fn clear_alarms(token: &str, device_id: &str) -> Result<(), Error> {
+  send_command(token, device_id, "clear_alarms", "").await
 }
 
-fn get_request(url: &str, token: &str) -> Result<serde_json::Value, Error> {
-  let agent: Client = ClientBuilder::new()
-    .timeout(std::time::Duration::from_secs(4))
-    .build()
-    .unwrap();
-
-  agent
+async fn get_request(url: &str, token: &str) -> Result<serde_json::Value, Error> {
+  let response = http::client()
     .get(url)
     .header("Accept", "application/json")
     .header("authorization", format!("Bearer {}", token))
     .send()
-    .map_err(|e| Error::Network(e.to_string()))
-    .and_then(|response| {
-      if response.status().is_success() {
-        let content = response.text().map_err(|_| Error::Protocol)?;
-        let value: serde_json::Value =
-          serde_json::from_str(content.as_str()).map_err(|_| Error::Protocol)?;
-
-        Ok(value)
-      } else {
-        log::debug!("Error {} {}", url, response.text().unwrap());
-        Err(Error::Value)
-      }
-    })
-}
+    .await
+    .map_err(|e| Error::Network(e.to_string()))?;
+
+  if response.status().is_success() {
+    let content = response.text().await.map_err(|_| Error::Protocol)?;
+    let value: serde_json::Value =
+      serde_json::from_str(content.as_str()).map_err(|_| Error::Protocol)?;
 
-fn post_request(url: &str, token: &str, value: serde_json::Value) -> Result<String, Error> {
-  let agent: Client = ClientBuilder::new()
-    .timeout(std::time::Duration::from_secs(4))
-    .build()
-    .unwrap();
+    Ok(value)
+  } else {
+    log::debug!(
+      "Error {} {}",
+      url,
+      response.text().await.unwrap_or_default()
+    );
+    Err(Error::Value)
+  }
+}
 
-  agent
+async fn post_request(url: &str, token: &str, value: serde_json::Value) -> Result<String, Error> {
+  let response = http::client()
     .post(url)
     .header("Accept", "application/json")
     .header("authorization", format!("Bearer {}", token))
     .json(&value)
     .send()
-    .map_err(|e| Error::Network(e.to_string()))
-    .and_then(|response| {
-      if response.status().is_success() {
-        Ok(response.text().unwrap())
-      } else {
-        log::warn!("Error response for {}: {}", url, response.text().unwrap());
-        Err(Error::Value)
-      }
-    })
+    .await
+    .map_err(|e| Error::Network(e.to_string()))?;
+
+  if response.status().is_success() {
+    response.text().await.map_err(|_| Error::Protocol)
+  } else {
+    log::warn!(
+      "Error response for {}: {}",
+      url,
+      response.text().await.unwrap_or_default()
+    );
+    Err(Error::Value)
+  }
 }
 
 fn get_named_item(items: &Vec<serde_json::Value>, name: &str) -> Option<serde_json::Value> {
@@ -438,7 +446,12 @@ fn get_named_item(items: &Vec<serde_json::Value>, name: &str) -> Option<serde_js
   None
 }
 
-fn send_command(token: &str, device_id: &str, command: &str, value: &str) -> Result<(), Error> {
+async fn send_command(
+  token: &str,
+  device_id: &str,
+  command: &str,
+  value: &str,
+) -> Result<(), Error> {
   log::info!("Sending command {}, value {}", command, value);
 
   let value: serde_json::Value = serde_json::json!({
@@ -465,5 +478,6 @@ fn send_command(token: &str, device_id: &str, command: &str, value: &str) -> Res
     token,
     value,
   )
+  .await
   .map(|_| ())
 }
@@ -0,0 +1,93 @@
+//! Catalog of the alarm codes reported in `State.alarm_code`.
+//!
+//! The built-in catalog is empty until the WS2020 firmware alarm table is available, so every
+//! code gets the generic description of `describe`. Alarms can be described on site by placing
+//! an `alarms.json` file with the same format in the data directory; its entries replace the
+//! built-in ones with the same code.
+use super::super::prefs;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fs;
+
+const BUILTIN_CATALOG: &str = include_str!("alarms.json");
+const CATALOG_FILE: &str = "alarms.json";
+
+#[derive(
+  Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+pub enum Severity {
+  Info,
+  Warning,
+  Critical,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Translated {
+  pub it: String,
+  pub en: String,
+}
+
+/// What an alarm code means and how to deal with it
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Alarm {
+  pub code: u16,
+  pub severity: Severity,
+  /// Whether the cycle can be resumed once the alarm is cleared
+  pub restartable: bool,
+  pub description: Translated,
+  pub remedy: Translated,
+}
+
+fn parse(content: &str) -> Result<Vec<Alarm>, serde_json::Error> {
+  serde_json::from_str(content)
+}
+
+static CATALOG: Lazy<HashMap<u16, Alarm>> = Lazy::new(|| {
+  let mut catalog: HashMap<u16, Alarm> = parse(BUILTIN_CATALOG)
+    .expect("Invalid built-in alarm catalog")
+    .into_iter()
+    .map(|alarm| (alarm.code, alarm))
+    .collect();
+
+  let overrides = prefs::data_file(CATALOG_FILE)
+    .and_then(|path| fs::read_to_string(path).ok())
+    .and_then(|content| match parse(content.as_str()) {
+      Ok(alarms) => Some(alarms),
+      Err(e) => {
+        log::error!("Invalid alarm catalog, using the built-in one: {:?}", e);
+        None
+      }
+    })
+    .unwrap_or_default();
+  for alarm in overrides {
+    catalog.insert(alarm.code, alarm);
+  }
+
+  catalog
+});
+
+/// Meaning of an alarm code; codes missing from the catalog get a generic description
+pub fn describe(code: u16) -> Alarm {
+  CATALOG.get(&code).cloned().unwrap_or_else(|| Alarm {
+    code,
+    severity: Severity::Warning,
+    restartable: false,
+    description: Translated {
+      it: format!("Allarme sconosciuto {}", code),
+      en: format!("Unknown alarm {}", code),
+    },
+    remedy: Translated {
+      it: String::from("Contattare l'assistenza"),
+      en: String::from("Call for service"),
+    },
+  })
+}
+
+/// Like `describe`, except for 0 which means that there is no alarm
+pub fn decode(code: u16) -> Option<Alarm> {
+  if code == 0 {
+    None
+  } else {
+    Some(describe(code))
+  }
+}
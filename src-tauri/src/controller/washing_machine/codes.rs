@@ -0,0 +1,139 @@
+//! Meaning of the numeric codes reported by the machines.
+//!
+//! Both enums are serialized as the raw code, so the wire format is the one of the machine;
+//! codes that are not known yet are kept as `Unknown`.
+use std::fmt;
+
+/// What the machine is doing, from `State.state`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(from = "u16", into = "u16")]
+pub enum StateCode {
+  Stopped,
+  Running,
+  Paused,
+  ForcedDrain,
+  Braking,
+  Unknown(u16),
+}
+
+impl StateCode {
+  /// Whether a cycle is in progress, even if paused or draining
+  pub fn is_running(self: &Self) -> bool {
+    *self != StateCode::Stopped
+  }
+}
+
+impl Default for StateCode {
+  fn default() -> Self {
+    StateCode::Stopped
+  }
+}
+
+impl From<u16> for StateCode {
+  fn from(code: u16) -> Self {
+    match code {
+      0 => StateCode::Stopped,
+      1 => StateCode::Running,
+      2 => StateCode::Paused,
+      3 => StateCode::ForcedDrain,
+      6 => StateCode::Braking,
+      code => StateCode::Unknown(code),
+    }
+  }
+}
+
+impl From<StateCode> for u16 {
+  fn from(state: StateCode) -> Self {
+    match state {
+      StateCode::Stopped => 0,
+      StateCode::Running => 1,
+      StateCode::Paused => 2,
+      StateCode::ForcedDrain => 3,
+      StateCode::Braking => 6,
+      StateCode::Unknown(code) => code,
+    }
+  }
+}
+
+impl fmt::Display for StateCode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      StateCode::Stopped => write!(f, "Stopped"),
+      StateCode::Running => write!(f, "Running"),
+      StateCode::Paused => write!(f, "Paused"),
+      StateCode::ForcedDrain => write!(f, "Forced drain"),
+      StateCode::Braking => write!(f, "Braking"),
+      StateCode::Unknown(code) => write!(f, "Unknown ({})", code),
+    }
+  }
+}
+
+/// Kind of the step being executed, from `State.step_code`; the same numbering is used by the
+/// steps of the programs in the configuration archive
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(from = "u16", into = "u16")]
+pub enum StepType {
+  Soaking,
+  Prewash,
+  Wash,
+  Rinse,
+  Drain,
+  Centrifuge,
+  Unrolling,
+  OperatorWait,
+  Unknown(u16),
+}
+
+impl Default for StepType {
+  fn default() -> Self {
+    StepType::Unknown(0)
+  }
+}
+
+impl From<u16> for StepType {
+  fn from(code: u16) -> Self {
+    match code {
+      1 => StepType::Soaking,
+      2 => StepType::Prewash,
+      3 => StepType::Wash,
+      4 => StepType::Rinse,
+      5 => StepType::Drain,
+      6 => StepType::Centrifuge,
+      7 => StepType::Unrolling,
+      8 => StepType::OperatorWait,
+      code => StepType::Unknown(code),
+    }
+  }
+}
+
+impl From<StepType> for u16 {
+  fn from(step: StepType) -> Self {
+    match step {
+      StepType::Soaking => 1,
+      StepType::Prewash => 2,
+      StepType::Wash => 3,
+      StepType::Rinse => 4,
+      StepType::Drain => 5,
+      StepType::Centrifuge => 6,
+      StepType::Unrolling => 7,
+      StepType::OperatorWait => 8,
+      StepType::Unknown(code) => code,
+    }
+  }
+}
+
+impl fmt::Display for StepType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      StepType::Soaking => write!(f, "Soaking"),
+      StepType::Prewash => write!(f, "Prewash"),
+      StepType::Wash => write!(f, "Wash"),
+      StepType::Rinse => write!(f, "Rinse"),
+      StepType::Drain => write!(f, "Drain"),
+      StepType::Centrifuge => write!(f, "Centrifuge"),
+      StepType::Unrolling => write!(f, "Unrolling"),
+      StepType::OperatorWait => write!(f, "Operator wait"),
+      StepType::Unknown(code) => write!(f, "Unknown ({})", code),
+    }
+  }
+}
@@ -0,0 +1,141 @@
+use super::{alarms, ConnectionState, MachineData};
+use std::time::{Duration, Instant};
+
+/// How a connection reacts to failed refreshes
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+  /// Delay before the first reconnection attempt
+  pub initial_delay: Duration,
+  /// Upper bound for the delay between attempts
+  pub max_delay: Duration,
+  /// Factor applied to the delay after every failed attempt
+  pub multiplier: u32,
+  /// Consecutive failures tolerated while still showing the last known data
+  pub degraded_failures: u32,
+  /// Time after which a machine that cannot be reached is considered offline
+  pub offline_after: Duration,
+}
+
+impl Default for ReconnectPolicy {
+  fn default() -> Self {
+    Self {
+      initial_delay: Duration::from_secs(1),
+      max_delay: Duration::from_secs(60),
+      multiplier: 2,
+      degraded_failures: 3,
+      offline_after: Duration::from_secs(300),
+    }
+  }
+}
+
+impl ReconnectPolicy {
+  /// Exponential backoff for the given reconnection attempt, starting from 1
+  pub fn delay(self: &Self, attempt: u32) -> Duration {
+    let factor = self
+      .multiplier
+      .checked_pow(attempt.saturating_sub(1))
+      .unwrap_or(u32::MAX);
+    self
+      .initial_delay
+      .checked_mul(factor)
+      .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+  }
+}
+
+/// Tracks the health of a connection across refreshes, deciding when the network should be
+/// tried again and which `ConnectionState` to report in the meantime.
+pub struct Lifecycle {
+  policy: ReconnectPolicy,
+  /// Last data successfully read from the machine
+  data: Option<MachineData>,
+  failures: u32,
+  unreachable_since: Option<Instant>,
+  next_attempt: Instant,
+}
+
+impl Lifecycle {
+  pub fn new(policy: ReconnectPolicy) -> Self {
+    Self {
+      policy,
+      data: None,
+      failures: 0,
+      unreachable_since: None,
+      next_attempt: Instant::now(),
+    }
+  }
+
+  pub fn data(self: &Self) -> Option<&MachineData> {
+    self.data.as_ref()
+  }
+
+  /// Whether the backend should hit the network on this refresh or keep waiting
+  pub fn should_attempt(self: &Self) -> bool {
+    Instant::now() >= self.next_attempt
+  }
+
+  pub fn success(self: &mut Self, mut data: MachineData) {
+    if self.failures > 0 {
+      log::info!("Connection restored after {} failures", self.failures);
+    }
+    data.state.alarm = alarms::decode(data.state.alarm_code);
+    self.data = Some(data);
+    self.failures = 0;
+    self.unreachable_since = None;
+    self.next_attempt = Instant::now();
+  }
+
+  pub fn failure(self: &mut Self) {
+    self.failures += 1;
+    let now = Instant::now();
+    self.unreachable_since.get_or_insert(now);
+
+    if self.degraded() {
+      self.next_attempt = now;
+    } else {
+      let attempt = self.reconnection_attempt();
+      let delay = self.policy.delay(attempt);
+      log::warn!(
+        "Connection lost, reconnection attempt {} in {:?}",
+        attempt,
+        delay
+      );
+      self.next_attempt = now + delay;
+    }
+  }
+
+  fn degraded(self: &Self) -> bool {
+    self.data.is_some() && self.failures <= self.policy.degraded_failures
+  }
+
+  fn reconnection_attempt(self: &Self) -> u32 {
+    if self.data.is_some() {
+      self.failures - self.policy.degraded_failures
+    } else {
+      self.failures
+    }
+  }
+
+  pub fn state(self: &Self) -> ConnectionState {
+    let now = Instant::now();
+    let unreachable_secs = self
+      .unreachable_since
+      .map_or(0, |since| (now - since).as_secs());
+
+    match (&self.data, self.failures) {
+      (None, 0) => ConnectionState::Connecting,
+      (Some(data), 0) => ConnectionState::Connected(data.clone()),
+      (Some(data), _) if self.degraded() => ConnectionState::Degraded {
+        data: data.clone(),
+        unreachable_secs,
+      },
+      _ if Duration::from_secs(unreachable_secs) >= self.policy.offline_after => {
+        ConnectionState::Offline { unreachable_secs }
+      }
+      _ => ConnectionState::Reconnecting {
+        attempt: self.reconnection_attempt(),
+        unreachable_secs,
+        retry_in_secs: self.next_attempt.saturating_duration_since(now).as_secs(),
+      },
+    }
+  }
+}
@@ -1,8 +1,13 @@
-use super::{ConnectionState, Statistics, WashingMachineConnection};
+use super::super::http;
+use super::{
+  Capabilities, ConnectionState, Lifecycle, MachineData, ReconnectPolicy, Statistics,
+  WashingMachineConnection,
+};
 use super::{Error, Result as WSResult};
+use async_trait::async_trait;
 use reqwest;
-use reqwest::blocking::{Client, ClientBuilder};
-use std::time::Duration;
+use reqwest::Client;
+use std::{sync::Mutex, time::Duration};
 use urlencoding::encode;
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -13,118 +18,135 @@ pub struct StatisticsPair {
 pub struct Connection {
   ip: String,
   agent: Client,
-  connection_state: ConnectionState,
+  lifecycle: Mutex<Lifecycle>,
 }
 
 impl Connection {
-  pub fn new(ip: String) -> Self {
-    let agent: Client = ClientBuilder::new()
-      .timeout(std::time::Duration::from_secs(4))
-      .build()
-      .unwrap();
-    let connection_state = Self::first_connection(&ip, &agent);
-
-    Self {
-      ip,
-      agent,
-      connection_state,
-    }
-  }
-
-  fn first_connection(ip: &String, agent: &Client) -> ConnectionState {
-    match json_get(ip, agent, "state")
-      .and_then(|state| {
-        json_get(ip, agent, "info")
-          .or_else(|_| json_get(ip, agent, "machine"))
-          .map(|configuration| (state, configuration))
-      })
-      .and_then(|(state, configuration)| {
-        json_get::<StatisticsPair>(ip, agent, "statistics").map(|stats| {
-          ConnectionState::Connected {
-            name: ip.clone(),
-            active: true,
-            state,
-            configuration,
-            stats: stats.total,
-          }
-        })
-      }) {
-      Ok(state) => {
-        log::info!("First connection successful");
-        state
-      }
-      Err(_) => {
-        log::warn!("First connection failed");
-        ConnectionState::Error
-      }
-    }
-  }
-
-  /*fn json_get<R: serde::de::DeserializeOwned>(self: &Self, target: &str) -> WSResult<R> {
-    json_get(&self.ip, &self.agent, target)
-  }*/
-
-  fn post(self: &Self, target: &str) -> reqwest::Result<()> {
-    self
-      .agent
-      .post(format!("http://{}/{}", self.ip, target).as_str())
-      .header("Connection", "close")
-      .send()
-      .map(|_| ())
+  pub async fn new(ip: String) -> Self {
+    Self::with_policy(ip, ReconnectPolicy::default()).await
   }
 
-  fn post_json<T: serde::Serialize>(self: &Self, target: &str, data: &T) -> reqwest::Result<()> {
-    self
-      .agent
-      .post(format!("http://{}/{}", self.ip, target).as_str())
-      .header("Connection", "close")
-      .json(data)
-      .send()
-      .map(|_| ())
+  pub async fn with_policy(ip: String, policy: ReconnectPolicy) -> Self {
+    let connection = Self {
+      ip,
+      agent: http::client(),
+      lifecycle: Mutex::new(Lifecycle::new(policy)),
+    };
+    connection.refresh_data().await;
+    connection
+  }
+
+  async fn first_connection(ip: &String, agent: &Client) -> WSResult<MachineData> {
+    let state = json_get(ip, agent, "state").await?;
+    let configuration = match json_get(ip, agent, "info").await {
+      Ok(configuration) => configuration,
+      Err(_) => json_get(ip, agent, "machine").await?,
+    };
+    let stats = json_get::<StatisticsPair>(ip, agent, "statistics").await?;
+
+    Ok(MachineData {
+      name: ip.clone(),
+      active: true,
+      state,
+      configuration,
+      stats: stats.total,
+    })
+  }
+
+  async fn post(self: &Self, target: &str) -> WSResult<()> {
+    command_result(
+      self
+        .agent
+        .post(format!("http://{}/{}", self.ip, target).as_str())
+        .header("Connection", "close")
+        .send()
+        .await,
+    )
+  }
+
+  async fn post_json<T: serde::Serialize + Sync>(
+    self: &Self,
+    target: &str,
+    data: &T,
+  ) -> WSResult<()> {
+    command_result(
+      self
+        .agent
+        .post(format!("http://{}/{}", self.ip, target).as_str())
+        .header("Connection", "close")
+        .json(data)
+        .send()
+        .await,
+    )
   }
 }
 
+#[async_trait]
 impl WashingMachineConnection for Connection {
   fn suggested_refresh_period(self: &Self) -> Duration {
     Duration::from_secs(1)
   }
 
-  fn refresh_data(self: &mut Self) {
-    self.connection_state = Self::first_connection(&self.ip, &self.agent);
+  fn capabilities(self: &Self) -> Capabilities {
+    Capabilities {
+      remote_control: true,
+      clear_alarms: true,
+      download_configuration: true,
+      upload_configuration: true,
+      select_configuration: true,
+      statistics: true,
+    }
   }
 
-  fn send_machine_configuration(self: &Self, data: Vec<u8>) -> WSResult<()> {
-    self
-      .agent
-      .post(format!("http://{}/machine", &self.ip).as_str())
-      .body(data)
-      .send()
-      .map_err(|e| Error::Network(e.to_string()))
-      .map(|_| ())
+  async fn refresh_data(self: &Self) {
+    let should_attempt = self.lifecycle.lock().unwrap().should_attempt();
+    if !should_attempt {
+      return;
+    }
+
+    let result = Self::first_connection(&self.ip, &self.agent).await;
+    let mut lifecycle = self.lifecycle.lock().unwrap();
+    match result {
+      Ok(data) => lifecycle.success(data),
+      Err(e) => {
+        log::warn!("Refresh of {} failed: {:?}", self.ip, e);
+        lifecycle.failure();
+      }
+    }
+  }
+
+  async fn send_machine_configuration(self: &Self, data: Vec<u8>) -> WSResult<()> {
+    command_result(
+      self
+        .agent
+        .post(format!("http://{}/machine", &self.ip).as_str())
+        .body(data)
+        .send()
+        .await,
+    )
   }
 
-  fn select_machine_configuration(self: &Self, archive: String) -> WSResult<()> {
+  async fn select_machine_configuration(self: &Self, archive: String) -> WSResult<()> {
     self
       .post(format!("select_machine/{}", encode(archive.as_str())).as_str())
-      .map_err(|e| Error::Network(e.to_string()))
+      .await
   }
 
-  fn get_machine_configuration(self: &Self) -> WSResult<Vec<u8>> {
-    let mut resp = self
+  async fn get_machine_configuration(self: &Self) -> WSResult<Vec<u8>> {
+    let resp = self
       .agent
       .get(format!("http://{}/machine", &self.ip).as_str())
       .send()
+      .await
       .map_err(|e| Error::Network(e.to_string()))?;
 
     if !resp.status().is_success() {
       log::warn!("Failed to download machine config");
       Err(Error::Protocol)
-    } else if let Some(header) = resp.headers().get("Content-Length") {
-      let len = header.len();
-      let mut bytes: Vec<u8> = Vec::with_capacity(len);
-      resp.copy_to(&mut bytes).map_err(|_| Error::Protocol)?;
+    } else if resp.headers().get("Content-Length").is_some() {
+      let bytes = resp.bytes().await.map_err(|_| Error::Protocol)?;
       log::info!("Downloaded machine config");
-      Ok(bytes)
+      Ok(bytes.to_vec())
     } else {
       log::warn!("Failed to download machine config");
       Err(Error::Protocol)
@@ -132,54 +154,61 @@ impl WashingMachineConnection for Connection {
   }
 
   fn get_connection_state(self: &Self) -> ConnectionState {
-    self.connection_state.clone()
+    self.lifecycle.lock().unwrap().state()
   }
 
-  fn restart(self: &Self) -> WSResult<()> {
-    self
-      .post("start")
-      .map_err(|e| Error::Network(e.to_string()))
+  async fn restart(self: &Self) -> WSResult<()> {
+    self.post("start").await
   }
 
-  fn pause(self: &Self) -> WSResult<()> {
-    self
-      .post("pause")
-      .map_err(|e| Error::Network(e.to_string()))
+  async fn pause(self: &Self) -> WSResult<()> {
+    self.post("pause").await
   }
 
-  fn stop(self: &Self) -> WSResult<()> {
-    self.post("stop").map_err(|e| Error::Network(e.to_string()))
+  async fn stop(self: &Self) -> WSResult<()> {
+    self.post("stop").await
   }
 
-  fn start_program(self: &Self, program: u16) -> WSResult<()> {
+  async fn start_program(self: &Self, program: u16) -> WSResult<()> {
     self
       .post_json("start", &serde_json::json!({ "cycle": program }))
-      .map_err(|e| Error::Network(e.to_string()))
+      .await
   }
 
-  fn clear_alarms(self: &Self) -> WSResult<()> {
-    self
-      .post("clear_alarms")
-      .map_err(|e| Error::Network(e.to_string()))
+  async fn clear_alarms(self: &Self) -> WSResult<()> {
+    self.post("clear_alarms").await
+  }
+}
+
+/// Outcome of a command: the board refuses the ones it cannot carry out with an error status
+fn command_result(response: reqwest::Result<reqwest::Response>) -> WSResult<()> {
+  let response = response.map_err(|e| Error::Network(e.to_string()))?;
+  let status = response.status();
+  if status.is_success() {
+    Ok(())
+  } else {
+    log::warn!("Command refused by {}: {}", response.url(), status);
+    Err(Error::Server(format!("The machine answered {}", status)))
   }
 }
 
-fn json_get<R: serde::de::DeserializeOwned>(
+async fn json_get<R: serde::de::DeserializeOwned>(
   ip: &String,
   agent: &Client,
   target: &str,
 ) -> WSResult<R> {
-  let json_response = match agent
+  let response = agent
     .get(format!("http://{}/{}", ip, target).as_str())
     .send()
-    .and_then(|resp| resp.json::<serde_json::Value>())
-  {
-    Ok(json_response) => Ok(json_response),
-    Err(e) => {
-      log::warn!("Json GET error: {:?}", e);
-      Err(Error::Network(e.to_string()))
-    }
-  }?;
+    .await;
+  let json_response = match response {
+    Ok(response) => response.json::<serde_json::Value>().await,
+    Err(e) => Err(e),
+  }
+  .map_err(|e| {
+    log::warn!("Json GET error: {:?}", e);
+    Error::Network(e.to_string())
+  })?;
 
   match serde_json::from_value::<R>(json_response.clone()) {
     Ok(value) => Ok(value),
@@ -1,7 +1,18 @@
 use serde;
+pub mod alarms;
+mod codes;
+mod lifecycle;
 pub mod local;
+pub mod modbus;
+pub mod recording;
+pub mod replay;
+pub mod simulated;
 pub mod things5;
 use super::Error;
+pub use alarms::Alarm;
+use async_trait::async_trait;
+pub use codes::{StateCode, StepType};
+pub use lifecycle::{Lifecycle, ReconnectPolicy};
 use std::time::Duration;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -23,12 +34,15 @@ pub struct Configuration {
 #[derive(Clone, serde::Serialize, serde::Deserialize, Default, Debug)]
 pub struct State {
   pub alarm_code: u16,
+  /// Meaning of `alarm_code`, filled in from the catalog rather than read from the machine
+  #[serde(default)]
+  pub alarm: Option<Alarm>,
   pub credit: u16,
   pub name: String,
   pub porthole_open: bool,
-  pub state: u16,
+  pub state: StateCode,
   pub cycle: u16,
-  pub step_code: u16,
+  pub step_code: StepType,
   pub step_number: u16,
   pub cycle_remaining: u16,
   pub step_remaining: u16,
@@ -56,28 +70,86 @@ pub struct Statistics {
   pub soap_times: Vec<u32>,
 }
 
-#[derive(Clone, serde::Serialize, Debug)]
+/// Everything read from a machine during a successful refresh
+#[derive(Clone, serde::Serialize, serde::Deserialize, Debug)]
+pub struct MachineData {
+  pub active: bool,
+  pub name: String,
+  pub state: State,
+  pub configuration: Configuration,
+  pub stats: Statistics,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize, Debug)]
 pub enum ConnectionState {
-  Connected {
-    active: bool,
-    name: String,
-    state: State,
-    configuration: Configuration,
-    stats: Statistics,
+  /// No data has been received yet
+  Connecting,
+  Connected(MachineData),
+  /// The last refreshes failed; the data is the last known one
+  Degraded {
+    #[serde(flatten)]
+    data: MachineData,
+    unreachable_secs: u64,
+  },
+  /// The machine cannot be reached, the next attempt is scheduled with backoff
+  Reconnecting {
+    attempt: u32,
+    unreachable_secs: u64,
+    retry_in_secs: u64,
   },
-  Error,
+  /// The machine has been unreachable for a long time; attempts continue at the slowest pace
+  Offline {
+    unreachable_secs: u64,
+  },
+}
+
+impl ConnectionState {
+  /// The machine data, possibly stale, if any is available
+  pub fn data(self: &Self) -> Option<&MachineData> {
+    match self {
+      ConnectionState::Connected(data) => Some(data),
+      ConnectionState::Degraded { data, .. } => Some(data),
+      _ => None,
+    }
+  }
+
+  pub fn is_connected(self: &Self) -> bool {
+    matches!(self, ConnectionState::Connected(_))
+  }
+}
+
+/// Operations a connection is able to carry out; the frontend only enables the matching actions.
+///
+/// Operations outside of this set fail with `Error::Unsupported`.
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize, Default, Debug, PartialEq)]
+pub struct Capabilities {
+  /// Starting, pausing, resuming and stopping programs
+  pub remote_control: bool,
+  pub clear_alarms: bool,
+  pub download_configuration: bool,
+  pub upload_configuration: bool,
+  /// Activating one of the configuration archives stored on the machine
+  pub select_configuration: bool,
+  pub statistics: bool,
 }
 
-pub trait WashingMachineConnection {
-  fn refresh_data(self: &mut Self);
-  fn send_machine_configuration(self: &Self, data: Vec<u8>) -> Result<()>;
-  fn get_machine_configuration(self: &Self) -> Result<Vec<u8>>;
-  fn select_machine_configuration(self: &Self, archive: String) -> Result<()>;
+/// A connection to a single washing machine.
+///
+/// Implementations are shared between the controller loop and the tasks that run refreshes and
+/// commands concurrently, so every method takes `&self` and the cached state is kept behind
+/// interior mutability.
+#[async_trait]
+pub trait WashingMachineConnection: Send + Sync {
+  async fn refresh_data(self: &Self);
+  async fn send_machine_configuration(self: &Self, data: Vec<u8>) -> Result<()>;
+  async fn get_machine_configuration(self: &Self) -> Result<Vec<u8>>;
+  async fn select_machine_configuration(self: &Self, archive: String) -> Result<()>;
   fn get_connection_state(self: &Self) -> ConnectionState;
-  fn start_program(self: &Self, program: u16) -> Result<()>;
-  fn restart(self: &Self) -> Result<()>;
-  fn pause(self: &Self) -> Result<()>;
-  fn stop(self: &Self) -> Result<()>;
-  fn clear_alarms(self: &Self) -> Result<()>;
+  async fn start_program(self: &Self, program: u16) -> Result<()>;
+  async fn restart(self: &Self) -> Result<()>;
+  async fn pause(self: &Self) -> Result<()>;
+  async fn stop(self: &Self) -> Result<()>;
+  async fn clear_alarms(self: &Self) -> Result<()>;
   fn suggested_refresh_period(self: &Self) -> Duration;
+  fn capabilities(self: &Self) -> Capabilities;
 }
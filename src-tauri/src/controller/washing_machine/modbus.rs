@@ -0,0 +1,643 @@
+//! Connection to controllers speaking Modbus TCP instead of the WS2020 HTTP API.
+//!
+//! Every controller lays out its registers differently, so the location of each value comes
+//! from a `RegisterMap`; values missing from the map are left at zero. Commands set a coil,
+//! which the controller is expected to reset once the command has been taken.
+use super::{
+  Capabilities, Configuration, ConnectionState, Lifecycle, MachineData, ProgramPreview,
+  ReconnectPolicy, State, Statistics, WashingMachineConnection,
+};
+use super::{Error, Result as WSResult};
+use async_trait::async_trait;
+use std::convert::TryFrom;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio_modbus::prelude::*;
+
+const REGISTER_MAP_FILE: &str = "modbus.json";
+/// Time allowed for every Modbus request
+const TIMEOUT: Duration = Duration::from_secs(4);
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum RegisterKind {
+  Holding,
+  Input,
+}
+
+impl Default for RegisterKind {
+  fn default() -> Self {
+    RegisterKind::Holding
+  }
+}
+
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Register {
+  pub address: u16,
+  #[serde(default)]
+  pub kind: RegisterKind,
+  /// Whether the value spans two registers, high word first
+  #[serde(default)]
+  pub double: bool,
+}
+
+/// Where the values of a controller are found
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RegisterMap {
+  /// Modbus unit id of the controller
+  pub unit: u8,
+
+  pub state: Option<Register>,
+  pub alarm_code: Option<Register>,
+  pub credit: Option<Register>,
+  pub porthole_open: Option<Register>,
+  pub cycle: Option<Register>,
+  pub step_code: Option<Register>,
+  pub step_number: Option<Register>,
+  pub cycle_remaining: Option<Register>,
+  pub step_remaining: Option<Register>,
+  pub step_count: Option<Register>,
+  pub temperature: Option<Register>,
+  pub level: Option<Register>,
+  pub speed: Option<Register>,
+
+  pub cycles: Option<Register>,
+  pub interrupted_cycles: Option<Register>,
+  pub loop_cycles: Option<Register>,
+  pub on_time: Option<Register>,
+  pub work_time: Option<Register>,
+  pub rotation_time: Option<Register>,
+  pub heating_time: Option<Register>,
+  pub cold_water_time: Option<Register>,
+  pub warm_water_time: Option<Register>,
+  pub recovery_water_time: Option<Register>,
+  pub flux_water_time: Option<Register>,
+  pub porthole_closings: Option<Register>,
+  pub porthole_openings: Option<Register>,
+  pub soap_times: Vec<Register>,
+
+  /// Holding register written with the program index before setting `start_coil`; programs
+  /// cannot be started without it
+  pub program_register: Option<u16>,
+  pub start_coil: Option<u16>,
+  /// Coil resuming a paused program; `start_coil` is used when missing
+  pub restart_coil: Option<u16>,
+  pub pause_coil: Option<u16>,
+  pub stop_coil: Option<u16>,
+  pub clear_alarms_coil: Option<u16>,
+
+  /// Programs of the controller, which cannot be read over Modbus
+  pub programs: Vec<ProgramPreview>,
+}
+
+impl RegisterMap {
+  /// The map saved in the data directory, or an empty one
+  pub fn load() -> Self {
+    super::super::prefs::data_file(REGISTER_MAP_FILE)
+      .and_then(|path| fs::read_to_string(path).ok())
+      .and_then(|content| match Self::parse(content.as_str()) {
+        Ok(map) => Some(map),
+        Err(e) => {
+          log::error!("Invalid Modbus register map: {:?}", e);
+          None
+        }
+      })
+      .unwrap_or_default()
+  }
+
+  pub fn parse(content: &str) -> WSResult<Self> {
+    let map: Self = serde_json::from_str(content).map_err(|e| Error::Json(e.to_string()))?;
+    map.validate()?;
+    Ok(map)
+  }
+
+  /// Rejects `double` registers for the values of `State`, which only have 16 bits
+  pub fn validate(self: &Self) -> WSResult<()> {
+    match self
+      .state_registers()
+      .into_iter()
+      .find(|(_, register)| register.map_or(false, |register| register.double))
+    {
+      Some((name, _)) => {
+        log::error!("Modbus register for {} cannot be double", name);
+        Err(Error::Value)
+      }
+      None => Ok(()),
+    }
+  }
+
+  fn state_registers(self: &Self) -> Vec<(&'static str, &Option<Register>)> {
+    vec![
+      ("state", &self.state),
+      ("alarm_code", &self.alarm_code),
+      ("credit", &self.credit),
+      ("porthole_open", &self.porthole_open),
+      ("cycle", &self.cycle),
+      ("step_code", &self.step_code),
+      ("step_number", &self.step_number),
+      ("cycle_remaining", &self.cycle_remaining),
+      ("step_remaining", &self.step_remaining),
+      ("step_count", &self.step_count),
+      ("temperature", &self.temperature),
+      ("level", &self.level),
+      ("speed", &self.speed),
+    ]
+  }
+
+  fn statistics(self: &Self) -> Vec<&Option<Register>> {
+    vec![
+      &self.cycles,
+      &self.interrupted_cycles,
+      &self.loop_cycles,
+      &self.on_time,
+      &self.work_time,
+      &self.rotation_time,
+      &self.heating_time,
+      &self.cold_water_time,
+      &self.warm_water_time,
+      &self.recovery_water_time,
+      &self.flux_water_time,
+      &self.porthole_closings,
+      &self.porthole_openings,
+    ]
+  }
+}
+
+async fn with_timeout<T>(request: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+  tokio::time::timeout(TIMEOUT, request)
+    .await
+    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Modbus timeout")))
+}
+
+/// Value of a register, or 0 if the map does not have it
+async fn read(context: &mut client::Context, register: &Option<Register>) -> io::Result<u32> {
+  let register = match register {
+    Some(register) => register,
+    None => return Ok(0),
+  };
+  let count = if register.double { 2 } else { 1 };
+  let words = match register.kind {
+    RegisterKind::Holding => {
+      with_timeout(context.read_holding_registers(register.address, count)).await?
+    }
+    RegisterKind::Input => {
+      with_timeout(context.read_input_registers(register.address, count)).await?
+    }
+  };
+  Ok(
+    words
+      .iter()
+      .fold(0, |value, word| (value << 16) | *word as u32),
+  )
+}
+
+/// Value of a 16 bit register, failing rather than truncating larger values
+async fn read_word(context: &mut client::Context, register: &Option<Register>) -> io::Result<u16> {
+  let value = read(context, register).await?;
+  u16::try_from(value).map_err(|_| {
+    io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("Value {} does not fit 16 bits", value),
+    )
+  })
+}
+
+async fn read_state(context: &mut client::Context, map: &RegisterMap) -> io::Result<State> {
+  Ok(State {
+    alarm_code: read_word(context, &map.alarm_code).await?,
+    alarm: None,
+    credit: read_word(context, &map.credit).await?,
+    name: String::new(),
+    porthole_open: read_word(context, &map.porthole_open).await? != 0,
+    state: read_word(context, &map.state).await?.into(),
+    cycle: read_word(context, &map.cycle).await?,
+    step_code: read_word(context, &map.step_code).await?.into(),
+    step_number: read_word(context, &map.step_number).await?,
+    cycle_remaining: read_word(context, &map.cycle_remaining).await?,
+    step_remaining: read_word(context, &map.step_remaining).await?,
+    step_count: read_word(context, &map.step_count).await?,
+    temperature: read_word(context, &map.temperature).await?,
+    level: read_word(context, &map.level).await?,
+    speed: read_word(context, &map.speed).await?,
+  })
+}
+
+async fn read_statistics(
+  context: &mut client::Context,
+  map: &RegisterMap,
+) -> io::Result<Statistics> {
+  let mut soap_times = vec![];
+  for register in &map.soap_times {
+    soap_times.push(read(context, &Some(*register)).await?);
+  }
+
+  Ok(Statistics {
+    cycles: read(context, &map.cycles).await?,
+    interrupted_cycles: read(context, &map.interrupted_cycles).await?,
+    loop_cycles: read(context, &map.loop_cycles).await?,
+    on_time: read(context, &map.on_time).await?,
+    work_time: read(context, &map.work_time).await?,
+    rotation_time: read(context, &map.rotation_time).await?,
+    heating_time: read(context, &map.heating_time).await?,
+    cold_water_time: read(context, &map.cold_water_time).await?,
+    warm_water_time: read(context, &map.warm_water_time).await?,
+    recovery_water_time: read(context, &map.recovery_water_time).await?,
+    flux_water_time: read(context, &map.flux_water_time).await?,
+    porthole_closings: read(context, &map.porthole_closings).await?,
+    porthole_openings: read(context, &map.porthole_openings).await?,
+    soap_times,
+  })
+}
+
+pub struct Connection {
+  address: SocketAddr,
+  map: RegisterMap,
+  /// Open Modbus session, dropped after a failure so that the next refresh reconnects
+  context: tokio::sync::Mutex<Option<client::Context>>,
+  lifecycle: Mutex<Lifecycle>,
+}
+
+impl Connection {
+  pub async fn new(address: SocketAddr, map: RegisterMap) -> Self {
+    Self::with_policy(address, map, ReconnectPolicy::default()).await
+  }
+
+  pub async fn with_policy(address: SocketAddr, map: RegisterMap, policy: ReconnectPolicy) -> Self {
+    let connection = Self {
+      address,
+      map,
+      context: tokio::sync::Mutex::new(None),
+      lifecycle: Mutex::new(Lifecycle::new(policy)),
+    };
+    connection.refresh_data().await;
+    connection
+  }
+
+  /// Runs requests on the Modbus session, opening it first if needed
+  async fn session<T>(
+    self: &Self,
+    requests: impl for<'a> FnOnce(
+      &'a mut client::Context,
+      &'a RegisterMap,
+    ) -> Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>,
+  ) -> WSResult<T> {
+    let mut guard = self.context.lock().await;
+    if guard.is_none() {
+      let context = with_timeout(tcp::connect_slave(self.address, Slave(self.map.unit)))
+        .await
+        .map_err(|e| Error::Network(e.to_string()))?;
+      *guard = Some(context);
+    }
+
+    let result = match guard.as_mut() {
+      Some(context) => requests(context, &self.map).await,
+      None => return Err(Error::NotConnected),
+    };
+    result.map_err(|e| {
+      log::warn!("Modbus request to {} failed: {:?}", self.address, e);
+      *guard = None;
+      Error::Network(e.to_string())
+    })
+  }
+
+  async fn set_coil(self: &Self, coil: Option<u16>) -> WSResult<()> {
+    let coil = coil.ok_or(Error::Unsupported)?;
+    self
+      .session(|context, _| Box::pin(with_timeout(context.write_single_coil(coil, true))))
+      .await
+  }
+
+  fn data(self: &Self, state: State, stats: Statistics) -> MachineData {
+    MachineData {
+      active: true,
+      name: self.address.to_string(),
+      state,
+      configuration: Configuration {
+        name: String::from("Modbus"),
+        app_version: String::new(),
+        machines: vec![],
+        programs: self.map.programs.clone(),
+      },
+      stats,
+    }
+  }
+}
+
+#[async_trait]
+impl WashingMachineConnection for Connection {
+  fn suggested_refresh_period(self: &Self) -> Duration {
+    Duration::from_secs(1)
+  }
+
+  fn capabilities(self: &Self) -> Capabilities {
+    let map = &self.map;
+    Capabilities {
+      remote_control: map.program_register.is_some()
+        && map.start_coil.is_some()
+        && map.pause_coil.is_some()
+        && map.stop_coil.is_some(),
+      clear_alarms: map.clear_alarms_coil.is_some(),
+      download_configuration: false,
+      upload_configuration: false,
+      select_configuration: false,
+      statistics: map.statistics().iter().any(|register| register.is_some())
+        || !map.soap_times.is_empty(),
+    }
+  }
+
+  async fn refresh_data(self: &Self) {
+    let should_attempt = self.lifecycle.lock().unwrap().should_attempt();
+    if !should_attempt {
+      return;
+    }
+
+    let result = self
+      .session(|context, map| {
+        Box::pin(async move {
+          let state = read_state(context, map).await?;
+          let stats = read_statistics(context, map).await?;
+          Ok((state, stats))
+        })
+      })
+      .await;
+
+    let mut lifecycle = self.lifecycle.lock().unwrap();
+    match result {
+      Ok((state, stats)) => lifecycle.success(self.data(state, stats)),
+      Err(e) => {
+        log::warn!("Refresh of {} failed: {:?}", self.address, e);
+        lifecycle.failure();
+      }
+    }
+  }
+
+  async fn send_machine_configuration(self: &Self, _data: Vec<u8>) -> WSResult<()> {
+    Err(Error::Unsupported)
+  }
+
+  async fn select_machine_configuration(self: &Self, _archive: String) -> WSResult<()> {
+    Err(Error::Unsupported)
+  }
+
+  async fn get_machine_configuration(self: &Self) -> WSResult<Vec<u8>> {
+    Err(Error::Unsupported)
+  }
+
+  fn get_connection_state(self: &Self) -> ConnectionState {
+    self.lifecycle.lock().unwrap().state()
+  }
+
+  async fn restart(self: &Self) -> WSResult<()> {
+    self
+      .set_coil(self.map.restart_coil.or(self.map.start_coil))
+      .await
+  }
+
+  async fn pause(self: &Self) -> WSResult<()> {
+    self.set_coil(self.map.pause_coil).await
+  }
+
+  async fn stop(self: &Self) -> WSResult<()> {
+    self.set_coil(self.map.stop_coil).await
+  }
+
+  async fn start_program(self: &Self, program: u16) -> WSResult<()> {
+    let register = self.map.program_register.ok_or(Error::Unsupported)?;
+    if self.map.start_coil.is_none() {
+      return Err(Error::Unsupported);
+    }
+    self
+      .session(|context, _| {
+        Box::pin(with_timeout(
+          context.write_single_register(register, program),
+        ))
+      })
+      .await?;
+    self.set_coil(self.map.start_coil).await
+  }
+
+  async fn clear_alarms(self: &Self) -> WSResult<()> {
+    self.set_coil(self.map.clear_alarms_coil).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::controller::washing_machine::codes::StateCode;
+  use futures::future;
+  use std::collections::HashMap;
+  use std::sync::Arc;
+  use tokio_modbus::server::{self, Service};
+
+  /// Registers and coils of the in-process controller
+  #[derive(Default)]
+  struct Registers {
+    holding: HashMap<u16, u16>,
+    input: HashMap<u16, u16>,
+    coils: Vec<u16>,
+  }
+
+  #[derive(Clone)]
+  struct Controller {
+    registers: Arc<Mutex<Registers>>,
+  }
+
+  fn words(registers: &HashMap<u16, u16>, address: u16, count: u16) -> Vec<u16> {
+    (address..address + count)
+      .map(|address| registers.get(&address).copied().unwrap_or(0))
+      .collect()
+  }
+
+  impl Service for Controller {
+    type Request = Request;
+    type Response = Response;
+    type Error = io::Error;
+    type Future = future::Ready<Result<Response, io::Error>>;
+
+    fn call(&self, request: Request) -> Self::Future {
+      let mut registers = self.registers.lock().unwrap();
+      future::ready(match request {
+        Request::ReadHoldingRegisters(address, count) => Ok(Response::ReadHoldingRegisters(words(
+          &registers.holding,
+          address,
+          count,
+        ))),
+        Request::ReadInputRegisters(address, count) => Ok(Response::ReadInputRegisters(words(
+          &registers.input,
+          address,
+          count,
+        ))),
+        Request::WriteSingleRegister(address, value) => {
+          registers.holding.insert(address, value);
+          Ok(Response::WriteSingleRegister(address, value))
+        }
+        Request::WriteSingleCoil(address, value) => {
+          if value {
+            registers.coils.push(address);
+          }
+          Ok(Response::WriteSingleCoil(address, value))
+        }
+        _ => Err(io::Error::new(
+          io::ErrorKind::Unsupported,
+          "Unexpected request",
+        )),
+      })
+    }
+  }
+
+  /// Serves the registers on a free local port
+  async fn serve(registers: Registers) -> (SocketAddr, Arc<Mutex<Registers>>) {
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+      .and_then(|listener| listener.local_addr())
+      .unwrap();
+    let registers = Arc::new(Mutex::new(registers));
+    let controller = Controller {
+      registers: registers.clone(),
+    };
+    tokio::spawn(server::tcp::Server::new(address).serve(move || Ok(controller.clone())));
+
+    for _ in 0..50 {
+      if tokio::net::TcpStream::connect(address).await.is_ok() {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    (address, registers)
+  }
+
+  fn holding(address: u16) -> Option<Register> {
+    Some(Register {
+      address,
+      kind: RegisterKind::Holding,
+      double: false,
+    })
+  }
+
+  fn input(address: u16, double: bool) -> Option<Register> {
+    Some(Register {
+      address,
+      kind: RegisterKind::Input,
+      double,
+    })
+  }
+
+  fn command_map() -> RegisterMap {
+    RegisterMap {
+      program_register: Some(300),
+      start_coil: Some(0),
+      pause_coil: Some(1),
+      stop_coil: Some(2),
+      clear_alarms_coil: Some(3),
+      ..RegisterMap::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn reads_state_and_statistics() {
+    let mut registers = Registers::default();
+    registers.holding.insert(0, 1);
+    registers.holding.insert(1, 5);
+    registers.input.insert(10, 60);
+    registers.holding.insert(100, 1);
+    registers.holding.insert(101, 2);
+    registers.input.insert(110, 0);
+    registers.input.insert(111, 42);
+    registers.input.insert(200, 7);
+    let (address, _) = serve(registers).await;
+
+    let map = RegisterMap {
+      state: holding(0),
+      cycle: holding(1),
+      temperature: input(10, false),
+      cycles: Some(Register {
+        address: 100,
+        kind: RegisterKind::Holding,
+        double: true,
+      }),
+      on_time: input(110, true),
+      soap_times: vec![input(200, false).unwrap()],
+      ..RegisterMap::default()
+    };
+    let connection = Connection::new(address, map).await;
+
+    let data = connection.get_connection_state().data().cloned().unwrap();
+    assert_eq!(data.state.state, StateCode::Running);
+    assert_eq!(data.state.cycle, 5);
+    assert_eq!(data.state.temperature, 60);
+    assert_eq!(data.state.speed, 0);
+    assert_eq!(data.stats.cycles, 65538);
+    assert_eq!(data.stats.on_time, 42);
+    assert_eq!(data.stats.work_time, 0);
+    assert_eq!(data.stats.soap_times, vec![7]);
+    assert!(connection.capabilities().statistics);
+    assert!(!connection.capabilities().remote_control);
+  }
+
+  #[tokio::test]
+  async fn commands_set_coils() {
+    let (address, registers) = serve(Registers::default()).await;
+    let connection = Connection::new(address, command_map()).await;
+    assert!(connection.capabilities().remote_control);
+    assert!(connection.capabilities().clear_alarms);
+
+    connection.start_program(4).await.unwrap();
+    connection.pause().await.unwrap();
+    connection.restart().await.unwrap();
+    connection.stop().await.unwrap();
+    connection.clear_alarms().await.unwrap();
+
+    let registers = registers.lock().unwrap();
+    assert_eq!(registers.holding.get(&300), Some(&4));
+    assert_eq!(registers.coils, vec![0, 1, 0, 2, 3]);
+  }
+
+  #[tokio::test]
+  async fn programs_need_the_program_register() {
+    let (address, registers) = serve(Registers::default()).await;
+    let map = RegisterMap {
+      program_register: None,
+      ..command_map()
+    };
+    let connection = Connection::new(address, map).await;
+
+    assert!(!connection.capabilities().remote_control);
+    assert!(matches!(
+      connection.start_program(4).await,
+      Err(Error::Unsupported)
+    ));
+    assert!(registers.lock().unwrap().coils.is_empty());
+  }
+
+  #[tokio::test]
+  async fn oversized_state_values_fail_the_refresh() {
+    let mut registers = Registers::default();
+    registers.input.insert(10, 1);
+    let (address, _) = serve(registers).await;
+    let map = RegisterMap {
+      temperature: input(10, true),
+      ..RegisterMap::default()
+    };
+    assert!(matches!(map.validate(), Err(Error::Value)));
+
+    let connection = Connection::new(address, map).await;
+    assert!(matches!(
+      connection.get_connection_state(),
+      ConnectionState::Reconnecting { .. }
+    ));
+  }
+
+  #[test]
+  fn rejects_double_state_registers() {
+    let map = r#"{ "state": { "address": 0, "double": true } }"#;
+    assert!(matches!(RegisterMap::parse(map), Err(Error::Value)));
+
+    let map = r#"{ "state": { "address": 0 }, "cycles": { "address": 100, "double": true } }"#;
+    assert!(RegisterMap::parse(map).is_ok());
+  }
+}
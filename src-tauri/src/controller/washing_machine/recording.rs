@@ -0,0 +1,199 @@
+//! Session recordings: a wrapper around any connection that logs what it sees to a file, one
+//! JSON entry per line, so that the session can be played back by `replay::Connection`.
+use super::{Capabilities, ConnectionState, WashingMachineConnection};
+use super::{Error, Result as WSResult};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Command {
+  StartProgram(u16),
+  Restart,
+  Pause,
+  Stop,
+  ClearAlarms,
+  /// Size of the uploaded archive; the archive itself is not recorded
+  SendConfiguration(usize),
+  GetConfiguration,
+  SelectConfiguration(String),
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind")]
+pub enum Record {
+  Started {
+    capabilities: Capabilities,
+    refresh_period_ms: u64,
+  },
+  /// State of the connection right after a refresh
+  Poll { state: ConnectionState },
+  Command {
+    command: Command,
+    error: Option<Error>,
+  },
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+  pub timestamp: DateTime<Utc>,
+  /// Time since the start of the recording
+  pub elapsed_ms: u64,
+  #[serde(flatten)]
+  pub record: Record,
+}
+
+/// Reads back a recording, skipping the lines that cannot be parsed
+pub fn load(path: &Path) -> WSResult<Vec<Entry>> {
+  let content = fs::read_to_string(path).map_err(|e| Error::Server(e.to_string()))?;
+  Ok(
+    content
+      .lines()
+      .filter_map(|line| match serde_json::from_str(line) {
+        Ok(entry) => Some(entry),
+        Err(e) => {
+          log::warn!("Skipping invalid recording entry: {:?}", e);
+          None
+        }
+      })
+      .collect(),
+  )
+}
+
+/// Forwards everything to the wrapped connection, logging polls and commands along the way
+pub struct Recorder {
+  inner: Arc<dyn WashingMachineConnection>,
+  path: PathBuf,
+  file: Mutex<File>,
+  started: Instant,
+}
+
+impl Recorder {
+  pub fn new(inner: Arc<dyn WashingMachineConnection>, path: PathBuf) -> io::Result<Self> {
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .truncate(true)
+      .open(&path)?;
+
+    let recorder = Self {
+      inner,
+      path,
+      file: Mutex::new(file),
+      started: Instant::now(),
+    };
+    recorder.append(Record::Started {
+      capabilities: recorder.inner.capabilities(),
+      refresh_period_ms: recorder.inner.suggested_refresh_period().as_millis() as u64,
+    });
+    recorder.append(Record::Poll {
+      state: recorder.inner.get_connection_state(),
+    });
+    Ok(recorder)
+  }
+
+  /// The wrapped connection, to go on without recording
+  pub fn inner(self: &Self) -> Arc<dyn WashingMachineConnection> {
+    self.inner.clone()
+  }
+
+  pub fn path(self: &Self) -> &Path {
+    self.path.as_path()
+  }
+
+  fn append(self: &Self, record: Record) {
+    let entry = Entry {
+      timestamp: Utc::now(),
+      elapsed_ms: self.started.elapsed().as_millis() as u64,
+      record,
+    };
+    let result = serde_json::to_string(&entry)
+      .map_err(io::Error::from)
+      .and_then(|line| writeln!(self.file.lock().unwrap(), "{}", line));
+
+    if let Err(e) = result {
+      log::error!("Unable to write recording to {:?}: {:?}", self.path, e);
+    }
+  }
+
+  fn command<T>(self: &Self, command: Command, result: WSResult<T>) -> WSResult<T> {
+    self.append(Record::Command {
+      command,
+      error: result.as_ref().err().cloned(),
+    });
+    result
+  }
+}
+
+#[async_trait]
+impl WashingMachineConnection for Recorder {
+  fn suggested_refresh_period(self: &Self) -> Duration {
+    self.inner.suggested_refresh_period()
+  }
+
+  fn capabilities(self: &Self) -> Capabilities {
+    self.inner.capabilities()
+  }
+
+  async fn refresh_data(self: &Self) {
+    self.inner.refresh_data().await;
+    self.append(Record::Poll {
+      state: self.inner.get_connection_state(),
+    });
+  }
+
+  async fn send_machine_configuration(self: &Self, data: Vec<u8>) -> WSResult<()> {
+    let size = data.len();
+    let result = self.inner.send_machine_configuration(data).await;
+    self.command(Command::SendConfiguration(size), result)
+  }
+
+  async fn select_machine_configuration(self: &Self, archive: String) -> WSResult<()> {
+    let result = self
+      .inner
+      .select_machine_configuration(archive.clone())
+      .await;
+    self.command(Command::SelectConfiguration(archive), result)
+  }
+
+  async fn get_machine_configuration(self: &Self) -> WSResult<Vec<u8>> {
+    let result = self.inner.get_machine_configuration().await;
+    self.command(Command::GetConfiguration, result)
+  }
+
+  fn get_connection_state(self: &Self) -> ConnectionState {
+    self.inner.get_connection_state()
+  }
+
+  async fn restart(self: &Self) -> WSResult<()> {
+    let result = self.inner.restart().await;
+    self.command(Command::Restart, result)
+  }
+
+  async fn pause(self: &Self) -> WSResult<()> {
+    let result = self.inner.pause().await;
+    self.command(Command::Pause, result)
+  }
+
+  async fn stop(self: &Self) -> WSResult<()> {
+    let result = self.inner.stop().await;
+    self.command(Command::Stop, result)
+  }
+
+  async fn start_program(self: &Self, program: u16) -> WSResult<()> {
+    let result = self.inner.start_program(program).await;
+    self.command(Command::StartProgram(program), result)
+  }
+
+  async fn clear_alarms(self: &Self) -> WSResult<()> {
+    let result = self.inner.clear_alarms().await;
+    self.command(Command::ClearAlarms, result)
+  }
+}
@@ -0,0 +1,210 @@
+//! Plays back a session saved by `recording::Recorder`, following the original timing.
+//!
+//! The machine cannot be controlled during playback: every operation is unsupported, while the
+//! commands sent during the recording are logged when playback reaches them. Statistics are
+//! available if they were in the recorded session.
+use super::recording::{self, Entry, Record};
+use super::simulated::clamp_speedup;
+use super::{Capabilities, ConnectionState, WashingMachineConnection};
+use super::{Error, Result as WSResult};
+use async_trait::async_trait;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Playback {
+  /// Index of the next entry to play
+  next: usize,
+  state: ConnectionState,
+}
+
+pub struct Connection {
+  entries: Vec<Entry>,
+  statistics: bool,
+  refresh_period: Duration,
+  started: Instant,
+  speedup: u32,
+  playback: Mutex<Playback>,
+}
+
+impl Connection {
+  pub fn load(path: &Path) -> WSResult<Self> {
+    Self::with_speedup(path, 1)
+  }
+
+  /// Plays the recording `speedup` times faster than it was recorded, within the same bounds as
+  /// the simulated machines
+  pub fn with_speedup(path: &Path, speedup: u32) -> WSResult<Self> {
+    let speedup = clamp_speedup(speedup);
+    let entries = recording::load(path)?;
+    let (statistics, refresh_period_ms) = entries
+      .iter()
+      .find_map(|entry| match entry.record {
+        Record::Started {
+          capabilities,
+          refresh_period_ms,
+        } => Some((capabilities.statistics, refresh_period_ms)),
+        _ => None,
+      })
+      .ok_or(Error::Protocol)?;
+
+    let connection = Self {
+      entries,
+      statistics,
+      refresh_period: Duration::from_millis(refresh_period_ms) / speedup,
+      started: Instant::now(),
+      speedup,
+      playback: Mutex::new(Playback {
+        next: 0,
+        state: ConnectionState::Connecting,
+      }),
+    };
+    connection.play();
+    Ok(connection)
+  }
+
+  /// Whether every entry has been played
+  pub fn finished(self: &Self) -> bool {
+    self.playback.lock().unwrap().next >= self.entries.len()
+  }
+
+  /// Plays the entries up to the current time of the recording
+  fn play(self: &Self) {
+    let elapsed_ms = (self.started.elapsed() * self.speedup).as_millis() as u64;
+    let mut playback = self.playback.lock().unwrap();
+
+    while let Some(entry) = self.entries.get(playback.next) {
+      if entry.elapsed_ms > elapsed_ms {
+        break;
+      }
+      match entry.record {
+        Record::Poll { ref state } => playback.state = state.clone(),
+        Record::Command {
+          ref command,
+          ref error,
+        } => log::info!(
+          "Replaying command {:?} recorded at {}: {:?}",
+          command,
+          entry.timestamp,
+          error
+        ),
+        Record::Started { .. } => (),
+      }
+      playback.next += 1;
+    }
+  }
+}
+
+#[async_trait]
+impl WashingMachineConnection for Connection {
+  fn suggested_refresh_period(self: &Self) -> Duration {
+    self.refresh_period
+  }
+
+  fn capabilities(self: &Self) -> Capabilities {
+    Capabilities {
+      statistics: self.statistics,
+      ..Capabilities::default()
+    }
+  }
+
+  async fn refresh_data(self: &Self) {
+    self.play();
+  }
+
+  async fn send_machine_configuration(self: &Self, _data: Vec<u8>) -> WSResult<()> {
+    Err(Error::Unsupported)
+  }
+
+  async fn select_machine_configuration(self: &Self, _archive: String) -> WSResult<()> {
+    Err(Error::Unsupported)
+  }
+
+  async fn get_machine_configuration(self: &Self) -> WSResult<Vec<u8>> {
+    Err(Error::Unsupported)
+  }
+
+  fn get_connection_state(self: &Self) -> ConnectionState {
+    self.playback.lock().unwrap().state.clone()
+  }
+
+  async fn restart(self: &Self) -> WSResult<()> {
+    Err(Error::Unsupported)
+  }
+
+  async fn pause(self: &Self) -> WSResult<()> {
+    Err(Error::Unsupported)
+  }
+
+  async fn stop(self: &Self) -> WSResult<()> {
+    Err(Error::Unsupported)
+  }
+
+  async fn start_program(self: &Self, _program: u16) -> WSResult<()> {
+    Err(Error::Unsupported)
+  }
+
+  async fn clear_alarms(self: &Self) -> WSResult<()> {
+    Err(Error::Unsupported)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::controller::washing_machine::recording::Recorder;
+  use crate::controller::washing_machine::simulated::{self, MAX_SPEEDUP};
+  use crate::controller::washing_machine::StateCode;
+  use std::sync::Arc;
+
+  #[tokio::test]
+  async fn plays_back_a_recorded_session() {
+    let path = std::env::temp_dir().join(format!("replay-{}.jsonl", std::process::id()));
+    let machine = Arc::new(simulated::Connection::new(
+      String::from("Lavanderia"),
+      simulated::Connection::demo_configuration(),
+    ));
+
+    let recorder = Recorder::new(machine, path.clone()).unwrap();
+    recorder.start_program(1).await.unwrap();
+    recorder.refresh_data().await;
+    recorder.pause().await.unwrap();
+    recorder.refresh_data().await;
+    assert!(recorder
+      .send_machine_configuration(vec![1, 2, 3])
+      .await
+      .is_err());
+    drop(recorder);
+
+    let entries = recording::load(&path).unwrap();
+    assert!(matches!(entries[0].record, Record::Started { .. }));
+    assert_eq!(
+      entries
+        .iter()
+        .filter(|entry| matches!(entry.record, Record::Command { .. }))
+        .count(),
+      3
+    );
+
+    // The session took a few milliseconds, played back a thousand times faster it is over by now
+    let replay = Connection::with_speedup(&path, 1000).unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    replay.refresh_data().await;
+    assert!(replay.finished());
+
+    let state = replay.get_connection_state();
+    let data = state.data().unwrap();
+    assert_eq!(data.name, "Lavanderia");
+    assert_eq!(data.state.state, StateCode::Paused);
+    assert_eq!(data.state.cycle, 1);
+    assert!(matches!(
+      replay.start_program(1).await,
+      Err(Error::Unsupported)
+    ));
+
+    let replay = Connection::with_speedup(&path, u32::MAX).unwrap();
+    assert_eq!(replay.speedup, MAX_SPEEDUP);
+
+    std::fs::remove_file(&path).ok();
+  }
+}
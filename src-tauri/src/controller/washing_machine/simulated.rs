@@ -0,0 +1,769 @@
+//! A washing machine that only exists in memory, for demos and for exercising the controller
+//! without hardware.
+//!
+//! Cycles follow the programs of the given `Configuration`: every program gets a plausible
+//! sequence of steps depending on its wash type, and sensors and counters evolve second by
+//! second while it runs. Time can be sped up so that a whole cycle fits in a short demo.
+use super::{
+  alarms, Capabilities, Configuration, ConnectionState, Lifecycle, MachineData, ProgramPreview,
+  ReconnectPolicy, State, StateCode, Statistics, StepType, WashingMachineConnection,
+};
+use super::{Error, Result as WSResult};
+use async_trait::async_trait;
+use std::{
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+const AMBIENT_TEMPERATURE: u16 = 20;
+/// Degrees gained every second while heating
+const HEATING_RATE: f64 = 0.1;
+/// Level units gained every second while filling, and lost while draining
+const FILLING_RATE: f64 = 0.5;
+const DRAINING_RATE: f64 = 1.0;
+/// Rpm gained every second when spinning up, and lost when braking
+const RAMP_RATE: f64 = 20.0;
+const BRAKING_RATE: f64 = 50.0;
+const SOAP_PUMPS: usize = 4;
+/// Fastest a simulated clock may run: beyond it a single refresh would simulate hours of work
+pub const MAX_SPEEDUP: u32 = 1000;
+
+/// The speedup to use for the one requested, brought within `1..=MAX_SPEEDUP`
+pub(super) fn clamp_speedup(speedup: u32) -> u32 {
+  let clamped = speedup.clamp(1, MAX_SPEEDUP);
+  if clamped != speedup {
+    log::warn!("Speedup {} out of range, using {}", speedup, clamped);
+  }
+  clamped
+}
+
+/// Something that can happen to the machine regardless of the commands it receives
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum SimulatedEvent {
+  /// Raises an alarm; restartable alarms pause the cycle, the others interrupt it
+  Alarm(u16),
+  /// Opens or closes the porthole; it stays locked while a cycle is running
+  Porthole(bool),
+}
+
+#[derive(Clone, Debug)]
+struct Step {
+  step_type: StepType,
+  duration: u16,
+  temperature: u16,
+  level: u16,
+  speed: u16,
+  /// Seconds of detergent dispensed at the start of the step, by the given pump
+  soap: Option<(usize, u16)>,
+}
+
+impl Step {
+  fn new(step_type: StepType, duration: u16) -> Self {
+    Self {
+      step_type,
+      duration,
+      temperature: 0,
+      level: 0,
+      speed: 0,
+      soap: None,
+    }
+  }
+
+  fn wash(
+    step_type: StepType,
+    duration: u16,
+    temperature: u16,
+    soap: Option<(usize, u16)>,
+  ) -> Self {
+    Self {
+      temperature,
+      level: 12,
+      speed: 45,
+      soap,
+      ..Self::new(step_type, duration)
+    }
+  }
+
+  fn centrifuge(duration: u16, speed: u16) -> Self {
+    Self {
+      speed,
+      ..Self::new(StepType::Centrifuge, duration)
+    }
+  }
+}
+
+/// Steps of a program, following the wash types of the machine configuration: prewash,
+/// temperature, length, rinses and spin speed all depend on it
+fn program_steps(program: &ProgramPreview) -> Vec<Step> {
+  let (prewash, temperature, duration, rinses, spin) = match program.wash_type {
+    // Very dirty and dirty, with and without prewash
+    0 => (true, 60, 720, 2, 900),
+    1 => (true, 50, 600, 2, 800),
+    2 => (false, 60, 720, 2, 900),
+    3 => (false, 50, 600, 2, 800),
+    // Coloured, synthetics, duvets, cold delicates, wool, linen and curtains
+    4 => (false, 40, 540, 2, 700),
+    5 => (false, 40, 420, 2, 600),
+    6 => (false, 30, 480, 3, 500),
+    7 => (false, 0, 360, 2, 400),
+    8 => (false, 30, 300, 2, 400),
+    9 => (false, 40, 540, 2, 600),
+    // Spin only, at 1000 and 600 rpm
+    10 => return vec![Step::centrifuge(300, 1000)],
+    11 => return vec![Step::centrifuge(300, 600)],
+    // Sanitization
+    12 => (false, 90, 600, 1, 800),
+    13 => {
+      return vec![
+        Step::wash(StepType::Soaking, 900, 30, Some((0, 10))),
+        Step::new(StepType::Drain, 30),
+      ]
+    }
+    14 => {
+      return vec![
+        Step::wash(StepType::Prewash, 240, 30, Some((0, 10))),
+        Step::new(StepType::Drain, 30),
+        Step::centrifuge(240, 800),
+      ]
+    }
+    15 => {
+      return vec![
+        Step::wash(StepType::Rinse, 180, 0, Some((2, 10))),
+        Step::new(StepType::Drain, 30),
+        Step::centrifuge(240, 800),
+      ]
+    }
+    _ => (false, 40, 540, 2, 700),
+  };
+
+  let mut steps = vec![];
+  if prewash {
+    steps.push(Step::wash(StepType::Prewash, 240, 30, Some((0, 10))));
+    steps.push(Step::new(StepType::Drain, 30));
+  }
+  steps.push(Step::wash(
+    StepType::Wash,
+    duration,
+    temperature,
+    Some((1, 20)),
+  ));
+  steps.push(Step::new(StepType::Drain, 30));
+  for rinse in 1..=rinses {
+    // Softener goes in with the last rinse
+    let soap = if rinse == rinses { Some((2, 10)) } else { None };
+    steps.push(Step::wash(StepType::Rinse, 180, 0, soap));
+    steps.push(Step::new(StepType::Drain, 30));
+  }
+  steps.push(Step::centrifuge(240, spin));
+  steps
+}
+
+struct Cycle {
+  steps: Vec<Step>,
+  step: usize,
+  step_elapsed: u16,
+}
+
+impl Cycle {
+  fn current(self: &Self) -> &Step {
+    &self.steps[self.step]
+  }
+
+  fn remaining(self: &Self) -> u16 {
+    let current = self.current().duration.saturating_sub(self.step_elapsed);
+    self.steps[self.step + 1..]
+      .iter()
+      .fold(current, |total, step| total.saturating_add(step.duration))
+  }
+}
+
+/// State of the simulated machine, advanced one simulated second at a time
+struct Simulation {
+  name: String,
+  configuration: Configuration,
+  state: State,
+  stats: Statistics,
+  cycle: Option<Cycle>,
+  temperature: f64,
+  level: f64,
+  speed: f64,
+  speedup: u32,
+  last_tick: Instant,
+  /// Fraction of simulated second not yet applied
+  carry: Duration,
+}
+
+impl Simulation {
+  fn new(name: String, configuration: Configuration, speedup: u32) -> Self {
+    Self {
+      state: State {
+        name: name.clone(),
+        state: StateCode::Stopped,
+        temperature: AMBIENT_TEMPERATURE,
+        ..State::default()
+      },
+      stats: Statistics {
+        soap_times: vec![0; SOAP_PUMPS],
+        ..Statistics::default()
+      },
+      name,
+      configuration,
+      cycle: None,
+      temperature: AMBIENT_TEMPERATURE as f64,
+      level: 0.0,
+      speed: 0.0,
+      speedup: clamp_speedup(speedup),
+      last_tick: Instant::now(),
+      carry: Duration::from_secs(0),
+    }
+  }
+
+  fn data(self: &Self) -> MachineData {
+    MachineData {
+      active: true,
+      name: self.name.clone(),
+      state: self.state.clone(),
+      configuration: self.configuration.clone(),
+      stats: self.stats.clone(),
+    }
+  }
+
+  /// Brings the simulation up to date with the wall clock
+  fn advance(self: &mut Self) {
+    let now = Instant::now();
+    let elapsed = (now - self.last_tick) * self.speedup + self.carry;
+    self.last_tick = now;
+    self.carry = Duration::from_nanos(elapsed.subsec_nanos() as u64);
+    for _ in 0..elapsed.as_secs() {
+      self.second();
+    }
+  }
+
+  fn second(self: &mut Self) {
+    self.stats.on_time += 1;
+
+    let target = match self.cycle {
+      Some(ref cycle) if self.state.state == StateCode::Running => Some(cycle.current().clone()),
+      _ => None,
+    };
+
+    match target {
+      Some(step) => self.run_step(&step),
+      None => self.idle(),
+    }
+
+    self.state.temperature = self.temperature.round() as u16;
+    self.state.level = self.level.round() as u16;
+    self.state.speed = self.speed.round() as u16;
+
+    if self.speed > 0.0 {
+      self.stats.rotation_time += 1;
+    }
+    if self.state.state == StateCode::Braking && self.speed <= 0.0 {
+      self.state.state = StateCode::Stopped;
+    }
+  }
+
+  /// Sensors drift back to rest when no step is running
+  fn idle(self: &mut Self) {
+    self.speed = (self.speed - BRAKING_RATE).max(0.0);
+    if self.temperature > AMBIENT_TEMPERATURE as f64 {
+      self.temperature = (self.temperature - HEATING_RATE / 4.0).max(AMBIENT_TEMPERATURE as f64);
+    }
+  }
+
+  fn run_step(self: &mut Self, step: &Step) {
+    self.stats.work_time += 1;
+
+    match step.step_type {
+      StepType::Drain | StepType::Centrifuge => {
+        self.level = (self.level - DRAINING_RATE).max(0.0);
+      }
+      _ if self.level < step.level as f64 => {
+        self.level = (self.level + FILLING_RATE).min(step.level as f64);
+        if step.temperature > 0 {
+          self.stats.warm_water_time += 1;
+        } else {
+          self.stats.cold_water_time += 1;
+        }
+      }
+      _ => (),
+    }
+
+    if (self.temperature as u16) < step.temperature && self.level > 0.0 {
+      self.temperature += HEATING_RATE;
+      self.stats.heating_time += 1;
+    } else if step.temperature == 0 && self.temperature > AMBIENT_TEMPERATURE as f64 {
+      self.temperature = (self.temperature - HEATING_RATE).max(AMBIENT_TEMPERATURE as f64);
+    }
+
+    let speed = step.speed as f64;
+    self.speed = if self.speed < speed {
+      (self.speed + RAMP_RATE).min(speed)
+    } else {
+      (self.speed - BRAKING_RATE).max(speed)
+    };
+
+    let cycle = match self.cycle {
+      Some(ref mut cycle) => cycle,
+      None => return,
+    };
+    if let Some((pump, secs)) = step.soap {
+      if cycle.step_elapsed < secs {
+        self.stats.soap_times[pump] += 1;
+      }
+    }
+
+    cycle.step_elapsed += 1;
+    if cycle.step_elapsed >= step.duration {
+      cycle.step += 1;
+      cycle.step_elapsed = 0;
+    }
+
+    if cycle.step >= cycle.steps.len() {
+      self.stats.cycles += 1;
+      self.finish();
+    } else {
+      self.state.step_number = cycle.step as u16;
+      self.state.step_code = cycle.current().step_type;
+      self.state.cycle_remaining = cycle.remaining();
+      self.state.step_remaining = cycle.current().duration - cycle.step_elapsed;
+    }
+  }
+
+  /// Ends the current cycle, braking the drum first if it is still spinning
+  fn finish(self: &mut Self) {
+    self.cycle = None;
+    self.state.state = if self.speed > 0.0 {
+      StateCode::Braking
+    } else {
+      StateCode::Stopped
+    };
+    self.state.cycle_remaining = 0;
+    self.state.step_remaining = 0;
+    self.state.step_number = 0;
+    self.state.step_count = 0;
+    self.state.step_code = StepType::default();
+  }
+
+  fn start(self: &mut Self, program: u16) -> WSResult<()> {
+    if self.state.state != StateCode::Stopped || self.state.alarm_code != 0 {
+      return Err(Error::Value);
+    }
+    if self.state.porthole_open {
+      return Err(Error::Value);
+    }
+    let preview = self
+      .configuration
+      .programs
+      .get(program as usize)
+      .ok_or(Error::Value)?;
+
+    let cycle = Cycle {
+      steps: program_steps(preview),
+      step: 0,
+      step_elapsed: 0,
+    };
+    self.state.state = StateCode::Running;
+    self.state.cycle = program;
+    self.state.step_number = 0;
+    self.state.step_count = cycle.steps.len() as u16;
+    self.state.step_code = cycle.current().step_type;
+    self.state.cycle_remaining = cycle.remaining();
+    self.state.step_remaining = cycle.current().duration;
+    self.cycle = Some(cycle);
+    Ok(())
+  }
+
+  fn pause(self: &mut Self) -> WSResult<()> {
+    match self.state.state {
+      StateCode::Running => {
+        self.state.state = StateCode::Paused;
+        Ok(())
+      }
+      _ => Err(Error::Value),
+    }
+  }
+
+  fn restart(self: &mut Self) -> WSResult<()> {
+    match self.state.state {
+      StateCode::Paused if self.state.alarm_code == 0 => {
+        self.state.state = StateCode::Running;
+        Ok(())
+      }
+      _ => Err(Error::Value),
+    }
+  }
+
+  fn stop(self: &mut Self) -> WSResult<()> {
+    match self.state.state {
+      StateCode::Running | StateCode::Paused => {
+        self.stats.interrupted_cycles += 1;
+        self.finish();
+        Ok(())
+      }
+      _ => Err(Error::Value),
+    }
+  }
+
+  fn inject(self: &mut Self, event: SimulatedEvent) -> WSResult<()> {
+    match event {
+      SimulatedEvent::Alarm(code) => {
+        self.state.alarm_code = code;
+        if code != 0 && self.cycle.is_some() {
+          if alarms::describe(code).restartable {
+            self.state.state = StateCode::Paused;
+          } else {
+            self.stats.interrupted_cycles += 1;
+            self.finish();
+          }
+        }
+        Ok(())
+      }
+      SimulatedEvent::Porthole(open) => {
+        if self.state.porthole_open == open {
+          return Ok(());
+        }
+        if self.state.state != StateCode::Stopped {
+          return Err(Error::Value);
+        }
+        self.state.porthole_open = open;
+        if open {
+          self.stats.porthole_openings += 1;
+        } else {
+          self.stats.porthole_closings += 1;
+        }
+        Ok(())
+      }
+    }
+  }
+}
+
+pub struct Connection {
+  simulation: Mutex<Simulation>,
+  lifecycle: Mutex<Lifecycle>,
+}
+
+impl Connection {
+  pub fn new(name: String, configuration: Configuration) -> Self {
+    Self::with_speedup(name, configuration, 1)
+  }
+
+  /// A machine whose clock runs `speedup` times faster than the wall clock, up to `MAX_SPEEDUP`
+  pub fn with_speedup(name: String, configuration: Configuration, speedup: u32) -> Self {
+    let simulation = Simulation::new(name, configuration, speedup);
+    let mut lifecycle = Lifecycle::new(ReconnectPolicy::default());
+    lifecycle.success(simulation.data());
+    Self {
+      simulation: Mutex::new(simulation),
+      lifecycle: Mutex::new(lifecycle),
+    }
+  }
+
+  /// A handful of programs with different wash types, for when there is no real configuration
+  /// at hand
+  pub fn demo_configuration() -> Configuration {
+    let programs = [
+      ("Molto sporchi con prelavaggio", 0),
+      ("Colorati", 4),
+      ("Delicati a freddo", 7),
+      ("Lana", 8),
+      ("Solo centrifuga", 10),
+    ];
+    Configuration {
+      name: String::from("Demo"),
+      app_version: String::from("simulated"),
+      machines: vec![],
+      programs: programs
+        .iter()
+        .map(|(name, wash_type)| ProgramPreview {
+          name: String::from(*name),
+          wash_type: *wash_type,
+        })
+        .collect(),
+    }
+  }
+
+  pub fn inject(self: &Self, event: SimulatedEvent) -> WSResult<()> {
+    self.command(|simulation| simulation.inject(event))
+  }
+
+  /// Runs a command on the simulation and publishes the outcome right away, as a real machine
+  /// would show it on the next poll
+  fn command(self: &Self, command: impl FnOnce(&mut Simulation) -> WSResult<()>) -> WSResult<()> {
+    let mut simulation = self.simulation.lock().unwrap();
+    simulation.advance();
+    let result = command(&mut simulation);
+    self.lifecycle.lock().unwrap().success(simulation.data());
+    result
+  }
+}
+
+#[async_trait]
+impl WashingMachineConnection for Connection {
+  fn suggested_refresh_period(self: &Self) -> Duration {
+    Duration::from_secs(1)
+  }
+
+  fn capabilities(self: &Self) -> Capabilities {
+    Capabilities {
+      remote_control: true,
+      clear_alarms: true,
+      download_configuration: false,
+      upload_configuration: false,
+      select_configuration: false,
+      statistics: true,
+    }
+  }
+
+  async fn refresh_data(self: &Self) {
+    let mut simulation = self.simulation.lock().unwrap();
+    simulation.advance();
+    self.lifecycle.lock().unwrap().success(simulation.data());
+  }
+
+  async fn send_machine_configuration(self: &Self, _data: Vec<u8>) -> WSResult<()> {
+    Err(Error::Unsupported)
+  }
+
+  async fn select_machine_configuration(self: &Self, _archive: String) -> WSResult<()> {
+    Err(Error::Unsupported)
+  }
+
+  async fn get_machine_configuration(self: &Self) -> WSResult<Vec<u8>> {
+    Err(Error::Unsupported)
+  }
+
+  fn get_connection_state(self: &Self) -> ConnectionState {
+    self.lifecycle.lock().unwrap().state()
+  }
+
+  async fn restart(self: &Self) -> WSResult<()> {
+    self.command(Simulation::restart)
+  }
+
+  async fn pause(self: &Self) -> WSResult<()> {
+    self.command(Simulation::pause)
+  }
+
+  async fn stop(self: &Self) -> WSResult<()> {
+    self.command(Simulation::stop)
+  }
+
+  async fn start_program(self: &Self, program: u16) -> WSResult<()> {
+    self.command(|simulation| simulation.start(program))
+  }
+
+  async fn clear_alarms(self: &Self) -> WSResult<()> {
+    self.command(|simulation| {
+      simulation.state.alarm_code = 0;
+      Ok(())
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::controller::events::{self, MachineEvent};
+
+  fn simulation(speedup: u32) -> Simulation {
+    Simulation::new(
+      String::from("Test"),
+      Connection::demo_configuration(),
+      speedup,
+    )
+  }
+
+  /// Pretends that `secs` seconds of wall clock went by since the last update
+  fn elapse(simulation: &mut Simulation, secs: u64) {
+    simulation.last_tick -= Duration::from_secs(secs);
+    simulation.advance();
+  }
+
+  #[test]
+  fn start_runs_the_program() {
+    let mut simulation = simulation(1);
+    simulation.start(1).unwrap();
+
+    let state = &simulation.state;
+    assert_eq!(state.state, StateCode::Running);
+    assert_eq!(state.cycle, 1);
+    assert_eq!(state.step_number, 0);
+    assert_eq!(state.step_code, StepType::Wash);
+    assert_eq!(state.step_count, 7);
+    assert!(state.cycle_remaining > state.step_remaining);
+  }
+
+  #[test]
+  fn start_is_refused_when_not_possible() {
+    let mut simulation = simulation(1);
+    assert!(matches!(simulation.start(99), Err(Error::Value)));
+
+    simulation.inject(SimulatedEvent::Porthole(true)).unwrap();
+    assert!(matches!(simulation.start(0), Err(Error::Value)));
+
+    simulation.inject(SimulatedEvent::Porthole(false)).unwrap();
+    simulation.start(0).unwrap();
+    assert!(matches!(simulation.start(0), Err(Error::Value)));
+  }
+
+  #[test]
+  fn pause_and_restart() {
+    let mut simulation = simulation(1);
+    assert!(matches!(simulation.pause(), Err(Error::Value)));
+
+    simulation.start(0).unwrap();
+    simulation.pause().unwrap();
+    assert_eq!(simulation.state.state, StateCode::Paused);
+
+    // A paused cycle does not make progress
+    let remaining = simulation.state.cycle_remaining;
+    elapse(&mut simulation, 10);
+    assert_eq!(simulation.state.cycle_remaining, remaining);
+
+    simulation.restart().unwrap();
+    assert_eq!(simulation.state.state, StateCode::Running);
+  }
+
+  #[test]
+  fn stop_interrupts_the_cycle() {
+    let mut simulation = simulation(1);
+    assert!(matches!(simulation.stop(), Err(Error::Value)));
+
+    simulation.start(0).unwrap();
+    simulation.stop().unwrap();
+    assert_eq!(simulation.state.state, StateCode::Stopped);
+    assert_eq!(simulation.state.cycle_remaining, 0);
+    assert_eq!(simulation.stats.interrupted_cycles, 1);
+    assert_eq!(simulation.stats.cycles, 0);
+  }
+
+  #[test]
+  fn alarms_stop_the_cycle() {
+    let mut simulation = simulation(1);
+    simulation.start(0).unwrap();
+    simulation.inject(SimulatedEvent::Alarm(7)).unwrap();
+
+    // Alarms missing from the catalog are not restartable
+    assert_eq!(simulation.state.alarm_code, 7);
+    assert_eq!(simulation.state.state, StateCode::Stopped);
+    assert_eq!(simulation.stats.interrupted_cycles, 1);
+    assert!(matches!(simulation.start(0), Err(Error::Value)));
+
+    simulation.inject(SimulatedEvent::Alarm(0)).unwrap();
+    simulation.start(0).unwrap();
+  }
+
+  #[test]
+  fn porthole_is_locked_while_running() {
+    let mut simulation = simulation(1);
+    simulation.inject(SimulatedEvent::Porthole(true)).unwrap();
+    simulation.inject(SimulatedEvent::Porthole(false)).unwrap();
+    assert_eq!(simulation.stats.porthole_openings, 1);
+    assert_eq!(simulation.stats.porthole_closings, 1);
+
+    simulation.start(0).unwrap();
+    assert!(matches!(
+      simulation.inject(SimulatedEvent::Porthole(true)),
+      Err(Error::Value)
+    ));
+  }
+
+  #[test]
+  fn speedup_is_bounded() {
+    assert_eq!(simulation(0).speedup, 1);
+    assert_eq!(simulation(MAX_SPEEDUP).speedup, MAX_SPEEDUP);
+    assert_eq!(simulation(u32::MAX).speedup, MAX_SPEEDUP);
+  }
+
+  #[test]
+  fn advance_applies_the_speedup() {
+    let mut simulation = simulation(6);
+    simulation.start(0).unwrap();
+    let remaining = simulation.state.cycle_remaining;
+
+    elapse(&mut simulation, 10);
+    assert_eq!(simulation.stats.on_time, 60);
+    assert_eq!(simulation.stats.work_time, 60);
+    assert_eq!(simulation.state.cycle_remaining, remaining - 60);
+    assert!(simulation.state.level > 0);
+  }
+
+  #[test]
+  fn cycles_run_to_completion() {
+    let mut simulation = simulation(10);
+    simulation.start(4).unwrap();
+    let remaining = simulation.state.cycle_remaining as u64;
+
+    // Leave the drum some time to brake after the last step
+    elapse(&mut simulation, remaining / 10 + 10);
+    assert_eq!(simulation.state.state, StateCode::Stopped);
+    assert_eq!(simulation.state.speed, 0);
+    assert_eq!(simulation.stats.cycles, 1);
+    assert_eq!(simulation.stats.interrupted_cycles, 0);
+  }
+
+  #[test]
+  fn wash_types_have_distinct_programs() {
+    let programs: Vec<Vec<(StepType, u16, u16, u16)>> = (0..16)
+      .map(|wash_type| {
+        let preview = ProgramPreview {
+          name: String::new(),
+          wash_type,
+        };
+        program_steps(&preview)
+          .iter()
+          .map(|step| (step.step_type, step.duration, step.temperature, step.speed))
+          .collect()
+      })
+      .collect();
+
+    for (i, program) in programs.iter().enumerate() {
+      assert!(!program.is_empty());
+      assert!(
+        programs[i + 1..].iter().all(|other| other != program),
+        "wash type {} has the same steps as a later one",
+        i
+      );
+    }
+  }
+
+  #[test]
+  fn snapshots_produce_events() {
+    let mut simulation = simulation(6);
+    let stopped = simulation.data();
+
+    simulation.start(0).unwrap();
+    let started = simulation.data();
+    assert!(matches!(
+      events::diff(&stopped, &started)[..],
+      [MachineEvent::CycleStarted { program: 0 }]
+    ));
+
+    // Past the prewash and its drain, into the wash
+    elapse(&mut simulation, 50);
+    let washing = simulation.data();
+    assert!(matches!(
+      events::diff(&started, &washing)[..],
+      [MachineEvent::StepChanged {
+        step_number: 2,
+        step_code: StepType::Wash
+      }]
+    ));
+
+    simulation.stop().unwrap();
+    let stopped = simulation.data();
+    assert!(matches!(
+      events::diff(&washing, &stopped)[..],
+      [MachineEvent::CycleInterrupted { program: 0 }]
+    ));
+
+    simulation.inject(SimulatedEvent::Alarm(7)).unwrap();
+    let alarm = simulation.data();
+    assert!(matches!(
+      events::diff(&stopped, &alarm)[..],
+      [MachineEvent::AlarmRaised { code: 7, .. }]
+    ));
+  }
+}
@@ -1,7 +1,14 @@
 use super::super::things5_api;
-use super::{ConnectionState, Statistics, WashingMachineConnection};
+use super::{
+  Capabilities, ConnectionState, Lifecycle, MachineData, ReconnectPolicy, Statistics,
+  WashingMachineConnection,
+};
 use super::{Error, Result as WSResult};
-use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use std::{
+  sync::Mutex,
+  time::{Duration, Instant},
+};
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct StatisticsPair {
@@ -11,131 +18,146 @@ pub struct StatisticsPair {
 pub struct Connection {
   token: String,
   device_id: String,
-  connection_state: ConnectionState,
-  last_complete_update: Instant,
+  lifecycle: Mutex<Lifecycle>,
+  last_complete_update: Mutex<Option<Instant>>,
 }
 
 impl Connection {
-  pub fn new(token: String, device_id: String) -> Self {
-    let connection_state =
-      match things5_api::refresh_data_ingestion(token.as_str(), device_id.as_str()) {
-        Ok(()) => Self::first_connection(token.clone(), device_id.clone()),
-        Err(e) => {
-          log::warn!("Could not refresh data ingestion: {:?}", e);
-          ConnectionState::Error
-        }
-      };
-
-    Self {
+  pub async fn new(token: String, device_id: String) -> Self {
+    Self::with_policy(token, device_id, ReconnectPolicy::default()).await
+  }
+
+  pub async fn with_policy(token: String, device_id: String, policy: ReconnectPolicy) -> Self {
+    let connection = Self {
       token,
       device_id,
-      connection_state,
-      last_complete_update: Instant::now(),
-    }
+      lifecycle: Mutex::new(Lifecycle::new(policy)),
+      last_complete_update: Mutex::new(None),
+    };
+    connection.refresh_data().await;
+    connection
   }
 
-  fn first_connection(token: String, device_id: String) -> ConnectionState {
-    let get_state = || -> Result<ConnectionState, Error> {
-      let (name, active) = things5_api::get_device_details(token.as_str(), device_id.as_str())?;
-      let (state, stats) =
-        things5_api::get_state_and_statistics(token.as_str(), device_id.as_str())?;
-      let configuration =
-        things5_api::get_preview_configuration(token.as_str(), device_id.as_str())?;
-      Ok(ConnectionState::Connected {
-        active,
-        name,
-        state,
-        stats,
-        configuration,
-      })
-    };
-    get_state().unwrap_or(ConnectionState::Error)
+  async fn first_connection(token: &str, device_id: &str) -> WSResult<MachineData> {
+    things5_api::refresh_data_ingestion(token, device_id)
+      .await
+      .map_err(|e| {
+        log::warn!("Could not refresh data ingestion: {:?}", e);
+        e
+      })?;
+
+    let (name, active) = things5_api::get_device_details(token, device_id).await?;
+    let (state, stats) = things5_api::get_state_and_statistics(token, device_id).await?;
+    let configuration = things5_api::get_preview_configuration(token, device_id).await?;
+    Ok(MachineData {
+      active,
+      name,
+      state,
+      stats,
+      configuration,
+    })
+  }
+
+  /// Refreshes only the data that changes over time, reusing the known configuration
+  async fn partial_update(self: &Self, previous: MachineData) -> WSResult<MachineData> {
+    let (name, active) =
+      things5_api::get_device_details(self.token.as_str(), self.device_id.as_str()).await?;
+    let (state, stats) =
+      things5_api::get_state_and_statistics(self.token.as_str(), self.device_id.as_str()).await?;
+    Ok(MachineData {
+      active,
+      name,
+      state,
+      stats,
+      configuration: previous.configuration,
+    })
   }
 }
 
+#[async_trait]
 impl WashingMachineConnection for Connection {
-  fn suggested_refresh_period(self : &Self) -> Duration {
+  fn suggested_refresh_period(self: &Self) -> Duration {
     Duration::from_secs(5)
   }
 
-  fn refresh_data(self: &mut Self) {
-    if Instant::now() - self.last_complete_update > Duration::from_secs(120) {
-      self.connection_state =
-        match things5_api::refresh_data_ingestion(self.token.as_str(), self.device_id.as_str()) {
-          Ok(()) => Self::first_connection(self.token.clone(), self.device_id.clone()),
-          Err(e) => {
-            log::warn!("Could not refresh data ingestion: {:?}", e);
-            ConnectionState::Error
-          }
-        };
-      self.last_complete_update = Instant::now();
-    } else {
-      match self.connection_state.clone() {
-        ConnectionState::Connected {
-          active: _,
-          name: _,
-          state: _,
-          stats: _,
-          configuration,
-        } => {
-          let get_state = || -> Result<ConnectionState, Error> {
-            let (name, active) =
-              things5_api::get_device_details(self.token.as_str(), self.device_id.as_str())?;
-            let (state, stats) =
-              things5_api::get_state_and_statistics(self.token.as_str(), self.device_id.as_str())?;
-            Ok(ConnectionState::Connected {
-              active,
-              name,
-              state,
-              stats,
-              configuration,
-            })
-          };
-
-          self.connection_state = get_state().unwrap_or(ConnectionState::Error);
-        }
-        ConnectionState::Error => {
-          self.connection_state =
-            Self::first_connection(self.token.clone(), self.device_id.clone());
-        }
+  fn capabilities(self: &Self) -> Capabilities {
+    Capabilities {
+      remote_control: true,
+      clear_alarms: true,
+      download_configuration: true,
+      upload_configuration: true,
+      // Things5 has no endpoint to activate a stored archive
+      select_configuration: false,
+      statistics: true,
+    }
+  }
+
+  async fn refresh_data(self: &Self) {
+    let previous = {
+      let lifecycle = self.lifecycle.lock().unwrap();
+      if !lifecycle.should_attempt() {
+        return;
+      }
+      lifecycle.data().cloned()
+    };
+
+    let complete_update_due = self
+      .last_complete_update
+      .lock()
+      .unwrap()
+      .map_or(true, |ts| ts.elapsed() > Duration::from_secs(120));
+
+    let result = match previous {
+      Some(previous) if !complete_update_due => self.partial_update(previous).await,
+      _ => {
+        *self.last_complete_update.lock().unwrap() = Some(Instant::now());
+        Self::first_connection(self.token.as_str(), self.device_id.as_str()).await
+      }
+    };
+
+    let mut lifecycle = self.lifecycle.lock().unwrap();
+    match result {
+      Ok(data) => lifecycle.success(data),
+      Err(e) => {
+        log::warn!("Refresh of {} failed: {:?}", self.device_id, e);
+        lifecycle.failure();
       }
     }
   }
 
-  fn send_machine_configuration(self: &Self, data: Vec<u8>) -> WSResult<()> {
-    things5_api::put_current_machine(self.token.as_str(), self.device_id.as_str(), data)
+  async fn send_machine_configuration(self: &Self, data: Vec<u8>) -> WSResult<()> {
+    things5_api::put_current_machine(self.token.as_str(), self.device_id.as_str(), data).await
   }
 
-  fn select_machine_configuration(self: &Self, _archive: String) -> WSResult<()> {
-    // permanent stub
-    Ok(())
+  async fn select_machine_configuration(self: &Self, _archive: String) -> WSResult<()> {
+    Err(Error::Unsupported)
   }
 
-  fn get_machine_configuration(self: &Self) -> WSResult<Vec<u8>> {
-    things5_api::get_current_machine(self.token.as_str(), self.device_id.as_str())
+  async fn get_machine_configuration(self: &Self) -> WSResult<Vec<u8>> {
+    things5_api::get_current_machine(self.token.as_str(), self.device_id.as_str()).await
   }
 
   fn get_connection_state(self: &Self) -> ConnectionState {
-    self.connection_state.clone()
+    self.lifecycle.lock().unwrap().state()
   }
 
-  fn restart(self: &Self) -> WSResult<()> {
-    things5_api::restart(self.token.as_str(), self.device_id.as_str())
+  async fn restart(self: &Self) -> WSResult<()> {
+    things5_api::restart(self.token.as_str(), self.device_id.as_str()).await
   }
 
-  fn pause(self: &Self) -> WSResult<()> {
-    things5_api::pause(self.token.as_str(), self.device_id.as_str())
+  async fn pause(self: &Self) -> WSResult<()> {
+    things5_api::pause(self.token.as_str(), self.device_id.as_str()).await
   }
 
-  fn stop(self: &Self) -> WSResult<()> {
-    things5_api::stop(self.token.as_str(), self.device_id.as_str())
+  async fn stop(self: &Self) -> WSResult<()> {
+    things5_api::stop(self.token.as_str(), self.device_id.as_str()).await
   }
 
-  fn start_program(self: &Self, program: u16) -> WSResult<()> {
-    things5_api::start(self.token.as_str(), self.device_id.as_str(), program)
+  async fn start_program(self: &Self, program: u16) -> WSResult<()> {
+    things5_api::start(self.token.as_str(), self.device_id.as_str(), program).await
   }
 
-  fn clear_alarms(self: &Self) -> WSResult<()> {
-    things5_api::clear_alarms(self.token.as_str(), self.device_id.as_str())
+  async fn clear_alarms(self: &Self) -> WSResult<()> {
+    things5_api::clear_alarms(self.token.as_str(), self.device_id.as_str()).await
   }
 }
@@ -0,0 +1,369 @@
+//! Stand-in for a WS2020 board on the local network: the HTTP API used by
+//! `washing_machine::local::Connection` and the UDP responder used by `discovery`.
+//!
+//! The machine behind the API is a simulated one, so cycles actually run. Faults can be
+//! scripted per endpoint to exercise the error paths of the clients: every request to the
+//! endpoint consumes the next fault in its queue, and is served normally once the queue is
+//! empty. Discovery probes use the `discovery` endpoint.
+use crate::controller::discovery::DISCOVERY_PROBE;
+use crate::controller::washing_machine::simulated;
+use crate::controller::washing_machine::{Configuration, WashingMachineConnection};
+use crate::controller::Error;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+pub const DISCOVERY_ENDPOINT: &str = "discovery";
+/// How long a `Timeout` fault keeps the client waiting, longer than any client timeout
+const HANG: Duration = Duration::from_secs(60);
+const MAX_HEADER: usize = 16 * 1024;
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Fault {
+  /// Accepts the request and never answers it
+  Timeout,
+  /// Answers with a body that is not valid JSON, or a malformed discovery reply
+  BadJson,
+  /// Leaves out the Content-Length header, ending the body by closing the connection
+  MissingContentLength,
+  /// Answers with the given HTTP status and an empty body
+  Status(u16),
+}
+
+struct Request {
+  method: String,
+  path: String,
+  body: Vec<u8>,
+}
+
+struct Response {
+  status: u16,
+  content_type: &'static str,
+  body: Vec<u8>,
+}
+
+impl Response {
+  fn json(value: impl serde::Serialize) -> Self {
+    Self {
+      status: 200,
+      content_type: "application/json",
+      body: serde_json::to_vec(&value).unwrap_or_default(),
+    }
+  }
+
+  fn empty(status: u16) -> Self {
+    Self {
+      status,
+      content_type: "text/plain",
+      body: vec![],
+    }
+  }
+
+  fn result(result: Result<(), Error>) -> Self {
+    match result {
+      Ok(()) => Self::empty(200),
+      Err(Error::Unsupported) => Self::empty(501),
+      Err(_) => Self::empty(400),
+    }
+  }
+}
+
+fn reason(status: u16) -> &'static str {
+  match status {
+    200 => "OK",
+    400 => "Bad Request",
+    404 => "Not Found",
+    500 => "Internal Server Error",
+    501 => "Not Implemented",
+    503 => "Service Unavailable",
+    _ => "Unknown",
+  }
+}
+
+struct Shared {
+  node: String,
+  machine: simulated::Connection,
+  archive: Mutex<Vec<u8>>,
+  selected_archive: Mutex<Option<String>>,
+  faults: Mutex<HashMap<String, VecDeque<Fault>>>,
+}
+
+#[derive(Clone)]
+pub struct Emulator {
+  shared: Arc<Shared>,
+}
+
+impl Emulator {
+  /// An emulated board for the given node number, running the demo programs
+  pub fn new(node: String, speedup: u32) -> Self {
+    Self::with_configuration(node, simulated::Connection::demo_configuration(), speedup)
+  }
+
+  pub fn with_configuration(node: String, configuration: Configuration, speedup: u32) -> Self {
+    Self {
+      shared: Arc::new(Shared {
+        machine: simulated::Connection::with_speedup(node.clone(), configuration, speedup),
+        node,
+        archive: Mutex::new(vec![]),
+        selected_archive: Mutex::new(None),
+        faults: Mutex::new(HashMap::new()),
+      }),
+    }
+  }
+
+  /// The simulated machine behind the API, to inject alarms and porthole events
+  pub fn machine(self: &Self) -> &simulated::Connection {
+    &self.shared.machine
+  }
+
+  /// Configuration archive currently stored on the board
+  pub fn archive(self: &Self) -> Vec<u8> {
+    self.shared.archive.lock().unwrap().clone()
+  }
+
+  pub fn set_archive(self: &Self, archive: Vec<u8>) {
+    *self.shared.archive.lock().unwrap() = archive;
+  }
+
+  /// Last archive activated through `/select_machine`
+  pub fn selected_archive(self: &Self) -> Option<String> {
+    self.shared.selected_archive.lock().unwrap().clone()
+  }
+
+  /// Queues faults for the next requests to an endpoint, e.g. `state` or `discovery`
+  pub fn script(self: &Self, endpoint: &str, faults: Vec<Fault>) {
+    self
+      .shared
+      .faults
+      .lock()
+      .unwrap()
+      .entry(String::from(endpoint))
+      .or_default()
+      .extend(faults);
+  }
+
+  fn next_fault(self: &Self, endpoint: &str) -> Option<Fault> {
+    self
+      .shared
+      .faults
+      .lock()
+      .unwrap()
+      .get_mut(endpoint)
+      .and_then(|queue| queue.pop_front())
+  }
+
+  /// Serves the HTTP API on the given address, returning the address actually bound so that
+  /// port 0 can be used
+  pub async fn serve_http(self: &Self, address: &str) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address).await?;
+    let local = listener.local_addr()?;
+    let emulator = self.clone();
+    tokio::spawn(async move {
+      loop {
+        match listener.accept().await {
+          Ok((stream, _)) => {
+            let emulator = emulator.clone();
+            tokio::spawn(async move {
+              if let Err(e) = emulator.connection(stream).await {
+                log::warn!("Emulator connection failed: {:?}", e);
+              }
+            });
+          }
+          Err(e) => {
+            log::error!("Emulator stopped accepting connections: {:?}", e);
+            break;
+          }
+        }
+      }
+    });
+    Ok(local)
+  }
+
+  /// Answers discovery probes on the given address, advertising `ip` as the ethernet address
+  pub async fn serve_discovery(self: &Self, address: &str, ip: String) -> io::Result<SocketAddr> {
+    let socket = UdpSocket::bind(address).await?;
+    let local = socket.local_addr()?;
+    let emulator = self.clone();
+    tokio::spawn(async move {
+      let mut buffer = [0; 64];
+      loop {
+        let (n, from) = match socket.recv_from(&mut buffer).await {
+          Ok(received) => received,
+          Err(e) => {
+            log::error!("Emulator stopped answering discovery: {:?}", e);
+            break;
+          }
+        };
+        if &buffer[..n] != DISCOVERY_PROBE.as_bytes() {
+          continue;
+        }
+
+        let reply = match emulator.next_fault(DISCOVERY_ENDPOINT) {
+          Some(Fault::Timeout) => continue,
+          Some(Fault::BadJson) => String::from("WS2020|garbage"),
+          _ => format!("WS2020|{}||{}", ip, emulator.shared.node),
+        };
+        if let Err(e) = socket.send_to(reply.as_bytes(), from).await {
+          log::warn!("Unable to answer discovery from {}: {:?}", from, e);
+        }
+      }
+    });
+    Ok(local)
+  }
+
+  async fn connection(self: &Self, mut stream: TcpStream) -> io::Result<()> {
+    let request = match read_request(&mut stream).await? {
+      Some(request) => request,
+      None => return Ok(()),
+    };
+    let endpoint = request
+      .path
+      .trim_start_matches('/')
+      .split('/')
+      .next()
+      .unwrap_or_default()
+      .to_string();
+    log::debug!("Emulator: {} {}", request.method, request.path);
+
+    match self.next_fault(endpoint.as_str()) {
+      None => {
+        let response = self.handle(&request, endpoint.as_str()).await;
+        write_response(&mut stream, &response, true).await
+      }
+      Some(Fault::Timeout) => {
+        tokio::time::sleep(HANG).await;
+        Ok(())
+      }
+      Some(Fault::BadJson) => {
+        let response = Response {
+          status: 200,
+          content_type: "application/json",
+          body: b"{\"truncated\": ".to_vec(),
+        };
+        write_response(&mut stream, &response, true).await
+      }
+      Some(Fault::MissingContentLength) => {
+        let response = self.handle(&request, endpoint.as_str()).await;
+        write_response(&mut stream, &response, false).await
+      }
+      Some(Fault::Status(status)) => {
+        write_response(&mut stream, &Response::empty(status), true).await
+      }
+    }
+  }
+
+  async fn handle(self: &Self, request: &Request, endpoint: &str) -> Response {
+    let machine = &self.shared.machine;
+    machine.refresh_data().await;
+    let data = match machine.get_connection_state().data() {
+      Some(data) => data.clone(),
+      None => return Response::empty(503),
+    };
+
+    match (request.method.as_str(), endpoint) {
+      ("GET", "state") => Response::json(&data.state),
+      ("GET", "info") => Response::json(&data.configuration),
+      ("GET", "machine") => Response {
+        status: 200,
+        content_type: "application/octet-stream",
+        body: self.archive(),
+      },
+      ("GET", "statistics") => Response::json(serde_json::json!({ "total": data.stats })),
+      ("POST", "machine") => {
+        self.set_archive(request.body.clone());
+        Response::empty(200)
+      }
+      ("POST", "start") => match serde_json::from_slice::<serde_json::Value>(&request.body) {
+        Ok(json) => match json.get("cycle").and_then(|cycle| cycle.as_u64()) {
+          Some(cycle) => Response::result(machine.start_program(cycle as u16).await),
+          None => Response::empty(400),
+        },
+        Err(_) if request.body.is_empty() => Response::result(machine.restart().await),
+        Err(_) => Response::empty(400),
+      },
+      ("POST", "pause") => Response::result(machine.pause().await),
+      ("POST", "stop") => Response::result(machine.stop().await),
+      ("POST", "clear_alarms") => Response::result(machine.clear_alarms().await),
+      ("POST", "select_machine") => {
+        let archive = request.path.splitn(3, '/').nth(2).unwrap_or_default();
+        match urlencoding::decode(archive) {
+          Ok(archive) if !archive.is_empty() => {
+            *self.shared.selected_archive.lock().unwrap() = Some(archive.into_owned());
+            Response::empty(200)
+          }
+          _ => Response::empty(400),
+        }
+      }
+      _ => Response::empty(404),
+    }
+  }
+}
+
+/// Reads a whole request, or nothing if the client closed the connection right away
+async fn read_request(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+  let mut buffer: Vec<u8> = vec![];
+  let mut chunk = [0; 4096];
+
+  let header_end = loop {
+    if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+      break end + 4;
+    }
+    if buffer.len() > MAX_HEADER {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Header too long",
+      ));
+    }
+    let n = stream.read(&mut chunk).await?;
+    if n == 0 {
+      return Ok(None);
+    }
+    buffer.extend_from_slice(&chunk[..n]);
+  };
+
+  let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+  let mut lines = head.split("\r\n");
+  let mut request_line = lines.next().unwrap_or_default().split(' ');
+  let method = request_line.next().unwrap_or_default().to_string();
+  let path = request_line.next().unwrap_or_default().to_string();
+  let content_length = lines
+    .filter_map(|line| line.split_once(':'))
+    .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+    .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+    .unwrap_or(0);
+
+  let mut body = buffer[header_end..].to_vec();
+  while body.len() < content_length {
+    let n = stream.read(&mut chunk).await?;
+    if n == 0 {
+      break;
+    }
+    body.extend_from_slice(&chunk[..n]);
+  }
+
+  Ok(Some(Request { method, path, body }))
+}
+
+async fn write_response(
+  stream: &mut TcpStream,
+  response: &Response,
+  content_length: bool,
+) -> io::Result<()> {
+  let mut head = format!(
+    "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nConnection: close\r\n",
+    response.status,
+    reason(response.status),
+    response.content_type
+  );
+  if content_length {
+    head.push_str(format!("Content-Length: {}\r\n", response.body.len()).as_str());
+  }
+  head.push_str("\r\n");
+
+  stream.write_all(head.as_bytes()).await?;
+  stream.write_all(&response.body).await?;
+  stream.shutdown().await
+}
@@ -2,18 +2,442 @@
   all(not(debug_assertions), target_os = "windows"),
   windows_subsystem = "windows"
 )]
-use std::thread;
-use tauri::{AppHandle, Window};
-mod controller;
+use laundry_control::controller::{
+  self,
+  consumption::{ConsumptionEstimate, RatesTable},
+  fleet::{FleetReport, FleetTarget},
+  history::{HistoryPage, HistoryQuery},
+  maintenance::{MaintenanceRule, MaintenanceStatus},
+  notifications::NotificationSettings,
+  scheduler::ScheduledStart,
+  statistics::{self, StatisticsExport, StatisticsRange},
+  washing_machine::{modbus::RegisterMap, simulated::SimulatedEvent},
+  BackEndPortMessage, Error, Handle, MachineUpdate, Things5Session,
+};
 use simplelog::*;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::thread;
+use tauri::{AppHandle, Manager, State, Window};
 
 #[tauri::command]
-fn init_tasks(_app: AppHandle, window: Window) -> Result<(), String> {
+fn init_tasks(_app: AppHandle, window: Window, handle: State<'_, Handle>) -> Result<(), String> {
   log::info!("Spawning backend thread");
-  thread::spawn(move || controller::task(window));
+  let handle = handle.inner().clone();
+  thread::spawn(move || controller::supervise(window, handle));
   Ok(())
 }
 
+fn backend(window: &Window) -> Handle {
+  window.state::<Handle>().inner().clone()
+}
+
+#[tauri::command]
+async fn things5_login(
+  window: Window,
+  username: String,
+  password: String,
+) -> Result<Things5Session, Error> {
+  backend(&window)
+    .request(
+      None,
+      BackEndPortMessage::Things5Login { username, password },
+    )
+    .await
+}
+
+#[tauri::command]
+async fn search_machines(window: Window) -> Result<Vec<(String, String)>, Error> {
+  backend(&window)
+    .request(None, BackEndPortMessage::SearchMachines)
+    .await
+}
+
+#[tauri::command]
+async fn connect_local(window: Window, ip: String) -> Result<String, Error> {
+  backend(&window)
+    .request(None, BackEndPortMessage::WashingMachineHttpConnect(ip))
+    .await
+}
+
+#[tauri::command]
+async fn connect_things5(
+  window: Window,
+  token: String,
+  device_id: String,
+) -> Result<String, Error> {
+  backend(&window)
+    .request(
+      None,
+      BackEndPortMessage::WashingMachineThings5Connect { token, device_id },
+    )
+    .await
+}
+
+/// Connects to a Modbus TCP controller, with the saved register map unless one is given
+#[tauri::command]
+async fn connect_modbus(
+  window: Window,
+  address: String,
+  map: Option<RegisterMap>,
+) -> Result<String, Error> {
+  backend(&window)
+    .request(
+      None,
+      BackEndPortMessage::WashingMachineModbusConnect { address, map },
+    )
+    .await
+}
+
+/// Adds a simulated machine, for demos without hardware
+#[tauri::command]
+async fn connect_simulated(
+  window: Window,
+  name: String,
+  speedup: Option<u32>,
+) -> Result<String, Error> {
+  backend(&window)
+    .request(
+      None,
+      BackEndPortMessage::WashingMachineSimulatedConnect { name, speedup },
+    )
+    .await
+}
+
+#[tauri::command]
+async fn simulate(
+  window: Window,
+  machine: Option<String>,
+  event: SimulatedEvent,
+) -> Result<(), Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::Simulate(event))
+    .await
+}
+
+/// Adds a machine playing back a session recorded with `start_recording`
+#[tauri::command]
+async fn connect_replay(
+  window: Window,
+  path: PathBuf,
+  speedup: Option<u32>,
+) -> Result<String, Error> {
+  backend(&window)
+    .request(
+      None,
+      BackEndPortMessage::WashingMachineReplayConnect { path, speedup },
+    )
+    .await
+}
+
+#[tauri::command]
+async fn start_recording(
+  window: Window,
+  machine: Option<String>,
+  path: PathBuf,
+) -> Result<(), Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::StartRecording(path))
+    .await
+}
+
+#[tauri::command]
+async fn stop_recording(window: Window, machine: Option<String>) -> Result<(), Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::StopRecording)
+    .await
+}
+
+#[tauri::command]
+async fn get_machines(
+  window: Window,
+  machine: Option<String>,
+) -> Result<Vec<MachineUpdate>, Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::GetMachines)
+    .await
+}
+
+#[tauri::command]
+async fn select_machine(window: Window, machine: String) -> Result<(), Error> {
+  backend(&window)
+    .request(None, BackEndPortMessage::SelectMachine(machine))
+    .await
+}
+
+#[tauri::command]
+async fn disconnect(window: Window, machine: Option<String>) -> Result<(), Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::Disconnect)
+    .await
+}
+
+#[tauri::command]
+async fn send_machine_configuration(
+  window: Window,
+  machine: Option<String>,
+  data: Vec<u8>,
+) -> Result<(), Error> {
+  backend(&window)
+    .request(
+      machine,
+      BackEndPortMessage::SendCurrentMachineConfiguration(data),
+    )
+    .await
+}
+
+#[tauri::command]
+async fn get_machine_configuration(
+  window: Window,
+  machine: Option<String>,
+) -> Result<Vec<u8>, Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::GetCurrentMachineConfiguration)
+    .await
+}
+
+#[tauri::command]
+async fn select_machine_configuration(
+  window: Window,
+  machine: Option<String>,
+  archive: String,
+) -> Result<(), Error> {
+  backend(&window)
+    .request(
+      machine,
+      BackEndPortMessage::SelectMachineConfiguration(archive),
+    )
+    .await
+}
+
+#[tauri::command]
+async fn start_program(window: Window, machine: Option<String>, program: u16) -> Result<(), Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::StartProgram(program))
+    .await
+}
+
+/// Uploads the archive to every target, activating `select` afterwards if given
+#[tauri::command]
+async fn push_configuration_to_fleet(
+  window: Window,
+  archive: Vec<u8>,
+  targets: Vec<FleetTarget>,
+  select: Option<String>,
+) -> Result<FleetReport, Error> {
+  backend(&window)
+    .request(
+      None,
+      BackEndPortMessage::PushConfigurationToFleet {
+        archive,
+        targets,
+        select,
+        token: None,
+      },
+    )
+    .await
+}
+
+/// Plans a start of the given program, on the selected machine unless another one is given
+#[tauri::command]
+async fn schedule_start(
+  window: Window,
+  machine: Option<String>,
+  program: u16,
+  at: chrono::DateTime<chrono::Utc>,
+) -> Result<ScheduledStart, Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::ScheduleStart { program, at })
+    .await
+}
+
+#[tauri::command]
+async fn cancel_scheduled_start(window: Window, id: u64) -> Result<(), Error> {
+  backend(&window)
+    .request(None, BackEndPortMessage::CancelScheduledStart(id))
+    .await
+}
+
+#[tauri::command]
+async fn get_scheduled_starts(window: Window) -> Result<Vec<ScheduledStart>, Error> {
+  backend(&window)
+    .request(None, BackEndPortMessage::GetScheduledStarts)
+    .await
+}
+
+#[tauri::command]
+async fn restart(window: Window, machine: Option<String>) -> Result<(), Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::Restart)
+    .await
+}
+
+#[tauri::command]
+async fn pause(window: Window, machine: Option<String>) -> Result<(), Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::Pause)
+    .await
+}
+
+#[tauri::command]
+async fn stop(window: Window, machine: Option<String>) -> Result<(), Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::Stop)
+    .await
+}
+
+#[tauri::command]
+async fn clear_alarms(window: Window, machine: Option<String>) -> Result<(), Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::ClearAlarms)
+    .await
+}
+
+/// Page of the recorded cycles, alarms and commands, newest first
+#[tauri::command]
+fn get_history(handle: State<'_, Handle>, query: HistoryQuery) -> Result<HistoryPage, Error> {
+  handle.history().query(&query)
+}
+
+/// Saves the service report of a machine, the selected one unless another one is given
+#[tauri::command]
+async fn generate_report(
+  window: Window,
+  machine: Option<String>,
+  path: PathBuf,
+  pdf: Option<PathBuf>,
+) -> Result<(), Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::GenerateReport { path, pdf })
+    .await
+}
+
+/// Whether reports can also be saved as PDF on this computer
+#[tauri::command]
+fn pdf_report_available() -> bool {
+  controller::report::pdf_available()
+}
+
+#[tauri::command]
+async fn get_maintenance_rules(window: Window) -> Result<Vec<MaintenanceRule>, Error> {
+  backend(&window)
+    .request(None, BackEndPortMessage::GetMaintenanceRules)
+    .await
+}
+
+#[tauri::command]
+async fn set_maintenance_rule(
+  window: Window,
+  rule: MaintenanceRule,
+) -> Result<MaintenanceRule, Error> {
+  backend(&window)
+    .request(None, BackEndPortMessage::SetMaintenanceRule(rule))
+    .await
+}
+
+#[tauri::command]
+async fn remove_maintenance_rule(window: Window, id: u64) -> Result<(), Error> {
+  backend(&window)
+    .request(None, BackEndPortMessage::RemoveMaintenanceRule(id))
+    .await
+}
+
+/// Records that the service of a rule was just done, on the selected machine unless another one
+/// is given
+#[tauri::command]
+async fn record_service(
+  window: Window,
+  machine: Option<String>,
+  rule: u64,
+) -> Result<MaintenanceStatus, Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::RecordService(rule))
+    .await
+}
+
+/// Service state of one machine, or of every connected machine if none is given
+#[tauri::command]
+async fn get_maintenance_status(
+  window: Window,
+  machine: Option<String>,
+) -> Result<Vec<MaintenanceStatus>, Error> {
+  backend(&window)
+    .request(machine, BackEndPortMessage::GetMaintenanceStatus)
+    .await
+}
+
+/// Seconds between two samples of the statistics, at least 60
+#[tauri::command]
+async fn set_statistics_interval(window: Window, secs: u64) -> Result<(), Error> {
+  backend(&window)
+    .request(None, BackEndPortMessage::SetStatisticsInterval(secs))
+    .await
+}
+
+/// Statistics sampled in the given range, as CSV or JSON text
+#[tauri::command]
+fn export_statistics(query: StatisticsExport) -> Result<String, Error> {
+  statistics::export(&query)
+}
+
+#[tauri::command]
+fn get_consumption_rates() -> RatesTable {
+  controller::prefs::get_consumption_rates()
+}
+
+#[tauri::command]
+fn set_consumption_rates(rates: RatesTable) -> Result<(), Error> {
+  controller::prefs::set_consumption_rates(&rates)
+}
+
+/// Water, energy and detergent used in the given range, per machine and period
+#[tauri::command]
+fn estimate_consumption(range: StatisticsRange) -> Result<Vec<ConsumptionEstimate>, Error> {
+  controller::consumption::estimates(&range)
+}
+
+#[tauri::command]
+fn get_notification_settings() -> NotificationSettings {
+  controller::notifications::settings()
+}
+
+#[tauri::command]
+fn set_notification_settings(settings: NotificationSettings) -> Result<(), Error> {
+  controller::notifications::set_settings(settings)
+}
+
+/// Language the frontend is shown in, for the texts of the notifications
+#[tauri::command]
+fn set_language(language: String) {
+  controller::notifications::set_language(language)
+}
+
+/// Sets the address the local gateway listens on, taking effect the next time the backend starts.
+/// A bare port listens on 127.0.0.1; other addresses must be loopback ones
+#[tauri::command]
+fn set_gateway_address(address: Option<String>) -> Result<(), Error> {
+  let address = match address {
+    Some(address) => {
+      let address = match address.parse::<u16>() {
+        Ok(port) => SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+        Err(_) => address.parse::<SocketAddr>().map_err(|_| Error::Value)?,
+      };
+      if !address.ip().is_loopback() {
+        return Err(Error::Value);
+      }
+      Some(address)
+    }
+    None => None,
+  };
+  controller::prefs::set_gateway_address(address)
+}
+
+/// Token the clients of the local gateway must send in the `Authorization` header
+#[tauri::command]
+fn get_gateway_token() -> Result<String, Error> {
+  controller::prefs::gateway_token()
+}
+
 fn main() {
   CombinedLogger::init(vec![
     TermLogger::new(
@@ -27,7 +451,53 @@ fn main() {
   .unwrap();
 
   tauri::Builder::default()
-    .invoke_handler(tauri::generate_handler![init_tasks])
+    .manage(Handle::default())
+    .invoke_handler(tauri::generate_handler![
+      init_tasks,
+      things5_login,
+      search_machines,
+      connect_local,
+      connect_things5,
+      connect_modbus,
+      connect_simulated,
+      simulate,
+      connect_replay,
+      start_recording,
+      stop_recording,
+      get_machines,
+      select_machine,
+      disconnect,
+      send_machine_configuration,
+      get_machine_configuration,
+      select_machine_configuration,
+      start_program,
+      restart,
+      pause,
+      stop,
+      clear_alarms,
+      push_configuration_to_fleet,
+      schedule_start,
+      cancel_scheduled_start,
+      get_scheduled_starts,
+      get_history,
+      set_statistics_interval,
+      get_maintenance_rules,
+      set_maintenance_rule,
+      remove_maintenance_rule,
+      record_service,
+      get_maintenance_status,
+      generate_report,
+      pdf_report_available,
+      export_statistics,
+      get_consumption_rates,
+      set_consumption_rates,
+      estimate_consumption,
+      get_notification_settings,
+      set_notification_settings,
+      set_language,
+      set_gateway_address,
+      get_gateway_token
+    ])
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
 }
@@ -0,0 +1,251 @@
+//! Drives the local network client and discovery against the WS2020 emulator
+use laundry_control::controller::discovery;
+use laundry_control::controller::washing_machine::{
+  local, simulated::SimulatedEvent, ConnectionState, ReconnectPolicy, State, StateCode,
+  WashingMachineConnection,
+};
+use laundry_control::controller::Error;
+use laundry_control::emulator::{Emulator, Fault, DISCOVERY_ENDPOINT};
+use std::time::Duration;
+
+/// Retries right away, so that every refresh hits the emulator
+fn policy() -> ReconnectPolicy {
+  ReconnectPolicy {
+    initial_delay: Duration::from_secs(0),
+    max_delay: Duration::from_secs(0),
+    multiplier: 2,
+    degraded_failures: 2,
+    offline_after: Duration::from_secs(300),
+  }
+}
+
+async fn emulator() -> (Emulator, String) {
+  let emulator = Emulator::new(String::from("1"), 1);
+  let address = emulator.serve_http("127.0.0.1:0").await.unwrap();
+  (emulator, address.to_string())
+}
+
+/// State of the simulated machine behind the emulator
+fn machine_state(emulator: &Emulator) -> State {
+  emulator
+    .machine()
+    .get_connection_state()
+    .data()
+    .unwrap()
+    .state
+    .clone()
+}
+
+#[tokio::test]
+async fn reads_the_machine() {
+  let (_emulator, address) = emulator().await;
+  let connection = local::Connection::new(address.clone()).await;
+
+  let state = connection.get_connection_state();
+  let data = state.data().unwrap();
+  assert!(state.is_connected());
+  assert_eq!(data.name, address);
+  assert_eq!(data.state.state, StateCode::Stopped);
+  assert_eq!(data.configuration.programs.len(), 5);
+  assert_eq!(data.stats.cycles, 0);
+}
+
+#[tokio::test]
+async fn remote_control() {
+  let (emulator, address) = emulator().await;
+  let connection = local::Connection::new(address).await;
+
+  connection.start_program(1).await.unwrap();
+  let state = machine_state(&emulator);
+  assert_eq!(state.state, StateCode::Running);
+  assert_eq!(state.cycle, 1);
+
+  connection.pause().await.unwrap();
+  assert_eq!(machine_state(&emulator).state, StateCode::Paused);
+
+  connection.restart().await.unwrap();
+  assert_eq!(machine_state(&emulator).state, StateCode::Running);
+
+  connection.refresh_data().await;
+  let state = connection.get_connection_state();
+  assert_eq!(state.data().unwrap().state.state, StateCode::Running);
+
+  connection.stop().await.unwrap();
+  assert_eq!(machine_state(&emulator).state, StateCode::Stopped);
+
+  emulator.machine().inject(SimulatedEvent::Alarm(7)).unwrap();
+  connection.refresh_data().await;
+  let state = connection.get_connection_state();
+  assert_eq!(state.data().unwrap().state.alarm_code, 7);
+
+  connection.clear_alarms().await.unwrap();
+  assert_eq!(machine_state(&emulator).alarm_code, 0);
+}
+
+#[tokio::test]
+async fn refused_commands_fail() {
+  let (emulator, address) = emulator().await;
+  let connection = local::Connection::new(address).await;
+
+  // The emulator answers 400 to programs it does not have and to commands out of place
+  assert!(matches!(
+    connection.start_program(99).await,
+    Err(Error::Server(_))
+  ));
+  assert!(matches!(connection.pause().await, Err(Error::Server(_))));
+  assert_eq!(machine_state(&emulator).state, StateCode::Stopped);
+
+  emulator.script("start", vec![Fault::Status(503)]);
+  assert!(matches!(
+    connection.start_program(0).await,
+    Err(Error::Server(_))
+  ));
+  assert_eq!(machine_state(&emulator).state, StateCode::Stopped);
+
+  emulator.script("clear_alarms", vec![Fault::Status(500)]);
+  assert!(matches!(
+    connection.clear_alarms().await,
+    Err(Error::Server(_))
+  ));
+}
+
+#[tokio::test]
+async fn configuration_archives() {
+  let (emulator, address) = emulator().await;
+  let connection = local::Connection::new(address).await;
+
+  connection
+    .send_machine_configuration(vec![1, 2, 3])
+    .await
+    .unwrap();
+  assert_eq!(emulator.archive(), vec![1, 2, 3]);
+  assert_eq!(
+    connection.get_machine_configuration().await.unwrap(),
+    vec![1, 2, 3]
+  );
+
+  connection
+    .select_machine_configuration(String::from("summer programs"))
+    .await
+    .unwrap();
+  assert_eq!(
+    emulator.selected_archive(),
+    Some(String::from("summer programs"))
+  );
+
+  // Downloads are only trusted when their length is known
+  emulator.script("machine", vec![Fault::MissingContentLength]);
+  assert!(matches!(
+    connection.get_machine_configuration().await,
+    Err(Error::Protocol)
+  ));
+}
+
+#[tokio::test]
+async fn failed_refreshes_degrade_then_reconnect() {
+  let (emulator, address) = emulator().await;
+  let connection = local::Connection::with_policy(address, policy()).await;
+  assert!(connection.get_connection_state().is_connected());
+
+  emulator.script(
+    "state",
+    vec![Fault::Status(500), Fault::BadJson, Fault::Status(503)],
+  );
+
+  connection.refresh_data().await;
+  assert!(matches!(
+    connection.get_connection_state(),
+    ConnectionState::Degraded { .. }
+  ));
+
+  connection.refresh_data().await;
+  assert!(matches!(
+    connection.get_connection_state(),
+    ConnectionState::Degraded { .. }
+  ));
+
+  connection.refresh_data().await;
+  assert!(matches!(
+    connection.get_connection_state(),
+    ConnectionState::Reconnecting { attempt: 1, .. }
+  ));
+
+  connection.refresh_data().await;
+  assert!(connection.get_connection_state().is_connected());
+}
+
+#[tokio::test]
+async fn timeouts_degrade_the_connection() {
+  let (emulator, address) = emulator().await;
+  let connection = local::Connection::with_policy(address, policy()).await;
+
+  emulator.script("state", vec![Fault::Timeout]);
+  connection.refresh_data().await;
+  assert!(matches!(
+    connection.get_connection_state(),
+    ConnectionState::Degraded { .. }
+  ));
+
+  connection.refresh_data().await;
+  assert!(connection.get_connection_state().is_connected());
+}
+
+#[tokio::test]
+async fn bodies_without_length_are_read_to_the_end() {
+  let (emulator, address) = emulator().await;
+  let connection = local::Connection::with_policy(address, policy()).await;
+
+  emulator.script("state", vec![Fault::MissingContentLength]);
+  connection.refresh_data().await;
+  assert!(connection.get_connection_state().is_connected());
+}
+
+#[tokio::test]
+async fn long_outages_are_offline() {
+  let (emulator, address) = emulator().await;
+  let policy = ReconnectPolicy {
+    degraded_failures: 0,
+    offline_after: Duration::from_secs(0),
+    ..policy()
+  };
+  let connection = local::Connection::with_policy(address, policy).await;
+
+  emulator.script("statistics", vec![Fault::Status(500)]);
+  connection.refresh_data().await;
+  assert!(matches!(
+    connection.get_connection_state(),
+    ConnectionState::Offline { .. }
+  ));
+
+  connection.refresh_data().await;
+  assert!(connection.get_connection_state().is_connected());
+}
+
+#[tokio::test]
+async fn discovery() {
+  let emulator = Emulator::new(String::from("3"), 1);
+  let address = emulator
+    .serve_discovery("127.0.0.1:0", String::from("192.168.1.20"))
+    .await
+    .unwrap()
+    .to_string();
+
+  let machines = discovery::poll_at(address.as_str()).await.unwrap();
+  assert_eq!(
+    machines,
+    vec![(String::from("192.168.1.20"), String::from("3"))]
+  );
+
+  // Malformed and missing replies are ignored
+  emulator.script(DISCOVERY_ENDPOINT, vec![Fault::BadJson]);
+  assert!(discovery::poll_at(address.as_str())
+    .await
+    .unwrap()
+    .is_empty());
+
+  emulator.script(DISCOVERY_ENDPOINT, vec![Fault::Timeout]);
+  assert!(discovery::poll_at(address.as_str())
+    .await
+    .unwrap()
+    .is_empty());
+}